@@ -37,14 +37,14 @@ pub fn surrounding_box(box0: AABBVolume, box1: AABBVolume) -> AABBVolume {
 // FIXME: Why does this result in fewer rays than previous approach?
 // Credit to Majercik et al. - http://jcgt.org/published/0007/03/04/
 #[inline(always)]
-fn slabs(aabb_min: Vec3, aabb_max: Vec3, ray_origin: Vec3, inv_ray_dir: Vec3) -> bool {
+fn slabs(aabb_min: Vec3, aabb_max: Vec3, ray_origin: Vec3, inv_ray_dir: Vec3, t_min: f32, t_max: f32) -> bool {
     let t0 = (aabb_min - ray_origin) * inv_ray_dir;
     let t1 = (aabb_max - ray_origin) * inv_ray_dir;
     let tmin = t0.min(&t1); // vector of element wise min
     let tmax = t0.max(&t1); // vector of element wise max
 
-    // max element in tmin <= min element in tmax
-    tmin.max_component() <= tmax.min_component()
+    // max element in tmin <= min element in tmax, clipped to the range of the ray
+    ffmax(tmin.max_component(), t_min) <= ffmin(tmax.min_component(), t_max)
 }
 
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -73,7 +73,16 @@ impl AABBVolume {
         self.max
     }
 
-    pub fn hit(&self, ray: &Ray, _t_min: f32, _t_max: f32) -> bool {
-        slabs(self.min, self.max, ray.origin(), ray.inverse_direction())
+    pub fn centroid(&self) -> Vec3 {
+        0.5 * (self.min + self.max)
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let extent = self.max - self.min;
+        2.0 * (extent.x() * extent.y() + extent.y() * extent.z() + extent.z() * extent.x())
+    }
+
+    pub fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
+        slabs(self.min, self.max, ray.origin(), ray.inverse_direction(), t_min, t_max)
     }
 }
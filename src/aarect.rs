@@ -4,6 +4,29 @@ use scene::{Entities, MaterialRef};
 use ray::Ray;
 use vec3::Vec3;
 
+use std::f32;
+
+// Solid angle pdf of picking a point uniformly on a rect with the given area, zero for rects with no area as picking a
+// point on them can't be weighted against anything
+fn rect_pdf_value<H: Hitable>(rect: &H, area: f32, entities: &Entities, origin: &Vec3, direction: &Vec3, time: f32) -> f32 {
+    if area <= 0.0 {
+        return 0.0;
+    }
+
+    let mut hit_record = HitRecord::zero();
+    if !rect.hit_ptr(entities, &Ray::new(*origin, *direction, time), 0.001, f32::MAX, &mut hit_record) {
+        return 0.0;
+    }
+
+    let distance_squared = hit_record.t * hit_record.t * direction.squared_length();
+    let cosine = (Vec3::dot(direction, &hit_record.normal) / direction.length()).abs();
+    if cosine > 0.0 {
+        distance_squared / (cosine * area)
+    } else {
+        0.0
+    }
+}
+
 #[derive(Debug)]
 pub struct XYRect {
     x0: f32,
@@ -48,16 +71,33 @@ impl Hitable for XYRect {
         hit_record.u = u;
         hit_record.v = v;
         hit_record.normal = Vec3::new(0.0, 0.0, 1.0);
+        hit_record.dpdu = Vec3::new(self.x1 - self.x0, 0.0, 0.0);
+        hit_record.dpdv = Vec3::new(0.0, self.y1 - self.y0, 0.0);
         hit_record.material = self.material;
         true
     }
 
-    fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<AABBVolume> {
+    fn bounding_box(&self, _entities: &Entities, _t0: f32, _t1: f32) -> Option<AABBVolume> {
         Some(AABBVolume::new(
             Vec3::new(self.x0, self.y0, self.k - 0.0001),
             Vec3::new(self.x1, self.y1, self.k + 0.0001),
         ))
     }
+
+    fn material(&self) -> Option<MaterialRef> {
+        Some(self.material)
+    }
+
+    fn pdf_value(&self, entities: &Entities, origin: &Vec3, direction: &Vec3, time: f32) -> f32 {
+        let area = (self.x1 - self.x0) * (self.y1 - self.y0);
+        rect_pdf_value(self, area, entities, origin, direction, time)
+    }
+
+    fn random_direction(&self, _entities: &Entities, origin: &Vec3, _time: f32, sample: [f32; 2]) -> Vec3 {
+        let x = self.x0 + sample[0] * (self.x1 - self.x0);
+        let y = self.y0 + sample[1] * (self.y1 - self.y0);
+        Vec3::new(x, y, self.k) - *origin
+    }
 }
 
 #[derive(Debug)]
@@ -104,16 +144,33 @@ impl Hitable for XZRect {
         hit_record.u = u;
         hit_record.v = v;
         hit_record.normal = Vec3::new(0.0, 1.0, 0.0);
+        hit_record.dpdu = Vec3::new(self.x1 - self.x0, 0.0, 0.0);
+        hit_record.dpdv = Vec3::new(0.0, 0.0, self.z1 - self.z0);
         hit_record.material = self.material;
         true
     }
 
-    fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<AABBVolume> {
+    fn bounding_box(&self, _entities: &Entities, _t0: f32, _t1: f32) -> Option<AABBVolume> {
         Some(AABBVolume::new(
             Vec3::new(self.x0, self.k - 0.0001, self.z0),
             Vec3::new(self.x1, self.k + 0.0001, self.z1),
         ))
     }
+
+    fn material(&self) -> Option<MaterialRef> {
+        Some(self.material)
+    }
+
+    fn pdf_value(&self, entities: &Entities, origin: &Vec3, direction: &Vec3, time: f32) -> f32 {
+        let area = (self.x1 - self.x0) * (self.z1 - self.z0);
+        rect_pdf_value(self, area, entities, origin, direction, time)
+    }
+
+    fn random_direction(&self, _entities: &Entities, origin: &Vec3, _time: f32, sample: [f32; 2]) -> Vec3 {
+        let x = self.x0 + sample[0] * (self.x1 - self.x0);
+        let z = self.z0 + sample[1] * (self.z1 - self.z0);
+        Vec3::new(x, self.k, z) - *origin
+    }
 }
 
 #[derive(Debug)]
@@ -160,14 +217,31 @@ impl Hitable for YZRect {
         hit_record.u = u;
         hit_record.v = v;
         hit_record.normal = Vec3::new(1.0, 0.0, 0.0);
+        hit_record.dpdu = Vec3::new(0.0, self.y1 - self.y0, 0.0);
+        hit_record.dpdv = Vec3::new(0.0, 0.0, self.z1 - self.z0);
         hit_record.material = self.material;
         true
     }
 
-    fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<AABBVolume> {
+    fn bounding_box(&self, _entities: &Entities, _t0: f32, _t1: f32) -> Option<AABBVolume> {
         Some(AABBVolume::new(
             Vec3::new(self.k - 0.0001, self.y0, self.z0),
             Vec3::new(self.k + 0.0001, self.y1, self.z1),
         ))
     }
+
+    fn material(&self) -> Option<MaterialRef> {
+        Some(self.material)
+    }
+
+    fn pdf_value(&self, entities: &Entities, origin: &Vec3, direction: &Vec3, time: f32) -> f32 {
+        let area = (self.y1 - self.y0) * (self.z1 - self.z0);
+        rect_pdf_value(self, area, entities, origin, direction, time)
+    }
+
+    fn random_direction(&self, _entities: &Entities, origin: &Vec3, _time: f32, sample: [f32; 2]) -> Vec3 {
+        let y = self.y0 + sample[0] * (self.y1 - self.y0);
+        let z = self.z0 + sample[1] * (self.z1 - self.z0);
+        Vec3::new(self.k, y, z) - *origin
+    }
 }
@@ -0,0 +1,152 @@
+// Stops sampling a pixel once its estimate is good enough rather than always taking the maximum number of samples
+
+use tonemap::luminance;
+use vec3::Vec3;
+
+// Keeps nearly black pixels from needing an impossibly small absolute error
+const LUMINANCE_FLOOR: f32 = 0.01;
+pub const DEFAULT_MIN_SAMPLES: u32 = 16;
+
+#[derive(Debug, Clone, Copy)]
+pub struct AdaptiveSampling {
+    // Largest relative standard error of a pixel's luminance that counts as converged
+    pub threshold: f32,
+    pub min_samples: u32,
+}
+
+impl AdaptiveSampling {
+    pub fn new(threshold: f32, min_samples: u32) -> AdaptiveSampling {
+        AdaptiveSampling { threshold, min_samples }
+    }
+
+    pub fn is_converged(&self, stats: &PixelStats) -> bool {
+        if stats.count() < self.min_samples {
+            return false;
+        }
+
+        stats.standard_error() <= self.threshold * stats.mean_luminance().max(LUMINANCE_FLOOR)
+    }
+}
+
+// Running mean of a pixel's colour plus the variance of its luminance, credit to Welford
+#[derive(Debug, Clone, Copy)]
+pub struct PixelStats {
+    count: u32,
+    sum: Vec3,
+    mean_luminance: f32,
+    squared_deviations: f32,
+}
+
+impl PixelStats {
+    pub fn new() -> PixelStats {
+        PixelStats {
+            count: 0,
+            sum: Vec3::zero(),
+            mean_luminance: 0.0,
+            squared_deviations: 0.0,
+        }
+    }
+
+    pub fn from_parts(count: u32, sum: Vec3, mean_luminance: f32, squared_deviations: f32) -> PixelStats {
+        PixelStats { count, sum, mean_luminance, squared_deviations }
+    }
+
+    pub fn add(&mut self, colour: &Vec3) {
+        self.count += 1;
+        self.sum += *colour;
+
+        let value = luminance(colour);
+        let delta = value - self.mean_luminance;
+        self.mean_luminance += delta / self.count as f32;
+        self.squared_deviations += delta * (value - self.mean_luminance);
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    pub fn sum(&self) -> Vec3 {
+        self.sum
+    }
+
+    pub fn squared_deviations(&self) -> f32 {
+        self.squared_deviations
+    }
+
+    pub fn mean(&self) -> Vec3 {
+        if self.count == 0 {
+            Vec3::zero()
+        } else {
+            self.sum / self.count as f32
+        }
+    }
+
+    pub fn mean_luminance(&self) -> f32 {
+        self.mean_luminance
+    }
+
+    // Sample variance of the luminance
+    pub fn variance(&self) -> f32 {
+        if self.count < 2 {
+            0.0
+        } else {
+            self.squared_deviations / (self.count - 1) as f32
+        }
+    }
+
+    // Standard error of mean_luminance
+    pub fn standard_error(&self) -> f32 {
+        if self.count == 0 {
+            0.0
+        } else {
+            (self.variance() / self.count as f32).sqrt()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats_of(values: &[f32]) -> PixelStats {
+        let mut stats = PixelStats::new();
+        for &value in values {
+            stats.add(&Vec3::uniform(value));
+        }
+        stats
+    }
+
+    #[test]
+    fn matches_the_mean_and_variance_computed_directly() {
+        let values = [0.3, 1.7, 0.2, 0.9, 4.0, 0.0, 2.5, 0.6];
+        let stats = stats_of(&values);
+
+        let count = values.len() as f32;
+        let mean = values.iter().sum::<f32>() / count;
+        let variance = values.iter().map(|value| (value - mean).powi(2)).sum::<f32>() / (count - 1.0);
+        assert_eq!(stats.count(), values.len() as u32);
+        assert!((stats.mean_luminance() - mean).abs() < 1e-5);
+        assert!((stats.mean() - Vec3::uniform(mean)).length() < 1e-5);
+        assert!((stats.variance() - variance).abs() < 1e-5 * variance);
+        assert!((stats.standard_error() - (variance / count).sqrt()).abs() < 1e-5);
+
+        assert_eq!(stats_of(&[]).mean(), Vec3::zero());
+        assert_eq!(stats_of(&[0.5]).variance(), 0.0);
+    }
+
+    #[test]
+    fn converges_once_the_error_is_below_the_threshold() {
+        // Relative standard errors of about 0.04 and 0.38
+        let steady = stats_of(&[0.9, 1.1, 0.9, 1.1, 0.9, 1.1, 0.9, 1.1]);
+        let noisy = stats_of(&[0.0, 2.0, 0.0, 2.0, 0.0, 2.0, 0.0, 2.0]);
+        let adaptive = AdaptiveSampling::new(0.1, 4);
+        assert!(adaptive.is_converged(&steady));
+        assert!(!adaptive.is_converged(&noisy));
+        assert!(AdaptiveSampling::new(0.4, 4).is_converged(&noisy));
+
+        // However steady the samples, pixels take at least min_samples
+        assert!(!AdaptiveSampling::new(0.1, 9).is_converged(&steady));
+        assert!(!AdaptiveSampling::new(0.1, 2).is_converged(&stats_of(&[1.0])));
+        assert!(AdaptiveSampling::new(0.1, 2).is_converged(&stats_of(&[1.0, 1.0])));
+    }
+}
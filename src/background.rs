@@ -0,0 +1,167 @@
+// What rays that miss every entity see
+
+use distribution::Distribution2D;
+use image::FrameBuffer;
+use sky::Sky;
+use tonemap::luminance;
+use vec3::Vec3;
+
+use std::f32::consts::PI;
+use std::path::Path;
+
+// Latitude-longitude image of the light arriving from every direction. The centre of the image is the -z direction
+// (before rotation) and the top row is straight up
+#[derive(Debug)]
+pub struct EnvironmentMap {
+    width: usize,
+    height: usize,
+    pixels: Vec<Vec3>,
+    // Radians anticlockwise about +y
+    rotation: f32,
+    intensity: f32,
+    distribution: Distribution2D,
+}
+
+impl EnvironmentMap {
+    // rotation is in degrees
+    pub fn new(framebuffer: FrameBuffer, rotation: f32, intensity: f32) -> EnvironmentMap {
+        let width = framebuffer.width() as usize;
+        let height = framebuffer.height() as usize;
+        // NaN and infinite texels would poison the distribution
+        let pixels: Vec<Vec3> = framebuffer.pixels()
+            .iter()
+            .map(|pixel| if pixel.r().is_finite() && pixel.g().is_finite() && pixel.b().is_finite() { *pixel } else { Vec3::zero() })
+            .collect();
+
+        // Rows near the poles cover less solid angle so they are picked less often
+        let weights: Vec<f32> = pixels.chunks(width)
+            .enumerate()
+            .flat_map(|(row, texels)| {
+                let sin_theta = (PI * (row as f32 + 0.5) / height as f32).sin();
+                texels.iter().map(move |texel| luminance(texel).max(0.0) * sin_theta)
+            })
+            .collect();
+        let distribution = Distribution2D::new(&weights, width, height);
+
+        EnvironmentMap {
+            width,
+            height,
+            pixels,
+            rotation: rotation * PI / 180.0,
+            intensity,
+            distribution,
+        }
+    }
+
+    pub fn load<T: AsRef<Path>>(path: T, rotation: f32, intensity: f32) -> Result<EnvironmentMap, String> {
+        Ok(EnvironmentMap::new(FrameBuffer::load(path)?, rotation, intensity))
+    }
+
+    fn rotate(&self, direction: &Vec3, angle: f32) -> Vec3 {
+        let (sin, cos) = angle.sin_cos();
+        Vec3::new(cos * direction.x() + sin * direction.z(), direction.y(), -sin * direction.x() + cos * direction.z())
+    }
+
+    // Position in [0, 1)^2 of direction on the map, v runs from the top row down
+    fn map_position(&self, direction: &Vec3) -> [f32; 2] {
+        let local = self.rotate(&direction.unit(), -self.rotation);
+        let phi = local.x().atan2(-local.z());
+        let theta = local.y().clamp(-1.0, 1.0).acos();
+        [(0.5 + phi / (2.0 * PI)).rem_euclid(1.0), theta / PI]
+    }
+
+    fn map_direction(&self, point: [f32; 2]) -> Vec3 {
+        let phi = (point[0] - 0.5) * 2.0 * PI;
+        let theta = point[1] * PI;
+        let local = Vec3::new(theta.sin() * phi.sin(), theta.cos(), -theta.sin() * phi.cos());
+        self.rotate(&local, self.rotation)
+    }
+
+    pub fn radiance(&self, direction: &Vec3) -> Vec3 {
+        let [u, v] = self.map_position(direction);
+        let x = ((u * self.width as f32) as usize).min(self.width - 1);
+        let y = ((v * self.height as f32) as usize).min(self.height - 1);
+        self.pixels[y * self.width + x] * self.intensity
+    }
+
+    // Solid angle pdf, the distribution is over the map so it is divided by the area each texel covers
+    fn pdf_from_map(&self, point: [f32; 2], map_pdf: f32) -> f32 {
+        let sin_theta = (point[1] * PI).sin();
+        if sin_theta <= 0.0 {
+            0.0
+        } else {
+            map_pdf / (2.0 * PI * PI * sin_theta)
+        }
+    }
+
+    pub fn sample(&self, sample: [f32; 2]) -> Option<(Vec3, f32)> {
+        let (point, map_pdf) = self.distribution.sample_continuous(sample);
+        let pdf = self.pdf_from_map(point, map_pdf);
+        if pdf <= 0.0 {
+            None
+        } else {
+            Some((self.map_direction(point), pdf))
+        }
+    }
+
+    pub fn pdf(&self, direction: &Vec3) -> f32 {
+        let point = self.map_position(direction);
+        self.pdf_from_map(point, self.distribution.pdf(point))
+    }
+}
+
+#[derive(Debug)]
+pub enum Background {
+    Constant(Vec3),
+    // Blends from bottom looking straight down to top looking straight up
+    Gradient { bottom: Vec3, top: Vec3 },
+    Environment(EnvironmentMap),
+    Sky(Sky),
+}
+
+impl Background {
+    // The sky from the first book
+    pub fn sky() -> Background {
+        Background::Gradient { bottom: Vec3::uniform(1.0), top: Vec3::new(0.5, 0.7, 1.0) }
+    }
+
+    pub fn radiance(&self, direction: &Vec3) -> Vec3 {
+        match self {
+            Background::Constant(colour) => *colour,
+            Background::Gradient { bottom, top } => {
+                let t = 0.5 * (direction.unit().y() + 1.0);
+                (1.0 - t) * *bottom + t * *top
+            },
+            Background::Environment(map) => map.radiance(direction),
+            Background::Sky(sky) => sky.radiance(direction),
+        }
+    }
+
+    // Only environment maps and the sun are sampled as lights, other backgrounds are only found by scattering
+    pub fn is_sampled(&self) -> bool {
+        matches!(self, Background::Environment(_) | Background::Sky(_))
+    }
+
+    // Direction towards the background along with its solid angle pdf
+    pub fn sample(&self, sample: [f32; 2]) -> Option<(Vec3, f32)> {
+        match self {
+            Background::Environment(map) => map.sample(sample),
+            Background::Sky(sky) => sky.sample(sample),
+            _ => None,
+        }
+    }
+
+    pub fn pdf(&self, direction: &Vec3) -> f32 {
+        match self {
+            Background::Environment(map) => map.pdf(direction),
+            Background::Sky(sky) => sky.pdf(direction),
+            _ => 0.0,
+        }
+    }
+}
+
+impl Default for Background {
+    fn default() -> Background {
+        Background::Constant(Vec3::zero())
+    }
+}
@@ -1,19 +1,27 @@
 // TODO: Use a proper arena implementation for the Bvh Tree
-// TODO: Implement in a way that lets Bvh trees contain sub-trees
 // TODO: Try using Z-Order curves to sort instead of random axis
 
 use aabb::{surrounding_box, AABBVolume};
-use hitable::{HitRecord, Hitable};
-use random::drand48;
+use hitable::HitRecord;
 use ray::Ray;
 
 use std::cmp::Ordering;
-use std::u32;
-use std::usize;
-use scene::Entities;
+use std::fmt;
+use std::str::FromStr;
+use scene::{Entities, HitableRef};
 
 type BvhNodeIndex = u32;
 const GEOMETRY_INDEX_SENTINEL: BvhNodeIndex = u32::MAX;
+// Maximum depth of a CompactBvh, sized so that the traversal stack can live on the stack
+const MAX_TRAVERSAL_DEPTH: usize = 64;
+// Below this depth splits are made at the median so that the tree always fits in MAX_TRAVERSAL_DEPTH
+const MAX_SAH_DEPTH: usize = MAX_TRAVERSAL_DEPTH / 2;
+
+// Relative costs of visiting a node and intersecting a hitable used by the surface area heuristic
+const TRAVERSAL_COST: f32 = 0.125;
+const INTERSECTION_COST: f32 = 1.0;
+const SAH_BIN_COUNT: usize = 12;
+pub const DEFAULT_MAX_LEAF_SIZE: usize = 4;
 
 // TODO: Make an arena based tree that stores the node's index + child indexes - makes nodes bigger but can be more easily created and ordered
 // Read this: https://rcoh.me/posts/cache-oblivious-datastructures/
@@ -40,92 +48,63 @@ const GEOMETRY_INDEX_SENTINEL: BvhNodeIndex = u32::MAX;
 //    }
 //}
 
-#[derive(Debug, Clone, Copy)]
-struct BvhNodeRef {
-    // TODO: Use an enum to indicate if it's a geometry index or a node index
-    index: BvhNodeIndex,
-    // Start with this and then make do something smart like a geometry ref counter and check if == to number of references or something and always put geometry references on the left
-    is_geometry: bool,
-}
-
-impl BvhNodeRef {
-    fn new() -> BvhNodeRef {
-        BvhNodeRef {
-            index: GEOMETRY_INDEX_SENTINEL,
-            is_geometry: false
-        }
-    }
-
-    fn is_sentinel(&self) -> bool {
-        self.index == GEOMETRY_INDEX_SENTINEL
-    }
-}
-
 #[derive(Debug)]
 struct CompactBvhNode {
     // TODO: Try giving this an id so that the Vec<CompactBvhNode> can be sorted as needed
     bbox: AABBVolume,
-    left: BvhNodeRef,
-    right: BvhNodeRef
+    // Leaves: index of the first geometry index in CompactBvh::geometry
+    // Aggregate nodes: index of the right child, the left child is always the next node
+    offset: BvhNodeIndex,
+    // Number of geometry indexes in a leaf, 0 for aggregate nodes
+    count: u32,
+    // Axis the children were split along, the left child holds the children with the smaller coordinates
+    axis: u8,
 }
 
 impl CompactBvhNode {
-    fn set_ref(node_ref: &mut BvhNodeRef, hitables: &mut [(usize, AABBVolume)], nodes: &mut Vec<CompactBvhNode>, t_min: f32, t_max: f32) -> AABBVolume {
-        // TODO: handle/panic if hitables is empty
-        if hitables.len() == 1 {
-            node_ref.index = hitables[0].0 as BvhNodeIndex;
-            node_ref.is_geometry = true;
-            hitables[0].1
-        } else {
-            let index = CompactBvhNode::create_node(hitables, nodes, t_min, t_max);
-            node_ref.index = index;
-            node_ref.is_geometry = false;
-            nodes[index as usize].bbox
-        }
+    fn is_leaf(&self) -> bool {
+        self.count > 0
     }
 
     fn create_node(
         hitables: &mut [(usize, AABBVolume)],
         nodes: &mut Vec<CompactBvhNode>,
-        t_min: f32,
-        t_max: f32,
-    ) -> BvhNodeIndex {
-        let current_index = nodes.len() as BvhNodeIndex;
-        let mut hitables = hitables;
-
-        // sort data
-        sort_entities(&mut hitables);
+        geometry: &mut Vec<usize>,
+        max_leaf_size: usize,
+        depth: usize,
+    ) {
+        assert!(depth < MAX_TRAVERSAL_DEPTH, "BVH is deeper than the traversal stack ({} levels)", MAX_TRAVERSAL_DEPTH);
+        let current_index = nodes.len();
 
         nodes.push(CompactBvhNode {
-            bbox: AABBVolume::zero(),
-            left: BvhNodeRef::new(),
-            right: BvhNodeRef::new(),
+            bbox: bounds_of(hitables),
+            offset: 0,
+            count: 0,
+            axis: 0,
         });
 
-        // TODO: Think up a less horrible way to do the correct rounding
-        let pivot_idx = (hitables.len() as f32 / 2.0).ceil() as usize;
-//        let pivot_idx = hitables.len()/ 2;
-        let (left_hitables, right_hitables) = hitables.split_at_mut(pivot_idx);
-
-        let mut left_ref = BvhNodeRef::new();
-        let mut node_bbox = CompactBvhNode::set_ref(&mut left_ref, left_hitables, nodes, t_min, t_max);
-
-        let mut right_ref = BvhNodeRef::new();
-        if !right_hitables.is_empty() {
-            let box_right = CompactBvhNode::set_ref(&mut right_ref, right_hitables, nodes, t_min, t_max);
-            node_bbox = surrounding_box(node_bbox, box_right);
+        match split_hitables(hitables, max_leaf_size, depth) {
+            Some((axis, pivot)) => {
+                let (left_hitables, right_hitables) = hitables.split_at_mut(pivot);
+                CompactBvhNode::create_node(left_hitables, nodes, geometry, max_leaf_size, depth + 1);
+                let right_index = nodes.len() as BvhNodeIndex;
+                CompactBvhNode::create_node(right_hitables, nodes, geometry, max_leaf_size, depth + 1);
+
+                let node = &mut nodes[current_index];
+                node.offset = right_index;
+                node.axis = axis;
+            },
+            None => {
+                let node = &mut nodes[current_index];
+                node.offset = geometry.len() as BvhNodeIndex;
+                node.count = hitables.len() as u32;
+                geometry.extend(hitables.iter().map(|hitable| hitable.0));
+            },
         }
-        let node = &mut nodes[current_index as usize];
-        node.left = left_ref;
-        node.right = right_ref;
-        node.bbox = node_bbox;
-
-        current_index
     }
 }
 
-// TODO: Add a flag to indicate whether it is a geometry node or not so that geometry nodes can contain 2 geometry indexes
-// Thereby reducing the number of nodes and the depth of the tree
+// Leaves of the CompactBvh can hold several geometry indexes, which handles the cases below
 /*
     Handle this case better (x = aggregate nodes, o = geometry nodes)
         x
@@ -194,71 +173,109 @@ pub struct CompactBvh {
 
     // TODO: Test using a proper arena type instead of writing own version
     nodes: Vec<CompactBvhNode>,
+    // Geometry indexes in leaf order, each leaf refers to a contiguous range
+    geometry: Vec<usize>,
 }
 
 impl CompactBvh {
-    pub fn new(entities: &Entities, t_min: f32, t_max: f32) -> CompactBvh {
-        use scene::HitableRef;
-        let mut nodes = Vec::with_capacity(entities.len() * 2);
-
-        // FIXME: Instead of effectively making a copy of the hitables list, sort the original Vec<Entity> (for mem/cache access/locality reasons)
-        let mut hitables: Vec<(HitableRef, AABBVolume)> = entities.entities.iter().map(|entity| {
-            let id = entity.hitable_id;
-            (id, entities.get_hitable(id).bounding_box(t_min, t_max).unwrap())
-        }).collect();
-
-        {
-            CompactBvhNode::create_node(
-                &mut hitables,
-                &mut nodes,
-                t_min,
-                t_max,
-            );
-        }
+    pub fn new(entities: &Entities, t_min: f32, t_max: f32, max_leaf_size: usize) -> Result<CompactBvh, String> {
+        let hitables = entity_bounds(entities, t_min, t_max)?;
+        let hitable_count = hitables.len();
+
+        let bvh = CompactBvh::from_bounds(hitables, max_leaf_size);
 
-        println!("created BVH of {} nodes using list of {}", nodes.len(), hitables.len());
+        println!("created compact BVH using list of {}: {}", hitable_count, bvh.stats());
 
-        CompactBvh { nodes }
+        Ok(bvh)
     }
 
-    fn hit_ref(&self, node_ref: BvhNodeRef, entities: &Entities, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
-        // TODO: Panic if the node_ref is a sentinel and not either aggregate or geometry?
-        if node_ref.is_geometry {
-            entities.get_hitable(node_ref.index as usize).hit_ptr(entities, ray, t_min, t_max, hit_record)
-        } else {
-            // Continue searching recursively
-            self.hit_internal_ptr(node_ref.index, entities, ray, t_min, t_max, hit_record)
+    // Builds a tree over anything that can be referred to by an index (e.g. the triangles of a mesh)
+    pub fn from_bounds(mut hitables: Vec<(usize, AABBVolume)>, max_leaf_size: usize) -> CompactBvh {
+        assert!(max_leaf_size > 0, "BVH leaves must be able to hold at least one hitable");
+        let mut nodes = Vec::with_capacity(hitables.len() * 2);
+        let mut geometry = Vec::with_capacity(hitables.len());
+
+        if !hitables.is_empty() {
+            CompactBvhNode::create_node(&mut hitables, &mut nodes, &mut geometry, max_leaf_size, 0);
         }
-    }
 
-    fn hit_internal_ptr(
-        &self,
-        node_idx: BvhNodeIndex,
-        entities: &Entities,
-        ray: &Ray,
-        t_min: f32,
-        t_max: f32,
-        hit_record: &mut HitRecord,
-    ) -> bool {
-        let node = &self.nodes[node_idx as usize];
+        CompactBvh { nodes, geometry }
+    }
 
-        if node.bbox.hit(ray, t_min, t_max) {
-            // FIXME: Various Bvh node/tree hits still seem to be around half the runtime of Bvh tests. Try and work out why
-            // FIXME: Does not seem to work correctly. e.g. shadow is missing on sphere in simple_light scene
-            if self.hit_ref(node.left, entities, ray, t_min, t_max, hit_record) {
-                self.hit_ref(node.right, entities, ray, t_min, t_max, hit_record);
+    pub fn bounding_box(&self) -> Option<AABBVolume> {
+        self.nodes.first().map(|node| node.bbox)
+    }
 
-                return true;
-            } else {
-                return self.hit_ref(node.right, entities, ray, t_min, t_max, hit_record);
+    pub fn stats(&self) -> BvhStats {
+        let mut stats = BvhStats::default();
+        let root_area = match self.nodes.first() {
+            Some(root) => root.bbox.surface_area(),
+            None => return stats,
+        };
+
+        let mut stack = vec![(0, 1)];
+        while let Some((index, depth)) = stack.pop() {
+            let node = &self.nodes[index];
+            stats.add_node(&node.bbox, root_area, depth, node.count as usize);
+            if !node.is_leaf() {
+                stack.push((index + 1, depth + 1));
+                stack.push((node.offset as usize, depth + 1));
             }
         }
 
-        false
+        stats
     }
 
-    pub fn hit(&self, entities: &Entities, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
-        self.hit_internal_ptr(0, entities, ray, t_min, t_max, hit_record)
+    // hit_geometry is called with the index of each leaf the ray reaches and the current t range
+    // Children are visited nearest first and the closest hit so far is used to skip nodes that are further away
+    pub fn hit_with<F>(&self, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord, mut hit_geometry: F) -> bool
+    where
+        F: FnMut(usize, f32, f32, &mut HitRecord) -> bool,
+    {
+        if self.nodes.is_empty() {
+            return false;
+        }
+
+        let direction = ray.direction();
+        let mut stack: [BvhNodeIndex; MAX_TRAVERSAL_DEPTH + 1] = [0; MAX_TRAVERSAL_DEPTH + 1];
+        let mut stack_len = 1;
+
+        let mut hit_anything = false;
+        let mut closest_so_far = t_max;
+
+        while stack_len > 0 {
+            stack_len -= 1;
+            let node_index = stack[stack_len];
+            let node = &self.nodes[node_index as usize];
+
+            if !node.bbox.hit(ray, t_min, closest_so_far) {
+                continue;
+            }
+
+            if node.is_leaf() {
+                let start = node.offset as usize;
+                for &index in &self.geometry[start..start + node.count as usize] {
+                    if hit_geometry(index, t_min, closest_so_far, hit_record) {
+                        hit_anything = true;
+                        closest_so_far = hit_record.t;
+                    }
+                }
+                continue;
+            }
+
+            let (near, far) = if direction[node.axis as usize] < 0.0 {
+                (node.offset, node_index + 1)
+            } else {
+                (node_index + 1, node.offset)
+            };
+
+            // Push the far child first so that the near child is popped first
+            stack[stack_len] = far;
+            stack[stack_len + 1] = near;
+            stack_len += 2;
+        }
+
+        hit_anything
     }
 }
 
@@ -275,14 +292,6 @@ impl BvhNode {
         self.bbox
     }
 
-    fn left(&self) -> BvhNodeIndex {
-        self.left
-    }
-
-    fn right(&self) -> BvhNodeIndex {
-        self.right
-    }
-
     fn geom_index(&self) -> BvhNodeIndex {
         self.left
     }
@@ -298,11 +307,13 @@ impl BvhNode {
         nodes: &mut Vec<BvhNode>,
         t_min: f32,
         t_max: f32,
+        depth: usize,
     ) -> BvhNodeIndex {
         let current_index = nodes.len() as BvhNodeIndex;
 
-        // sort data
-        sort_entities(&mut hitables[low_idx as usize..high_idx as usize]);
+        // Leaves only hold a single hitable so there is always a split
+        let (_, pivot) = split_hitables(&mut hitables[low_idx as usize..high_idx as usize], 1, depth)
+            .expect("more than one hitable is always split");
 
         nodes.push(BvhNode {
             bbox: AABBVolume::zero(),
@@ -310,9 +321,9 @@ impl BvhNode {
             right: 0,
         });
 
-        let pivot_idx = low_idx + (high_idx - low_idx) / 2;
-        let left_node_index = BvhNode::create_node(low_idx, pivot_idx, hitables, nodes, t_min, t_max);
-        let right_node_index = BvhNode::create_node(pivot_idx, high_idx, hitables, nodes, t_min, t_max);
+        let pivot_idx = low_idx + pivot as BvhNodeIndex;
+        let left_node_index = BvhNode::create_node(low_idx, pivot_idx, hitables, nodes, t_min, t_max, depth + 1);
+        let right_node_index = BvhNode::create_node(pivot_idx, high_idx, hitables, nodes, t_min, t_max, depth + 1);
 
         let box_left = nodes[left_node_index as usize].bounding_box();
         let box_right = nodes[right_node_index as usize].bounding_box();
@@ -329,8 +340,8 @@ impl BvhNode {
         data_index: BvhNodeIndex,
         hitables: &mut [(usize, AABBVolume)],
         nodes: &mut Vec<BvhNode>,
-        t_min: f32,
-        t_max: f32,
+        _t_min: f32,
+        _t_max: f32,
     ) -> BvhNodeIndex {
         let current_index = nodes.len() as BvhNodeIndex;
 
@@ -350,34 +361,216 @@ impl BvhNode {
         nodes: &mut Vec<BvhNode>,
         t_min: f32,
         t_max: f32,
+        depth: usize,
     ) -> BvhNodeIndex {
         // FIXME: Need to be able to do this in a loop instead of recursively because of stack overflowing
         if low_index == high_index || low_index + 1 == high_index {
             BvhNode::create_leaf_node(low_index, hitables, nodes, t_min, t_max)
         } else {
-            BvhNode::create_aggregate_node(low_index, high_index, hitables, nodes, t_min, t_max)
+            BvhNode::create_aggregate_node(low_index, high_index, hitables, nodes, t_min, t_max, depth)
         }
     }
 }
 
-fn arena_bbox_x_compare(box_1: &(usize, AABBVolume), box_2: &(usize, AABBVolume)) -> Ordering {
-    box_1.1.min().x().partial_cmp(&box_2.1.min().x()).unwrap()
+// Bounds of every entity over the shutter, which the top level of the scene needs for all of them
+fn entity_bounds(entities: &Entities, t_min: f32, t_max: f32) -> Result<Vec<(HitableRef, AABBVolume)>, String> {
+    // FIXME: Instead of effectively making a copy of the hitables list, sort the original Vec<Entity> (for mem/cache access/locality reasons)
+    entities.entities.iter().map(|entity| {
+        let id = entity.hitable_id;
+        match entities.get_hitable(id).bounding_box(entities, t_min, t_max) {
+            Some(aabb) => Ok((id, aabb)),
+            None => Err(format!("entity {} has no bounding box", id)),
+        }
+    }).collect()
 }
 
-fn arena_bbox_y_compare(box_1: &(usize, AABBVolume), box_2: &(usize, AABBVolume)) -> Ordering {
-    box_1.1.min().y().partial_cmp(&box_2.1.min().y()).unwrap()
+fn bounds_of(hitables: &[(usize, AABBVolume)]) -> AABBVolume {
+    hitables[1..].iter().fold(hitables[0].1, |bbox, hitable| surrounding_box(bbox, hitable.1))
 }
 
-fn arena_bbox_z_compare(box_1: &(usize, AABBVolume), box_2: &(usize, AABBVolume)) -> Ordering {
-    box_1.1.min().z().partial_cmp(&box_2.1.min().z()).unwrap()
+fn bin_index(centroid: f32, min: f32, extent: f32) -> usize {
+    (((centroid - min) / extent * SAH_BIN_COUNT as f32) as usize).min(SAH_BIN_COUNT - 1)
+}
+
+// Moves the hitables that match goes_left to the front and returns how many there are
+fn partition<F: Fn(&AABBVolume) -> bool>(hitables: &mut [(usize, AABBVolume)], goes_left: F) -> usize {
+    let mut pivot = 0;
+    for index in 0..hitables.len() {
+        if goes_left(&hitables[index].1) {
+            hitables.swap(index, pivot);
+            pivot += 1;
+        }
+    }
+    pivot
+}
+
+// Fallback for when the surface area heuristic can't separate the hitables (e.g. they all share a centroid)
+fn median_split(hitables: &mut [(usize, AABBVolume)], axis: usize) -> (u8, usize) {
+    // sort_by is stable so hitables with equal centroids keep their input order
+    hitables.sort_by(|a, b| {
+        a.1.centroid()[axis].partial_cmp(&b.1.centroid()[axis]).unwrap_or(Ordering::Equal)
+    });
+    (axis as u8, hitables.len() / 2)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SahBin {
+    bbox: Option<AABBVolume>,
+    count: usize,
+}
+
+fn merge(bbox: Option<AABBVolume>, other: AABBVolume) -> AABBVolume {
+    match bbox {
+        Some(bbox) => surrounding_box(bbox, other),
+        None => other,
+    }
 }
 
-fn sort_entities(hitables: &mut [(usize, AABBVolume)]) {
-    match drand48() as u8 * 3 {
-        0 => hitables.sort_by(arena_bbox_x_compare),
-        1 => hitables.sort_by(arena_bbox_y_compare),
-        _ => hitables.sort_by(arena_bbox_z_compare),
+// Binned surface area heuristic, credit to Wald - https://doi.org/10.1109/RT.2007.4342588
+// Partitions the hitables in place and returns the split axis and the index of the first hitable on the right,
+// or None if a leaf is cheaper. Groups of more than max_leaf_size hitables are always split
+fn split_hitables(hitables: &mut [(usize, AABBVolume)], max_leaf_size: usize, depth: usize) -> Option<(u8, usize)> {
+    let count = hitables.len();
+    if count <= 1 {
+        return None;
+    }
+
+    let first_centroid = hitables[0].1.centroid();
+    let centroid_bounds = hitables[1..].iter().fold(AABBVolume::new(first_centroid, first_centroid), |bbox, hitable| {
+        let centroid = hitable.1.centroid();
+        surrounding_box(bbox, AABBVolume::new(centroid, centroid))
+    });
+    let centroid_min = centroid_bounds.min();
+    let extent = centroid_bounds.max() - centroid_min;
+    let widest_axis = if extent.x() > extent.y() && extent.x() > extent.z() {
+        0
+    } else if extent.y() > extent.z() {
+        1
+    } else {
+        2
+    };
+
+    let fallback = |hitables: &mut [(usize, AABBVolume)]| {
+        if count <= max_leaf_size {
+            None
+        } else {
+            Some(median_split(hitables, widest_axis))
+        }
     };
+
+    if depth >= MAX_SAH_DEPTH || extent[widest_axis] <= 0.0 {
+        return fallback(hitables);
+    }
+
+    // Flat groups have no area so the costs are relative to a tiny area instead
+    let area = bounds_of(hitables).surface_area().max(f32::MIN_POSITIVE);
+    let leaf_cost = INTERSECTION_COST * count as f32;
+
+    // (cost, axis, first bin on the right)
+    let mut best: Option<(f32, usize, usize)> = None;
+    for axis in 0..3 {
+        if extent[axis] <= 0.0 {
+            continue;
+        }
+
+        let mut bins = [SahBin { bbox: None, count: 0 }; SAH_BIN_COUNT];
+        for hitable in hitables.iter() {
+            let bin = &mut bins[bin_index(hitable.1.centroid()[axis], centroid_min[axis], extent[axis])];
+            bin.count += 1;
+            bin.bbox = Some(merge(bin.bbox, hitable.1));
+        }
+
+        // Sweep from the right so each boundary knows the area and count of everything after it
+        let mut right_areas = [0.0; SAH_BIN_COUNT];
+        let mut right_counts = [0; SAH_BIN_COUNT];
+        let mut right_bbox = None;
+        let mut right_count = 0;
+        for bin in (1..SAH_BIN_COUNT).rev() {
+            right_count += bins[bin].count;
+            if let Some(bbox) = bins[bin].bbox {
+                right_bbox = Some(merge(right_bbox, bbox));
+            }
+            right_counts[bin] = right_count;
+            right_areas[bin] = right_bbox.map_or(0.0, |bbox| bbox.surface_area());
+        }
+
+        let mut left_bbox = None;
+        let mut left_count = 0;
+        for bin in 1..SAH_BIN_COUNT {
+            left_count += bins[bin - 1].count;
+            if let Some(bbox) = bins[bin - 1].bbox {
+                left_bbox = Some(merge(left_bbox, bbox));
+            }
+            if left_count == 0 || right_counts[bin] == 0 {
+                continue;
+            }
+
+            let left_area = left_bbox.map_or(0.0, |bbox| bbox.surface_area());
+            let cost = TRAVERSAL_COST
+                + INTERSECTION_COST * (left_count as f32 * left_area + right_counts[bin] as f32 * right_areas[bin]) / area;
+            if best.is_none_or(|(best_cost, _, _)| cost < best_cost) {
+                best = Some((cost, axis, bin));
+            }
+        }
+    }
+
+    match best {
+        Some((cost, axis, bin)) if count > max_leaf_size || cost < leaf_cost => {
+            let (min, axis_extent) = (centroid_min[axis], extent[axis]);
+            let pivot = partition(hitables, |bbox| bin_index(bbox.centroid()[axis], min, axis_extent) < bin);
+            Some((axis as u8, pivot))
+        },
+        Some(_) => None,
+        None => fallback(hitables),
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct BvhStats {
+    pub nodes: usize,
+    pub leaves: usize,
+    pub geometry: usize,
+    pub depth: usize,
+    // Expected cost of a ray that hits the root, relative to intersecting a single hitable
+    pub sah_cost: f32,
+}
+
+impl BvhStats {
+    fn add_node(&mut self, bbox: &AABBVolume, root_area: f32, depth: usize, geometry_count: usize) {
+        // A tree with a single flat leaf has no area to be relative to
+        let relative_area = if root_area > 0.0 { bbox.surface_area() / root_area } else { 1.0 };
+
+        self.nodes += 1;
+        self.depth = self.depth.max(depth);
+        if geometry_count > 0 {
+            self.leaves += 1;
+            self.geometry += geometry_count;
+            self.sah_cost += INTERSECTION_COST * geometry_count as f32 * relative_area;
+        } else {
+            self.sah_cost += TRAVERSAL_COST * relative_area;
+        }
+    }
+
+    pub fn average_leaf_size(&self) -> f32 {
+        if self.leaves == 0 {
+            0.0
+        } else {
+            self.geometry as f32 / self.leaves as f32
+        }
+    }
+}
+
+impl fmt::Display for BvhStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} nodes, depth {}, SAH cost {:.2}, average leaf size {:.2}",
+            self.nodes,
+            self.depth,
+            self.sah_cost,
+            self.average_leaf_size()
+        )
+    }
 }
 
 #[derive(Debug)]
@@ -392,66 +585,96 @@ pub struct Bvh {
 }
 
 impl Bvh {
-    pub fn new(entities: &Entities, t_min: f32, t_max: f32) -> Bvh {
-        use scene::HitableRef;
-        let mut nodes = Vec::with_capacity(entities.len() * 2);
-
-        // FIXME: Instead of effectively making a copy of the hitables list, sort the original Vec<Entity> (for mem/cache access/locality reasons)
-        let mut hitables: Vec<(HitableRef, AABBVolume)> = entities.entities.iter().map(|entity| {
-            let id = entity.hitable_id;
-            (id, entities.get_hitable(id).bounding_box(t_min, t_max).unwrap())
-        }).collect();
-
-        {
-            BvhNode::create_node(
-                0 as BvhNodeIndex,
-                hitables.len() as BvhNodeIndex,
-                &mut hitables,
-                &mut nodes,
-                t_min,
-                t_max,
-            );
+    pub fn new(entities: &Entities, t_min: f32, t_max: f32) -> Result<Bvh, String> {
+        let hitables = entity_bounds(entities, t_min, t_max)?;
+        let hitable_count = hitables.len();
+
+        let bvh = Bvh::from_bounds(hitables, t_min, t_max);
+
+        println!("created BVH using list of {}: {}", hitable_count, bvh.stats());
+
+        Ok(bvh)
+    }
+
+    // Builds a tree over anything that can be referred to by an index (e.g. the triangles of a mesh)
+    pub fn from_bounds(mut hitables: Vec<(usize, AABBVolume)>, t_min: f32, t_max: f32) -> Bvh {
+        let mut nodes = Vec::with_capacity(hitables.len() * 2);
+        if hitables.is_empty() {
+            return Bvh { nodes };
         }
 
-        println!("created BVH of {} nodes using list of {}", nodes.len(), hitables.len());
+        BvhNode::create_node(
+            0 as BvhNodeIndex,
+            hitables.len() as BvhNodeIndex,
+            &mut hitables,
+            &mut nodes,
+            t_min,
+            t_max,
+            0,
+        );
 
         Bvh { nodes }
     }
 
-    // TODO: Implement an iterative version that won't be able to blow up the stack
-    fn hit_internal_ptr(
+    pub fn stats(&self) -> BvhStats {
+        let mut stats = BvhStats::default();
+        let root_area = match self.nodes.first() {
+            Some(root) => root.bbox.surface_area(),
+            None => return stats,
+        };
+
+        let mut stack = vec![(0, 1)];
+        while let Some((index, depth)) = stack.pop() {
+            let node = &self.nodes[index as usize];
+            if node.is_geometry_node() {
+                stats.add_node(&node.bbox, root_area, depth, 1);
+            } else {
+                stats.add_node(&node.bbox, root_area, depth, 0);
+                stack.push((node.left, depth + 1));
+                stack.push((node.right, depth + 1));
+            }
+        }
+
+        stats
+    }
+
+    // Kept for comparison with CompactBvh, which traverses iteratively so it cannot blow up the stack
+    fn hit_internal_ptr<F>(
         &self,
         node_idx: BvhNodeIndex,
-        entities: &Entities,
         ray: &Ray,
         t_min: f32,
         t_max: f32,
         hit_record: &mut HitRecord,
-    ) -> bool {
+        hit_geometry: &mut F,
+    ) -> bool
+    where
+        F: FnMut(usize, f32, f32, &mut HitRecord) -> bool,
+    {
         let node = &self.nodes[node_idx as usize];
 
         if node.bbox.hit(ray, t_min, t_max) {
 //            println!("hit node: {} (is_geometry {})", node_idx, node.is_geometry_node());
             if node.is_geometry_node() {
-                return entities.get_hitable(node.geom_index() as usize).hit_ptr(entities, ray, t_min, t_max, hit_record);
-            } else if self.hit_internal_ptr(node.left, entities, ray, t_min, t_max, hit_record) {
+                return hit_geometry(node.geom_index() as usize, t_min, t_max, hit_record);
+            } else if self.hit_internal_ptr(node.left, ray, t_min, t_max, hit_record, hit_geometry) {
                 self.hit_internal_ptr(
                     node.right,
-                    entities,
                     ray,
                     t_min,
                     hit_record.t,
                     hit_record,
+                    hit_geometry,
                 );
                 return true;
             } else {
                 return self.hit_internal_ptr(
                     node.right,
-                    entities,
                     ray,
                     t_min,
                     t_max,
                     hit_record,
+                    hit_geometry,
                 );
             }
         }
@@ -459,22 +682,263 @@ impl Bvh {
         false
     }
 
-    pub fn hit(&self, entities: &Entities, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
-        self.hit_internal_ptr(0, entities, ray, t_min, t_max, hit_record)
+    // hit_geometry is called with the index of each leaf the ray reaches and the current t range
+    pub fn hit_with<F>(&self, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord, mut hit_geometry: F) -> bool
+    where
+        F: FnMut(usize, f32, f32, &mut HitRecord) -> bool,
+    {
+        if self.nodes.is_empty() {
+            return false;
+        }
+        self.hit_internal_ptr(0, ray, t_min, t_max, hit_record, &mut hit_geometry)
     }
 }
 
-// TODO: In order to correctly implement this, Bvh must own the Hitables/Entities collection
-//impl Hitable for Bvh {
-//    fn hit_ptr(&self, entities: &Entities, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
-//        self.hit(entities, ray, t_min, t_max, hit_record)
-//    }
-//
-//    fn bounding_box(&self, t_min: f32, t_max: f32) -> Option<AABBVolume> {
-//        if self.nodes.is_empty() {
-//            None
-//        } else {
-//            Some(self.nodes[0].bbox)
-//        }
-//    }
-//}
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BvhType {
+    Compact,
+    Recursive,
+}
+
+impl BvhType {
+    pub const NAMES: [&'static str; 2] = ["compact", "recursive"];
+}
+
+impl FromStr for BvhType {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<BvhType, String> {
+        match name {
+            "compact" => Ok(BvhType::Compact),
+            "recursive" => Ok(BvhType::Recursive),
+            _ => Err(format!("unknown BVH type '{}' (expected one of {})", name, BvhType::NAMES.join(", "))),
+        }
+    }
+}
+
+impl fmt::Display for BvhType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BvhType::Compact => write!(f, "compact"),
+            BvhType::Recursive => write!(f, "recursive"),
+        }
+    }
+}
+
+// Lets the acceleration structure used for the scene be picked at run time
+#[derive(Debug)]
+pub enum SceneBvh {
+    Compact(CompactBvh),
+    Recursive(Bvh),
+}
+
+impl SceneBvh {
+    // max_leaf_size only applies to the compact BVH, the recursive one always has a single hitable per leaf
+    pub fn new(bvh_type: BvhType, entities: &Entities, t_min: f32, t_max: f32, max_leaf_size: usize) -> Result<SceneBvh, String> {
+        Ok(match bvh_type {
+            BvhType::Compact => SceneBvh::Compact(CompactBvh::new(entities, t_min, t_max, max_leaf_size)?),
+            BvhType::Recursive => SceneBvh::Recursive(Bvh::new(entities, t_min, t_max)?),
+        })
+    }
+
+    // Returns which entity was hit if any, e.g. to tell whether a shadow ray reached the light it was aimed at
+    pub fn hit_entity(&self, entities: &Entities, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> Option<HitableRef> {
+        let mut hit_entity = None;
+        let hit_geometry = |index, t_min, t_max, hit_record: &mut HitRecord| {
+            let hit = entities.get_hitable(index).hit_ptr(entities, ray, t_min, t_max, hit_record);
+            if hit {
+                hit_entity = Some(index);
+            }
+            hit
+        };
+
+        match self {
+            SceneBvh::Compact(bvh) => bvh.hit_with(ray, t_min, t_max, hit_record, hit_geometry),
+            SceneBvh::Recursive(bvh) => bvh.hit_with(ray, t_min, t_max, hit_record, hit_geometry),
+        };
+
+        hit_entity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hitable::Hitable;
+    use random::{hash, random_sphere_direction, seed_stream};
+    use sampler::{Sampler, SobolSampler};
+    use scenes::{load_scene, SCENE_NAMES};
+    use vec3::Vec3;
+
+    use std::path::Path;
+
+    // Like SceneBvh::hit_entity, but volumes pick how far into them a ray goes at random so each hitable gets the same
+    // random numbers for a ray whichever order the BVH tries them in
+    fn hit(bvh: &SceneBvh, entities: &Entities, ray: &Ray, index: u64) -> Option<(HitableRef, f32)> {
+        let mut hit_entity = None;
+        let hit_geometry = |entity, t_min, t_max, hit_record: &mut HitRecord| {
+            seed_stream(index, entity as u64);
+            let hit = entities.get_hitable(entity).hit_ptr(entities, ray, t_min, t_max, hit_record);
+            if hit {
+                hit_entity = Some(entity);
+            }
+            hit
+        };
+
+        let mut hit_record = HitRecord::zero();
+        match bvh {
+            SceneBvh::Compact(bvh) => bvh.hit_with(ray, 0.001, f32::MAX, &mut hit_record, hit_geometry),
+            SceneBvh::Recursive(bvh) => bvh.hit_with(ray, 0.001, f32::MAX, &mut hit_record, hit_geometry),
+        };
+        hit_entity.map(|entity| (entity, hit_record.t))
+    }
+
+    // Surfaces that meet (e.g. the walls of a box) can both be hit at the same t, when either is the right answer
+    fn assert_same_hit(compact: &SceneBvh, recursive: &SceneBvh, entities: &Entities, ray: &Ray, index: u64) -> Option<f32> {
+        let expected = hit(recursive, entities, ray, index);
+        let found = hit(compact, entities, ray, index);
+        assert_eq!(found.map(|(_, t)| t), expected.map(|(_, t)| t), "ray {}", index);
+        if let (Some((entity, t)), Some((expected_entity, _))) = (found, expected) {
+            if entity != expected_entity {
+                seed_stream(index, entity as u64);
+                let mut hit_record = HitRecord::zero();
+                assert!(entities.get_hitable(entity).hit_ptr(entities, ray, 0.001, f32::MAX, &mut hit_record));
+                assert_eq!(hit_record.t, t, "ray {} hit entity {} rather than {}", index, entity, expected_entity);
+            }
+        }
+        expected.map(|(_, t)| t)
+    }
+
+    #[test]
+    fn compact_and_recursive_bvhs_hit_the_same_entities() {
+        for &name in SCENE_NAMES.iter() {
+            // These need image textures that aren't part of the repository
+            let image = match name {
+                "earth" => Some("earthmap.jpg"),
+                "final_scene" => Some("earthmap2.jpg"),
+                _ => None,
+            };
+            if image.is_some_and(|image| !Path::new(image).is_file()) {
+                continue;
+            }
+
+            let (scene, window) = load_scene(name, 32, 32, 1).unwrap();
+            let entities = &scene.resources.entities;
+            let (time0, time1) = window.camera.shutter();
+            let compact = SceneBvh::new(BvhType::Compact, entities, time0, time1, DEFAULT_MAX_LEAF_SIZE).unwrap();
+            let recursive = SceneBvh::new(BvhType::Recursive, entities, time0, time1, DEFAULT_MAX_LEAF_SIZE).unwrap();
+
+            // Camera rays, then a ray in a random direction from wherever each of them hit
+            let mut sampler = SobolSampler::new(5);
+            let mut hits = 0;
+            for pixel in 0..32 * 32 {
+                sampler.start_sample(pixel, 0);
+                let [u, v] = sampler.get_2d();
+                let ray = match window.camera.get_ray((u + (pixel % 32) as f32) / 32.0, (v + (pixel / 32) as f32) / 32.0, &mut sampler) {
+                    Some(ray) => ray,
+                    None => continue,
+                };
+                let index = pixel as u64 * 2;
+                if let Some(t) = assert_same_hit(&compact, &recursive, entities, &ray, index) {
+                    hits += 1;
+                    let bounce = Ray::new(ray.point_at_parameter(t), random_sphere_direction(sampler.get_2d()), ray.time());
+                    assert_same_hit(&compact, &recursive, entities, &bounce, index + 1);
+                }
+            }
+            assert!(hits > 0, "no camera rays hit {}", name);
+        }
+    }
+
+    #[derive(Debug)]
+    struct Unbounded;
+
+    impl Hitable for Unbounded {
+        fn hit_ptr(&self, _entities: &Entities, _ray: &Ray, _t_min: f32, _t_max: f32, _hit_record: &mut HitRecord) -> bool {
+            false
+        }
+
+        fn bounding_box(&self, _entities: &Entities, _t_min: f32, _t_max: f32) -> Option<AABBVolume> {
+            None
+        }
+    }
+
+    #[test]
+    fn builds_empty_bvhs_and_rejects_entities_without_bounds() {
+        let mut entities = Entities::new();
+        let ray = Ray::new(Vec3::zero(), Vec3::new(0.0, 0.0, 1.0), 0.0);
+        for &bvh_type in &[BvhType::Compact, BvhType::Recursive] {
+            let bvh = SceneBvh::new(bvh_type, &entities, 0.0, 1.0, DEFAULT_MAX_LEAF_SIZE).unwrap();
+            assert_eq!(hit(&bvh, &entities, &ray, 0), None);
+        }
+        assert!(Bvh::from_bounds(vec![], 0.0, 1.0).stats().nodes == 0);
+
+        entities.new_entity(Unbounded);
+        for &bvh_type in &[BvhType::Compact, BvhType::Recursive] {
+            let error = SceneBvh::new(bvh_type, &entities, 0.0, 1.0, DEFAULT_MAX_LEAF_SIZE).unwrap_err();
+            assert_eq!(error, "entity 0 has no bounding box");
+        }
+    }
+
+    fn unit_box(min: Vec3) -> AABBVolume {
+        AABBVolume::new(min, min + Vec3::uniform(1.0))
+    }
+
+    // Boxes of different sizes scattered through a cube, with some stacked on the same centroid and a run spaced
+    // further apart each time, which the SAH would split one at a time past the depth where it gives up
+    fn boxes() -> Vec<(usize, AABBVolume)> {
+        let random = |index: usize, axis: u64| to_float(hash(7, index as u64, axis));
+        let mut boxes: Vec<AABBVolume> = (0..500)
+            .map(|i| {
+                let min = Vec3::new(random(i, 0), random(i, 1), random(i, 2)) * 100.0;
+                AABBVolume::new(min, min + Vec3::new(random(i, 3), random(i, 4), random(i, 5)) * 5.0)
+            })
+            .collect();
+        boxes.extend((0..20).map(|_| unit_box(Vec3::uniform(50.0))));
+        boxes.extend((0..60).map(|i| unit_box(Vec3::new(4f32.powi(i), 0.0, 0.0))));
+        boxes.into_iter().enumerate().collect()
+    }
+
+    fn to_float(value: u64) -> f32 {
+        (value >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    #[test]
+    fn builds_the_same_tree_from_the_same_hitables() {
+        let first = CompactBvh::from_bounds(boxes(), DEFAULT_MAX_LEAF_SIZE);
+        let second = CompactBvh::from_bounds(boxes(), DEFAULT_MAX_LEAF_SIZE);
+        assert_eq!(first.geometry, second.geometry);
+        assert_eq!(format!("{:?}", first.nodes), format!("{:?}", second.nodes));
+    }
+
+    #[test]
+    fn leaves_hold_at_most_the_leaf_size() {
+        for &max_leaf_size in &[1, 2, 4, 8] {
+            let bvh = CompactBvh::from_bounds(boxes(), max_leaf_size);
+            let stats = bvh.stats();
+            assert!(bvh.nodes.iter().all(|node| node.count as usize <= max_leaf_size), "leaf size {}", max_leaf_size);
+            assert!(stats.depth <= MAX_TRAVERSAL_DEPTH, "depth {} with leaf size {}", stats.depth, max_leaf_size);
+            assert!(stats.depth > MAX_SAH_DEPTH, "depth {} never fell back to median splits", stats.depth);
+
+            // Every hitable is in exactly one leaf
+            let mut geometry = bvh.geometry.clone();
+            geometry.sort_unstable();
+            assert_eq!(geometry, (0..boxes().len()).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn counts_the_nodes_of_a_small_tree() {
+        // Four boxes in a row far enough apart that the SAH splits them into pairs and then single boxes
+        let hitables: Vec<(usize, AABBVolume)> = (0..4).map(|i| (i, unit_box(Vec3::new(i as f32 * 10.0, 0.0, 0.0)))).collect();
+
+        let stats = CompactBvh::from_bounds(hitables.clone(), DEFAULT_MAX_LEAF_SIZE).stats();
+        assert_eq!((stats.nodes, stats.leaves, stats.geometry, stats.depth), (7, 4, 4, 3));
+        assert_eq!(stats.average_leaf_size(), 1.0);
+        // The root is 31 x 1 x 1, each pair 11 x 1 x 1 and each leaf a unit cube
+        let expected_cost = TRAVERSAL_COST * (1.0 + 2.0 * 46.0 / 126.0) + INTERSECTION_COST * 4.0 * 6.0 / 126.0;
+        assert!((stats.sah_cost - expected_cost).abs() < 1e-5, "SAH cost {} not {}", stats.sah_cost, expected_cost);
+
+        let stats = Bvh::from_bounds(hitables, 0.0, 1.0).stats();
+        assert_eq!((stats.nodes, stats.leaves, stats.geometry, stats.depth), (7, 4, 4, 3));
+    }
+}
@@ -1,33 +1,53 @@
-use random::drand48;
+use random::random_in_unit_disk;
 use ray::Ray;
+use sampler::Sampler;
 use std::f32::consts::PI;
 use vec3::Vec3;
 
-fn random_in_unit_disk() -> Vec3 {
-    loop {
-        let p = 2.0 * Vec3::random_2d() - Vec3::uniform_2d(1.0);
-
-        if p.squared_length() < 1.0 {
-            return p;
-        }
-    }
+#[derive(Debug)]
+pub enum Projection {
+    // Thin lens, the image plane is at the focus distance
+    Perspective {
+        lower_left_corner: Vec3,
+        horizontal: Vec3,
+        vertical: Vec3,
+        lens_radius: f32,
+    },
+    // Parallel rays from a view plane of the given size centred on the camera's position
+    Orthographic {
+        width: f32,
+        height: f32,
+    },
+    // Equidistant fisheye, the distance from the centre of the image circle is proportional to the angle from the
+    // view direction. Pixels outside the circle don't see anything
+    Fisheye {
+        half_fov: f32,
+        aspect: f32,
+    },
+    // Longitude across the image and latitude up it, covering every direction
+    Equirectangular,
 }
 
 #[allow(clippy::too_many_arguments)]
 #[derive(Debug)]
 pub struct Camera {
     origin: Vec3,
-    lower_left_corner: Vec3,
-    horizontal: Vec3,
-    vertical: Vec3,
     u: Vec3,
     v: Vec3,
     w: Vec3,
-    lens_radius: f32,
+    projection: Projection,
     time0: f32,
     time1: f32,
 }
 
+// Orthonormal basis looking from lookfrom towards lookat, w points backwards so the view direction is -w
+fn basis(lookfrom: Vec3, lookat: Vec3, vup: Vec3) -> (Vec3, Vec3, Vec3) {
+    let w = (lookfrom - lookat).unit();
+    let u = Vec3::cross(&vup, &w).unit();
+    let v = Vec3::cross(&w, &u);
+    (u, v, w)
+}
+
 impl Camera {
     #[allow(clippy::too_many_arguments)]
     // vfov is top to bottom in degrees
@@ -46,9 +66,7 @@ impl Camera {
         let half_height = (theta / 2.0).tan();
         let half_width = aspect * half_height;
 
-        let w = (lookfrom - lookat).unit();
-        let u = Vec3::cross(&vup, &w).unit();
-        let v = Vec3::cross(&w, &u);
+        let (u, v, w) = basis(lookfrom, lookat, vup);
 
         let origin = lookfrom;
         let lower_left_corner =
@@ -58,26 +76,178 @@ impl Camera {
 
         Camera {
             origin,
-            lower_left_corner,
-            horizontal,
-            vertical,
             u,
             v,
             w,
-            lens_radius: aperture / 2.0,
+            projection: Projection::Perspective {
+                lower_left_corner,
+                horizontal,
+                vertical,
+                lens_radius: aperture / 2.0,
+            },
+            time0: t0,
+            time1: t1,
+        }
+    }
+
+    // view_height is the height of the visible part of the world
+    pub fn orthographic(lookfrom: Vec3, lookat: Vec3, vup: Vec3, view_height: f32, aspect: f32, t0: f32, t1: f32) -> Camera {
+        let (u, v, w) = basis(lookfrom, lookat, vup);
+        Camera {
+            origin: lookfrom,
+            u,
+            v,
+            w,
+            projection: Projection::Orthographic { width: view_height * aspect, height: view_height },
+            time0: t0,
+            time1: t1,
+        }
+    }
+
+    // fov is the angle in degrees across the image circle, which fits the shorter side of the image
+    // 180 gives a hemispherical view
+    pub fn fisheye(lookfrom: Vec3, lookat: Vec3, vup: Vec3, fov: f32, aspect: f32, t0: f32, t1: f32) -> Camera {
+        let (u, v, w) = basis(lookfrom, lookat, vup);
+        Camera {
+            origin: lookfrom,
+            u,
+            v,
+            w,
+            projection: Projection::Fisheye { half_fov: fov * PI / 360.0, aspect },
+            time0: t0,
+            time1: t1,
+        }
+    }
+
+    // The centre of the image looks at lookat and vup is straight up, best with an image twice as wide as it is high
+    pub fn equirectangular(lookfrom: Vec3, lookat: Vec3, vup: Vec3, t0: f32, t1: f32) -> Camera {
+        let (u, v, w) = basis(lookfrom, lookat, vup);
+        Camera {
+            origin: lookfrom,
+            u,
+            v,
+            w,
+            projection: Projection::Equirectangular,
             time0: t0,
             time1: t1,
         }
     }
 
-    pub fn get_ray(&self, s: f32, t: f32) -> Ray {
-        let rd = self.lens_radius * random_in_unit_disk();
-        let offset = self.u * rd.x() + self.v * rd.y();
-        let time = self.time0 + drand48() * (self.time1 - self.time0);
-        Ray::new(
-            self.origin + offset,
-            self.lower_left_corner + s * self.horizontal + t * self.vertical - self.origin - offset,
-            time,
-        )
+    // Times the shutter opens and closes, every ray is at a time between them
+    pub fn shutter(&self) -> (f32, f32) {
+        (self.time0, self.time1)
+    }
+
+    // s and t run from 0 to 1 across and up the image. Takes the lens and time dimensions from sampler whether or not
+    // the projection uses them. None if the point isn't covered by the projection
+    pub fn get_ray(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Option<Ray> {
+        let lens = sampler.get_2d();
+        let time = self.time0 + sampler.get_1d() * (self.time1 - self.time0);
+
+        match self.projection {
+            Projection::Perspective { lower_left_corner, horizontal, vertical, lens_radius } => {
+                let rd = lens_radius * random_in_unit_disk(lens);
+                let offset = self.u * rd.x() + self.v * rd.y();
+                Some(Ray::new(
+                    self.origin + offset,
+                    lower_left_corner + s * horizontal + t * vertical - self.origin - offset,
+                    time,
+                ))
+            },
+            Projection::Orthographic { width, height } => {
+                let origin = self.origin + (s - 0.5) * width * self.u + (t - 0.5) * height * self.v;
+                Some(Ray::new(origin, -self.w, time))
+            },
+            Projection::Fisheye { half_fov, aspect } => {
+                // Scaled so that the unit circle fits the shorter side
+                let (x, y) = if aspect >= 1.0 {
+                    ((2.0 * s - 1.0) * aspect, 2.0 * t - 1.0)
+                } else {
+                    (2.0 * s - 1.0, (2.0 * t - 1.0) / aspect)
+                };
+                let r = (x * x + y * y).sqrt();
+                if r > 1.0 {
+                    return None;
+                }
+
+                let theta = r * half_fov;
+                let phi = y.atan2(x);
+                let direction = theta.sin() * (phi.cos() * self.u + phi.sin() * self.v) - theta.cos() * self.w;
+                Some(Ray::new(self.origin, direction, time))
+            },
+            Projection::Equirectangular => {
+                let longitude = (s - 0.5) * 2.0 * PI;
+                let latitude = (t - 0.5) * PI;
+                let direction = latitude.cos() * (longitude.sin() * self.u - longitude.cos() * self.w)
+                    + latitude.sin() * self.v;
+                Some(Ray::new(self.origin, direction, time))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sampler::SobolSampler;
+
+    fn cameras() -> Vec<Camera> {
+        let (lookfrom, lookat, vup) = (Vec3::new(1.0, 2.0, 3.0), Vec3::new(-2.0, 0.5, -1.0), Vec3::new(0.0, 1.0, 0.0));
+        vec![
+            Camera::new(lookfrom, lookat, vup, 40.0, 1.5, 0.0, 5.0, 0.0, 1.0),
+            Camera::orthographic(lookfrom, lookat, vup, 4.0, 1.5, 0.0, 1.0),
+            Camera::fisheye(lookfrom, lookat, vup, 180.0, 1.5, 0.0, 1.0),
+            Camera::fisheye(lookfrom, lookat, vup, 270.0, 0.5, 0.0, 1.0),
+            Camera::equirectangular(lookfrom, lookat, vup, 0.0, 1.0),
+        ]
+    }
+
+    fn direction(camera: &Camera, s: f32, t: f32) -> Option<Vec3> {
+        camera.get_ray(s, t, &mut SobolSampler::new(1)).map(|ray| ray.direction().unit())
+    }
+
+    #[test]
+    fn centre_of_the_image_looks_at_lookat() {
+        for camera in cameras() {
+            let centre = direction(&camera, 0.5, 0.5).unwrap();
+            assert!((centre + camera.w).length() < 1e-5, "{:?} looks along {:?}", camera.projection, centre);
+        }
+    }
+
+    #[test]
+    fn fisheyes_see_nothing_outside_the_image_circle() {
+        let cameras = cameras();
+        let (wide, tall) = (&cameras[2], &cameras[3]);
+        // The circle touches the middle of the top and bottom of the wide image and the sides of the tall one
+        for &(s, t) in &[(0.0, 0.0), (1.0, 1.0), (0.1, 0.5), (0.9, 0.5), (0.5 + 0.5 / 1.5, 0.99)] {
+            assert!(direction(wide, s, t).is_none(), "{} {}", s, t);
+        }
+        for &(s, t) in &[(0.5, 0.0), (0.5, 1.0), (0.5 - 0.5 / 1.5 + 0.01, 0.5), (0.6, 0.6)] {
+            assert!(direction(wide, s, t).is_some(), "{} {}", s, t);
+        }
+        assert!(direction(tall, 0.5, 0.1).is_none() && direction(tall, 0.0, 0.5).is_some());
+
+        // 180 degrees across sees straight out to the sides at the edge of the circle
+        let edge = direction(wide, 0.5, 1.0).unwrap();
+        assert!(Vec3::dot(&edge, &wide.w).abs() < 1e-5 && (edge - wide.v).length() < 1e-5);
+    }
+
+    #[test]
+    fn equirectangular_covers_every_azimuth() {
+        let camera = &cameras()[4];
+        let steps = 720;
+        let mut covered = [false; 36];
+        for step in 0..=steps {
+            let d = direction(camera, step as f32 / steps as f32, 0.5).unwrap();
+            assert!(Vec3::dot(&d, &camera.v).abs() < 1e-5);
+            let azimuth = Vec3::dot(&d, &camera.u).atan2(-Vec3::dot(&d, &camera.w));
+            covered[(((azimuth + PI) / (2.0 * PI) * 36.0) as usize).min(35)] = true;
+        }
+        assert!(covered.iter().all(|&covered| covered));
+
+        // Both edges look straight back and the top and bottom straight up and down
+        for &(s, t, expected) in &[(0.0, 0.5, camera.w), (1.0, 0.5, camera.w), (0.3, 1.0, camera.v), (0.8, 0.0, -camera.v)] {
+            assert!((direction(camera, s, t).unwrap() - expected).length() < 1e-5, "{} {}", s, t);
+        }
     }
 }
@@ -0,0 +1,185 @@
+// Saves the accumulated samples of a progressive render so that it can be resumed
+// Random numbers are derived from the seed, pixel and sample index so the counts are all the state needed to carry on
+// with the same sequence
+//
+// The format is a text header followed by each pixel's statistics as little endian binary:
+//   raytracinginaweekend checkpoint 2
+//   <scene>
+//   <width> <height> <seed> <sampler> <samples> <max depth>
+//   (count: u32, sum: 3 x f32, mean luminance: f32, squared deviations: f32) per pixel
+
+use adaptive::PixelStats;
+use sampler::SamplerType;
+use vec3::Vec3;
+
+use std::fs::{rename, File};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+const MAGIC: &str = "raytracinginaweekend checkpoint 2";
+// Checkpoints before the samples and maximum depth were saved can't be checked against the settings
+const OLD_MAGIC: &str = "raytracinginaweekend checkpoint 1";
+const PIXEL_BYTES: usize = 24;
+
+// Everything the samples depend on, resuming with different settings would mix samples from two different renders.
+// The sample patterns of the stratified and low discrepancy samplers depend on the number of samples as well as the seed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub scene: String,
+    pub width: u32,
+    pub height: u32,
+    pub seed: u64,
+    pub sampler: SamplerType,
+    pub samples: u32,
+    pub max_depth: u8,
+}
+
+#[derive(Debug)]
+pub struct Checkpoint {
+    pub settings: Settings,
+    pub pixels: Vec<PixelStats>,
+}
+
+impl Checkpoint {
+    // Written to a temporary file first so a crash while saving doesn't lose the previous checkpoint
+    pub fn save<T: AsRef<Path>>(&self, path: T) -> Result<(), String> {
+        let path = path.as_ref();
+        let temporary = path.with_extension("tmp");
+        let error = |e: ::std::io::Error| format!("failed to write checkpoint '{}': {}", path.display(), e);
+
+        {
+            let mut writer = BufWriter::new(File::create(&temporary).map_err(error)?);
+            let settings = &self.settings;
+            write!(writer, "{}\n{}\n{} {} {} {} {} {}\n", MAGIC, settings.scene, settings.width, settings.height,
+                   settings.seed, settings.sampler, settings.samples, settings.max_depth).map_err(error)?;
+            for stats in &self.pixels {
+                let sum = stats.sum();
+                writer.write_all(&stats.count().to_le_bytes()).map_err(error)?;
+                for value in &[sum.r(), sum.g(), sum.b(), stats.mean_luminance(), stats.squared_deviations()] {
+                    writer.write_all(&value.to_le_bytes()).map_err(error)?;
+                }
+            }
+            writer.flush().map_err(error)?;
+        }
+
+        rename(&temporary, path).map_err(error)
+    }
+
+    pub fn load<T: AsRef<Path>>(path: T) -> Result<Checkpoint, String> {
+        let path = path.as_ref();
+        let error = |message: String| format!("failed to read checkpoint '{}': {}", path.display(), message);
+
+        let mut reader = BufReader::new(File::open(path).map_err(|e| error(e.to_string()))?);
+        let mut read_line = || {
+            let mut line = String::new();
+            match reader.read_line(&mut line) {
+                Ok(0) => Err(error("unexpected end of file".to_owned())),
+                Ok(_) => Ok(line.trim_end_matches('\n').to_owned()),
+                Err(e) => Err(error(e.to_string())),
+            }
+        };
+
+        match read_line()?.as_str() {
+            MAGIC => {},
+            OLD_MAGIC => return Err(error("written by an older version that didn't save the samples and maximum depth".to_owned())),
+            _ => return Err(error("not a checkpoint file".to_owned())),
+        }
+        let scene = read_line()?;
+        let settings = read_line()?;
+        let fields: Vec<&str> = settings.split_whitespace().collect();
+        if fields.len() != 6 {
+            return Err(error(format!("malformed settings '{}'", settings)));
+        }
+        let width: u32 = fields[0].parse().map_err(|_| error(format!("bad width '{}'", fields[0])))?;
+        let height: u32 = fields[1].parse().map_err(|_| error(format!("bad height '{}'", fields[1])))?;
+        let seed: u64 = fields[2].parse().map_err(|_| error(format!("bad seed '{}'", fields[2])))?;
+        let sampler: SamplerType = fields[3].parse().map_err(error)?;
+        let samples: u32 = fields[4].parse().map_err(|_| error(format!("bad samples '{}'", fields[4])))?;
+        let max_depth: u8 = fields[5].parse().map_err(|_| error(format!("bad maximum depth '{}'", fields[5])))?;
+
+        let pixel_count = width as usize * height as usize;
+        let mut bytes = Vec::with_capacity(pixel_count * PIXEL_BYTES);
+        reader.read_to_end(&mut bytes).map_err(|e| error(e.to_string()))?;
+        if bytes.len() != pixel_count * PIXEL_BYTES {
+            return Err(error(format!("expected {} bytes of pixels but found {}", pixel_count * PIXEL_BYTES, bytes.len())));
+        }
+
+        let pixels = bytes.chunks(PIXEL_BYTES)
+            .map(|chunk| {
+                let word = |i: usize| [chunk[i * 4], chunk[i * 4 + 1], chunk[i * 4 + 2], chunk[i * 4 + 3]];
+                let float = |i: usize| f32::from_le_bytes(word(i));
+                PixelStats::from_parts(
+                    u32::from_le_bytes(word(0)),
+                    Vec3::new(float(1), float(2), float(3)),
+                    float(4),
+                    float(5),
+                )
+            })
+            .collect();
+
+        let settings = Settings { scene, width, height, seed, sampler, samples, max_depth };
+        Ok(Checkpoint { settings, pixels })
+    }
+
+    pub fn check_matches(&self, settings: &Settings) -> Result<(), String> {
+        let saved = &self.settings;
+        if saved.scene != settings.scene {
+            return Err(format!("checkpoint is of scene '{}' not '{}'", saved.scene, settings.scene));
+        }
+        if (saved.width, saved.height) != (settings.width, settings.height) {
+            return Err(format!("checkpoint is {}x{} not {}x{}", saved.width, saved.height, settings.width, settings.height));
+        }
+        if saved.seed != settings.seed {
+            return Err(format!("checkpoint used seed {} not {}", saved.seed, settings.seed));
+        }
+        if saved.sampler != settings.sampler {
+            return Err(format!("checkpoint used the {} sampler not {}", saved.sampler, settings.sampler));
+        }
+        if saved.samples != settings.samples {
+            return Err(format!("checkpoint was rendered with {} samples not {}", saved.samples, settings.samples));
+        }
+        if saved.max_depth != settings.max_depth {
+            return Err(format!("checkpoint used a maximum depth of {} not {}", saved.max_depth, settings.max_depth));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::fs::remove_file;
+
+    fn settings() -> Settings {
+        Settings {
+            scene: "cornell_box".to_owned(),
+            width: 2,
+            height: 1,
+            seed: 7,
+            sampler: SamplerType::Sobol,
+            samples: 64,
+            max_depth: 50,
+        }
+    }
+
+    #[test]
+    fn loads_what_was_saved_and_rejects_other_settings() {
+        let pixels = vec![
+            PixelStats::from_parts(3, Vec3::new(0.5, 1.0, 1.5), 0.25, 0.125),
+            PixelStats::from_parts(0, Vec3::zero(), 0.0, 0.0),
+        ];
+        let path = temp_dir().join(format!("raytracinginaweekend-checkpoint-test-{}", std::process::id()));
+        Checkpoint { settings: settings(), pixels }.save(&path).unwrap();
+        let loaded = Checkpoint::load(&path);
+        remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+
+        assert_eq!(loaded.settings, settings());
+        assert_eq!(loaded.pixels[0].count(), 3);
+        assert_eq!(loaded.pixels[0].sum(), Vec3::new(0.5, 1.0, 1.5));
+        assert!(loaded.check_matches(&settings()).is_ok());
+        assert!(loaded.check_matches(&Settings { samples: 128, ..settings() }).is_err());
+        assert!(loaded.check_matches(&Settings { max_depth: 8, ..settings() }).is_err());
+    }
+}
@@ -0,0 +1,370 @@
+use adaptive::{AdaptiveSampling, DEFAULT_MIN_SAMPLES};
+use bvh::{BvhType, DEFAULT_MAX_LEAF_SIZE};
+use clap::{App, Arg, ArgMatches, Error, ErrorKind};
+use sampler::SamplerType;
+use scenes::SCENE_NAMES;
+use tiles::{TileOrder, DEFAULT_TILE_SIZE};
+use tonemap::{ToneMapOperator, ToneMapping};
+
+use std::env;
+use std::ffi::OsString;
+use std::path::Path;
+use std::str::FromStr;
+
+const DEFAULT_SCENE: &str = "simple_light";
+pub const SCENE_FILE_EXTENSION: &str = ".scene";
+const DEFAULT_OUTPUT: &str = "images/current_progress.jpg";
+// How often a --checkpoint is saved when neither --snapshot-passes nor --snapshot-seconds is given
+const DEFAULT_CHECKPOINT_SECONDS: f32 = 60.0;
+const SUPPORTED_EXTENSIONS: [&str; 9] = ["jpg", "jpeg", "png", "bmp", "ppm", "tiff", "hdr", "pfm", "exr"];
+
+#[derive(Debug)]
+pub struct Config {
+    pub scene: String,
+    pub width: u32,
+    pub height: u32,
+    pub samples: u32,
+    pub output: String,
+    pub max_depth: u8,
+    // None leaves rayon to pick the number of threads
+    pub threads: Option<usize>,
+    pub bvh: BvhType,
+    pub leaf_size: usize,
+    pub tone_mapping: ToneMapping,
+    // Renders with the same seed and settings produce identical images
+    pub seed: u64,
+    pub sampler: SamplerType,
+    // None takes the full number of samples in every pixel
+    pub adaptive: Option<AdaptiveSampling>,
+    // Image of the samples each pixel took
+    pub sample_count_output: Option<String>,
+    // Samples added to each pixel per pass when rendering progressively
+    pub pass_samples: u32,
+    pub snapshot_passes: Option<u32>,
+    pub snapshot_seconds: Option<f32>,
+    pub checkpoint: Option<String>,
+    pub resume: bool,
+    pub tile_size: u32,
+    pub tile_order: TileOrder,
+    pub list_scenes: bool,
+}
+
+fn validate_positive<T: FromStr + PartialOrd + Default>(value: String) -> Result<(), String> {
+    match value.parse::<T>() {
+        Ok(parsed) if parsed > T::default() => Ok(()),
+        _ => Err(format!("'{}' is not a positive whole number", value)),
+    }
+}
+
+fn validate_seed(value: String) -> Result<(), String> {
+    match value.parse::<u64>() {
+        Ok(_) => Ok(()),
+        _ => Err(format!("'{}' is not a whole number between 0 and {}", value, u64::MAX)),
+    }
+}
+
+fn validate_exposure(value: String) -> Result<(), String> {
+    match value.parse::<f32>() {
+        Ok(exposure) if exposure.is_finite() => Ok(()),
+        _ => Err(format!("'{}' is not a number of stops", value)),
+    }
+}
+
+fn validate_positive_float(value: String) -> Result<(), String> {
+    match value.parse::<f32>() {
+        Ok(parsed) if parsed > 0.0 && parsed.is_finite() => Ok(()),
+        _ => Err(format!("'{}' is not a positive number", value)),
+    }
+}
+
+// A single sample has no variance, so every pixel would count as converged after its first sample
+fn validate_min_samples(value: String) -> Result<(), String> {
+    match value.parse::<u32>() {
+        Ok(samples) if samples >= 2 => Ok(()),
+        _ => Err(format!("'{}' is not a whole number of at least 2", value)),
+    }
+}
+
+fn validate_depth(value: String) -> Result<(), String> {
+    match value.parse::<u8>() {
+        Ok(depth) if depth > 0 => Ok(()),
+        _ => Err(format!("'{}' is not a depth between 1 and {}", value, u8::MAX)),
+    }
+}
+
+fn validate_scene(value: String) -> Result<(), String> {
+    if SCENE_NAMES.contains(&value.as_str()) {
+        Ok(())
+    } else if value.ends_with(SCENE_FILE_EXTENSION) {
+        if Path::new(&value).is_file() {
+            Ok(())
+        } else {
+            Err(format!("scene file '{}' does not exist", value))
+        }
+    } else {
+        Err(format!("unknown scene '{}'. Use --list-scenes to see the available scenes", value))
+    }
+}
+
+fn validate_output(value: String) -> Result<(), String> {
+    let extension = Path::new(&value)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_lowercase());
+
+    match extension {
+        Some(ref extension) if SUPPORTED_EXTENSIONS.contains(&extension.as_str()) => Ok(()),
+        _ => Err(format!(
+            "'{}' does not end in a supported image extension ({})",
+            value,
+            SUPPORTED_EXTENSIONS.join(", ")
+        )),
+    }
+}
+
+fn app<'a, 'b>() -> App<'a, 'b> {
+    App::new("raytracinginaweekend")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Renders a built-in scene or a .scene file to an image, or to one image per frame for animated scenes")
+        .arg(Arg::with_name("scene")
+            .help("Name of a built-in scene or path to a .scene file to render")
+            .index(1)
+            .default_value(DEFAULT_SCENE)
+            .validator(validate_scene))
+        .arg(Arg::with_name("width")
+            .help("Width of the output image in pixels")
+            .short("W")
+            .long("width")
+            .takes_value(true)
+            .default_value("400")
+            .validator(validate_positive::<u32>))
+        .arg(Arg::with_name("height")
+            .help("Height of the output image in pixels")
+            .short("H")
+            .long("height")
+            .takes_value(true)
+            .default_value("400")
+            .validator(validate_positive::<u32>))
+        .arg(Arg::with_name("samples")
+            .help("Number of samples per pixel")
+            .short("s")
+            .long("samples")
+            .takes_value(true)
+            .default_value("100")
+            .validator(validate_positive::<u32>))
+        .arg(Arg::with_name("output")
+            .help("Path of the image to write, each frame of an animated scene adds its number to the name (frame_0001.png)")
+            .short("o")
+            .long("output")
+            .takes_value(true)
+            .default_value(DEFAULT_OUTPUT)
+            .validator(validate_output))
+        .arg(Arg::with_name("max-depth")
+            .help("Maximum number of bounces per ray")
+            .short("d")
+            .long("max-depth")
+            .takes_value(true)
+            .default_value("50")
+            .validator(validate_depth))
+        .arg(Arg::with_name("threads")
+            .help("Number of render threads (defaults to one per logical core)")
+            .short("t")
+            .long("threads")
+            .takes_value(true)
+            .validator(validate_positive::<usize>))
+        .arg(Arg::with_name("bvh")
+            .help("Acceleration structure used to find ray intersections")
+            .long("bvh")
+            .takes_value(true)
+            .possible_values(&BvhType::NAMES)
+            .default_value("compact"))
+        .arg(Arg::with_name("leaf-size")
+            .help("Maximum number of hitables in a leaf of the compact BVH")
+            .long("leaf-size")
+            .takes_value(true)
+            .validator(validate_positive::<usize>))
+        .arg(Arg::with_name("tonemap")
+            .help("Tone mapping operator used for 8 bit output")
+            .long("tonemap")
+            .takes_value(true)
+            .possible_values(&ToneMapOperator::NAMES)
+            .default_value("clamp"))
+        .arg(Arg::with_name("exposure")
+            .help("Exposure adjustment in stops applied before tone mapping")
+            .long("exposure")
+            .takes_value(true)
+            .allow_hyphen_values(true)
+            .default_value("0")
+            .validator(validate_exposure))
+        .arg(Arg::with_name("white-point")
+            .help("Radiance mapped to white by the reinhard-extended and hable operators (defaults to the brightest pixel and 11.2)")
+            .long("white-point")
+            .takes_value(true)
+            .validator(validate_positive_float))
+        .arg(Arg::with_name("seed")
+            .help("Seed for the random numbers used to build scenes and render them")
+            .long("seed")
+            .takes_value(true)
+            .default_value("0")
+            .validator(validate_seed))
+        .arg(Arg::with_name("sampler")
+            .help("How the random numbers for each pixel sample are chosen")
+            .long("sampler")
+            .takes_value(true)
+            .possible_values(&SamplerType::NAMES)
+            .default_value("sobol"))
+        .arg(Arg::with_name("adaptive-threshold")
+            .help("Stops sampling a pixel once the relative standard error of its luminance is below this, --samples becomes the maximum")
+            .long("adaptive-threshold")
+            .takes_value(true)
+            .validator(validate_positive_float))
+        .arg(Arg::with_name("min-samples")
+            .help("Samples every pixel takes before adaptive sampling can stop it, from 2 up to --samples (defaults to 16)")
+            .long("min-samples")
+            .takes_value(true)
+            .requires("adaptive-threshold")
+            .validator(validate_min_samples))
+        .arg(Arg::with_name("sample-count-output")
+            .help("Path of an image showing the fraction of --samples each pixel took")
+            .long("sample-count-output")
+            .takes_value(true)
+            .validator(validate_output))
+        .arg(Arg::with_name("pass-samples")
+            .help("Samples added to each pixel per pass when rendering progressively")
+            .long("pass-samples")
+            .takes_value(true)
+            .default_value("1")
+            .validator(validate_positive::<u32>))
+        .arg(Arg::with_name("snapshot-passes")
+            .help("Writes the image so far every this many passes")
+            .long("snapshot-passes")
+            .takes_value(true)
+            .validator(validate_positive::<u32>))
+        .arg(Arg::with_name("snapshot-seconds")
+            .help("Writes the image so far after a pass once this many seconds have passed since the last one")
+            .long("snapshot-seconds")
+            .takes_value(true)
+            .validator(validate_positive_float))
+        .arg(Arg::with_name("checkpoint")
+            .help("Path of a file to save the accumulated samples to with every snapshot, every minute if no snapshots are asked for")
+            .long("checkpoint")
+            .takes_value(true))
+        .arg(Arg::with_name("resume")
+            .help("Carries on from the samples in the --checkpoint file if it exists and skips frames that were already rendered")
+            .long("resume"))
+        .arg(Arg::with_name("tile-size")
+            .help("Width and height in pixels of the tiles the image is rendered in")
+            .long("tile-size")
+            .takes_value(true)
+            .validator(validate_positive::<u32>))
+        .arg(Arg::with_name("tile-order")
+            .help("Order the tiles are rendered in")
+            .long("tile-order")
+            .takes_value(true)
+            .possible_values(&TileOrder::NAMES)
+            .default_value("hilbert"))
+        .arg(Arg::with_name("list-scenes")
+            .help("Prints the names of the built-in scenes and exits")
+            .long("list-scenes"))
+}
+
+// Values have already been checked by the validators so parsing them cannot fail
+fn parse<T: FromStr>(matches: &ArgMatches, name: &str) -> T {
+    match matches.value_of(name).unwrap().parse() {
+        Ok(value) => value,
+        Err(_) => unreachable!("{} was not validated", name),
+    }
+}
+
+impl Config {
+    pub fn from_args() -> Config {
+        Config::from_arg_list(env::args_os()).unwrap_or_else(|e| e.exit())
+    }
+
+    // args starts with the name of the program, as the command line does
+    pub fn from_arg_list<I, T>(args: I) -> Result<Config, Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let matches = app().get_matches_from_safe(args)?;
+
+        if matches.is_present("min-samples") {
+            let min_samples: u32 = parse(&matches, "min-samples");
+            let samples: u32 = parse(&matches, "samples");
+            if min_samples > samples {
+                return Err(Error::with_description(
+                    &format!("--min-samples ({}) can't be more than --samples ({})", min_samples, samples),
+                    ErrorKind::ValueValidation,
+                ));
+            }
+        }
+
+        Ok(Config {
+            scene: matches.value_of("scene").unwrap().to_owned(),
+            width: parse(&matches, "width"),
+            height: parse(&matches, "height"),
+            samples: parse(&matches, "samples"),
+            output: matches.value_of("output").unwrap().to_owned(),
+            max_depth: parse(&matches, "max-depth"),
+            threads: matches.value_of("threads").map(|_| parse(&matches, "threads")),
+            bvh: parse(&matches, "bvh"),
+            tone_mapping: ToneMapping::new(
+                parse(&matches, "tonemap"),
+                parse(&matches, "exposure"),
+                matches.value_of("white-point").map(|_| parse(&matches, "white-point")),
+            ),
+            seed: parse(&matches, "seed"),
+            sampler: parse(&matches, "sampler"),
+            adaptive: matches.value_of("adaptive-threshold").map(|_| AdaptiveSampling::new(
+                parse(&matches, "adaptive-threshold"),
+                matches.value_of("min-samples").map_or(DEFAULT_MIN_SAMPLES, |_| parse(&matches, "min-samples")),
+            )),
+            sample_count_output: matches.value_of("sample-count-output").map(str::to_owned),
+            pass_samples: parse(&matches, "pass-samples"),
+            snapshot_passes: matches.value_of("snapshot-passes").map(|_| parse(&matches, "snapshot-passes")),
+            // A checkpoint is only any use if it is saved along the way, so it gets saved regularly by default
+            snapshot_seconds: match matches.value_of("snapshot-seconds") {
+                Some(_) => Some(parse(&matches, "snapshot-seconds")),
+                None if matches.is_present("checkpoint") && !matches.is_present("snapshot-passes") => {
+                    Some(DEFAULT_CHECKPOINT_SECONDS)
+                },
+                None => None,
+            },
+            checkpoint: matches.value_of("checkpoint").map(str::to_owned),
+            resume: matches.is_present("resume"),
+            tile_size: matches.value_of("tile-size").map_or(DEFAULT_TILE_SIZE, |_| parse(&matches, "tile-size")),
+            tile_order: parse(&matches, "tile-order"),
+            leaf_size: matches.value_of("leaf-size").map_or(DEFAULT_MAX_LEAF_SIZE, |_| parse(&matches, "leaf-size")),
+            list_scenes: matches.is_present("list-scenes"),
+        })
+    }
+
+    // Renders in passes so that snapshots and checkpoints can be written along the way
+    pub fn is_progressive(&self) -> bool {
+        self.snapshot_passes.is_some() || self.snapshot_seconds.is_some() || self.checkpoint.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(args: &[&str]) -> Result<Config, Error> {
+        Config::from_arg_list(Some("raytracinginaweekend").into_iter().chain(args.iter().cloned()))
+    }
+
+    #[test]
+    fn min_samples_needs_at_least_two_and_no_more_than_samples() {
+        let adaptive = config(&["-s", "64", "--adaptive-threshold", "0.05", "--min-samples", "2"]).unwrap().adaptive.unwrap();
+        assert_eq!(adaptive.min_samples, 2);
+        let adaptive = config(&["-s", "64", "--adaptive-threshold", "0.05"]).unwrap().adaptive.unwrap();
+        assert_eq!(adaptive.min_samples, DEFAULT_MIN_SAMPLES);
+
+        let error = config(&["-s", "64", "--adaptive-threshold", "0.05", "--min-samples", "1"]).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::ValueValidation);
+        let error = config(&["-s", "64", "--adaptive-threshold", "0.05", "--min-samples", "65"]).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::ValueValidation);
+        assert!(error.message.contains("--min-samples (65) can't be more than --samples (64)"), "{}", error.message);
+    }
+}
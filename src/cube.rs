@@ -1,6 +1,7 @@
 use aabb::{surrounding_box, AABBVolume};
 use aarect::{XYRect, XZRect, YZRect};
 use hitable::{HitRecord, Hitable};
+use random::pick;
 use scene::{Entities, MaterialRef};
 use ray::Ray;
 use transform::FlipNormals;
@@ -14,6 +15,7 @@ pub struct Cube {
     back: FlipNormals<XYRect>,
     left: FlipNormals<YZRect>,
     right: YZRect,
+    material: MaterialRef,
 }
 
 impl Cube {
@@ -53,8 +55,13 @@ impl Cube {
             back,
             left,
             right,
+            material: mat,
         }
     }
+
+    fn faces(&self) -> [&dyn Hitable; 6] {
+        [&self.top, &self.bottom, &self.front, &self.back, &self.left, &self.right]
+    }
 }
 
 impl Hitable for Cube {
@@ -107,13 +114,32 @@ impl Hitable for Cube {
         hit_anything
     }
 
-    fn bounding_box(&self, t_min: f32, t_max: f32) -> Option<AABBVolume> {
-        let mut bbox = self.top.bounding_box(t_min, t_max).unwrap();
-        bbox = surrounding_box(bbox, self.bottom.bounding_box(t_min, t_max).unwrap());
-        bbox = surrounding_box(bbox, self.left.bounding_box(t_min, t_max).unwrap());
-        bbox = surrounding_box(bbox, self.right.bounding_box(t_min, t_max).unwrap());
-        bbox = surrounding_box(bbox, self.front.bounding_box(t_min, t_max).unwrap());
-        bbox = surrounding_box(bbox, self.back.bounding_box(t_min, t_max).unwrap());
+    fn bounding_box(&self, entities: &Entities, t_min: f32, t_max: f32) -> Option<AABBVolume> {
+        let mut bbox = self.top.bounding_box(entities, t_min, t_max).unwrap();
+        bbox = surrounding_box(bbox, self.bottom.bounding_box(entities, t_min, t_max).unwrap());
+        bbox = surrounding_box(bbox, self.left.bounding_box(entities, t_min, t_max).unwrap());
+        bbox = surrounding_box(bbox, self.right.bounding_box(entities, t_min, t_max).unwrap());
+        bbox = surrounding_box(bbox, self.front.bounding_box(entities, t_min, t_max).unwrap());
+        bbox = surrounding_box(bbox, self.back.bounding_box(entities, t_min, t_max).unwrap());
         Some(bbox)
     }
+
+    fn material(&self) -> Option<MaterialRef> {
+        Some(self.material)
+    }
+
+    // A face is picked at random and then a point on it, so the pdf is the average of the faces' pdfs
+    fn pdf_value(&self, entities: &Entities, origin: &Vec3, direction: &Vec3, time: f32) -> f32 {
+        let faces = self.faces();
+        let total: f32 = faces.iter()
+            .map(|face| face.pdf_value(entities, origin, direction, time))
+            .sum();
+        total / faces.len() as f32
+    }
+
+    fn random_direction(&self, entities: &Entities, origin: &Vec3, time: f32, sample: [f32; 2]) -> Vec3 {
+        let faces = self.faces();
+        let (index, remapped) = pick(sample[0], faces.len());
+        faces[index].random_direction(entities, origin, time, [remapped, sample[1]])
+    }
 }
@@ -0,0 +1,134 @@
+// Normal and bump maps, which add small scale detail to a surface by changing the normal it is shaded with rather than
+// its geometry
+// Credit to Blinn - Simulation of Wrinkled Surfaces - https://dl.acm.org/doi/10.1145/965139.507101
+
+use hitable::HitRecord;
+use onb::Onb;
+use texture::{Texture, TextureRef};
+use vec3::Vec3;
+
+// Step in u and v used to find the slope of a height texture by finite differences
+const BUMP_DELTA: f32 = 0.0005;
+
+#[derive(Debug, Clone, Copy)]
+pub enum SurfaceDetail {
+    // Tangent space normals stored as colours with +x along dpdu, +y along dpdv and +z along the normal. strength
+    // scales how far they tilt the normal
+    NormalMap { texture: TextureRef, strength: f32 },
+    // A texture of heights above the surface, scale turns the texture's red channel into a distance
+    BumpMap { height: TextureRef, scale: f32 },
+}
+
+impl SurfaceDetail {
+    // hit_record with its normal replaced by the one to shade with
+    pub fn apply(&self, textures: &[Texture], hit_record: &HitRecord) -> HitRecord {
+        let normal = match self {
+            SurfaceDetail::NormalMap { texture, strength } => normal_map(textures, *texture, *strength, hit_record),
+            SurfaceDetail::BumpMap { height, scale } => bump_map(textures, *height, *scale, hit_record),
+        };
+
+        HitRecord { normal, ..*hit_record }
+    }
+}
+
+// Tangent and bitangent around the normal that follow dpdu and dpdv as closely as possible, falling back to an
+// arbitrary frame where the surface has no usable dpdu (such as at the poles of a sphere)
+fn tangent_frame(hit_record: &HitRecord) -> (Vec3, Vec3) {
+    let normal = hit_record.normal;
+    let tangent = hit_record.dpdu - Vec3::dot(&hit_record.dpdu, &normal) * normal;
+    let tangent = if tangent.squared_length() > 1e-12 {
+        tangent.unit()
+    } else {
+        Onb::from_w(&normal).local(&Vec3::new(1.0, 0.0, 0.0))
+    };
+
+    // Surfaces where dpdu, dpdv and the normal are left handed still get bitangents along dpdv
+    let bitangent = Vec3::cross(&normal, &tangent);
+    if Vec3::dot(&bitangent, &hit_record.dpdv) < 0.0 {
+        (tangent, -bitangent)
+    } else {
+        (tangent, bitangent)
+    }
+}
+
+fn normal_map(textures: &[Texture], texture: TextureRef, strength: f32, hit_record: &HitRecord) -> Vec3 {
+    let colour = textures[texture].value(textures, hit_record.u, hit_record.v, &hit_record.p);
+    let local = 2.0 * colour - Vec3::uniform(1.0);
+
+    let (tangent, bitangent) = tangent_frame(hit_record);
+    // Normals pointing into the surface would shade it as if it were lit from behind
+    let normal = strength * (local.x() * tangent + local.y() * bitangent) + local.z().max(0.0) * hit_record.normal;
+    if normal.squared_length() > 0.0 {
+        normal.unit()
+    } else {
+        hit_record.normal
+    }
+}
+
+// The normal of the surface displaced along its normal by the height texture
+fn bump_map(textures: &[Texture], height: TextureRef, scale: f32, hit_record: &HitRecord) -> Vec3 {
+    let (u, v, p) = (hit_record.u, hit_record.v, hit_record.p);
+    let height_at = |u: f32, v: f32, p: &Vec3| scale * textures[height].value(textures, u, v, p).r();
+
+    let base = height_at(u, v, &p);
+    let du = (height_at(u + BUMP_DELTA, v, &(p + BUMP_DELTA * hit_record.dpdu)) - base) / BUMP_DELTA;
+    let dv = (height_at(u, v + BUMP_DELTA, &(p + BUMP_DELTA * hit_record.dpdv)) - base) / BUMP_DELTA;
+
+    let normal = Vec3::cross(&(hit_record.dpdu + du * hit_record.normal), &(hit_record.dpdv + dv * hit_record.normal));
+    if normal.squared_length() <= 0.0 || !normal.x().is_finite() {
+        return hit_record.normal;
+    }
+
+    // The cross product points along the normal or against it depending on the handedness of dpdu and dpdv
+    let normal = normal.unit();
+    if Vec3::dot(&normal, &hit_record.normal) < 0.0 { -normal } else { normal }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hit_record(normal: Vec3, dpdu: Vec3) -> HitRecord {
+        let mut hit_record = HitRecord::zero();
+        hit_record.normal = normal.unit();
+        hit_record.dpdu = dpdu;
+        hit_record.dpdv = 2.0 * Vec3::cross(&hit_record.normal, &dpdu);
+        hit_record
+    }
+
+    fn tilted() -> HitRecord {
+        hit_record(Vec3::new(0.6, 0.0, 0.8), Vec3::new(0.0, 3.0, 0.0))
+    }
+
+    fn assert_close(a: &Vec3, b: &Vec3) {
+        assert!((*a - *b).length() < 1e-5, "{:?} isn't {:?}", a, b);
+    }
+
+    #[test]
+    fn flat_detail_keeps_the_normal() {
+        let textures = [Texture::Constant(Vec3::new(0.5, 0.5, 1.0)), Texture::Constant(Vec3::uniform(0.7))];
+        let hit_record = tilted();
+        for &strength in &[0.5, 1.0, 4.0] {
+            let detail = SurfaceDetail::NormalMap { texture: 0, strength };
+            assert_close(&detail.apply(&textures, &hit_record).normal, &hit_record.normal);
+        }
+        for &scale in &[0.1, 10.0] {
+            let detail = SurfaceDetail::BumpMap { height: 1, scale };
+            assert_close(&detail.apply(&textures, &hit_record).normal, &hit_record.normal);
+        }
+    }
+
+    #[test]
+    fn tangent_frame_is_orthonormal_without_a_usable_dpdu() {
+        let normal = Vec3::new(0.6, 0.0, 0.8);
+        for hit_record in &[tilted(), hit_record(normal, 3.0 * normal), hit_record(normal, Vec3::zero())] {
+            let (tangent, bitangent) = tangent_frame(hit_record);
+            for (a, b) in &[(tangent, bitangent), (tangent, hit_record.normal), (bitangent, hit_record.normal)] {
+                assert!(Vec3::dot(a, b).abs() < 1e-5, "{:?} and {:?} aren't perpendicular", a, b);
+            }
+            assert!((tangent.length() - 1.0).abs() < 1e-5 && (bitangent.length() - 1.0).abs() < 1e-5);
+            assert!(tangent.x().is_finite() && bitangent.x().is_finite());
+        }
+        assert_close(&tangent_frame(&tilted()).0, &Vec3::new(0.0, 1.0, 0.0));
+    }
+}
@@ -0,0 +1,151 @@
+// Piecewise constant distributions for importance sampling tabulated functions such as environment maps
+// Credit to Pharr, Jakob and Humphreys - Physically Based Rendering, section 13.6
+
+#[derive(Debug)]
+pub struct Distribution1D {
+    // Absolute values, with anything that isn't finite (e.g. a NaN pixel in an image) treated as 0
+    function: Vec<f32>,
+    // One more entry than function, running from 0 to 1
+    cdf: Vec<f32>,
+    integral: f32,
+}
+
+impl Distribution1D {
+    pub fn new(function: Vec<f32>) -> Distribution1D {
+        assert!(!function.is_empty(), "a distribution needs at least one value");
+
+        let function: Vec<f32> = function.into_iter()
+            .map(|value| if value.is_finite() { value.abs() } else { 0.0 })
+            .collect();
+        let count = function.len() as f32;
+        let mut cdf = Vec::with_capacity(function.len() + 1);
+        cdf.push(0.0);
+        for (i, value) in function.iter().enumerate() {
+            let previous = cdf[i];
+            cdf.push(previous + value / count);
+        }
+
+        let integral = cdf[function.len()];
+        if integral > 0.0 {
+            for value in cdf.iter_mut() {
+                *value /= integral;
+            }
+        } else {
+            // Nothing to importance sample so fall back to uniform
+            for (i, value) in cdf.iter_mut().enumerate() {
+                *value = i as f32 / count;
+            }
+        }
+
+        Distribution1D { function, cdf, integral }
+    }
+
+    pub fn len(&self) -> usize {
+        self.function.len()
+    }
+
+    pub fn integral(&self) -> f32 {
+        self.integral
+    }
+
+    // Returns a value in [0, 1), its pdf and the index of the piece it is in
+    pub fn sample_continuous(&self, sample: f32) -> (f32, f32, usize) {
+        // Last cdf entry that is <= sample, which skips over pieces with nothing in them
+        let index = self.cdf.partition_point(|&value| value <= sample).saturating_sub(1).min(self.len() - 1);
+
+        let width = self.cdf[index + 1] - self.cdf[index];
+        let offset = if width > 0.0 { (sample - self.cdf[index]) / width } else { 0.0 };
+        let value = ((index as f32 + offset) / self.len() as f32).min(1.0 - f32::EPSILON / 2.0);
+        (value, self.pdf(index), index)
+    }
+
+    // Density at the piece index with respect to [0, 1)
+    pub fn pdf(&self, index: usize) -> f32 {
+        if self.integral > 0.0 {
+            self.function[index] / self.integral
+        } else {
+            1.0
+        }
+    }
+}
+
+// Samples rows with the marginal distribution and then a column within the row
+#[derive(Debug)]
+pub struct Distribution2D {
+    rows: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    // function is stored row by row
+    pub fn new(function: &[f32], width: usize, height: usize) -> Distribution2D {
+        assert_eq!(function.len(), width * height, "function doesn't match the size of the distribution");
+
+        let rows: Vec<Distribution1D> = function.chunks(width)
+            .map(|row| Distribution1D::new(row.to_vec()))
+            .collect();
+        let marginal = Distribution1D::new(rows.iter().map(Distribution1D::integral).collect());
+        Distribution2D { rows, marginal }
+    }
+
+    // Returns a point in [0, 1)^2 along with its pdf
+    pub fn sample_continuous(&self, sample: [f32; 2]) -> ([f32; 2], f32) {
+        let (v, marginal_pdf, row) = self.marginal.sample_continuous(sample[1]);
+        let (u, conditional_pdf, _) = self.rows[row].sample_continuous(sample[0]);
+        ([u, v], marginal_pdf * conditional_pdf)
+    }
+
+    pub fn pdf(&self, point: [f32; 2]) -> f32 {
+        let row = ((point[1] * self.marginal.len() as f32) as usize).min(self.marginal.len() - 1);
+        let columns = self.rows[row].len();
+        let column = ((point[0] * columns as f32) as usize).min(columns - 1);
+        if self.marginal.integral() > 0.0 {
+            self.rows[row].function[column] / self.marginal.integral()
+        } else {
+            1.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_match_the_pdf() {
+        let distribution = Distribution1D::new(vec![1.0, 3.0, 0.0, 4.0]);
+        assert_eq!(distribution.integral(), 2.0);
+        let pdfs: Vec<f32> = (0..4).map(|index| distribution.pdf(index)).collect();
+        assert_eq!(pdfs, vec![0.5, 1.5, 0.0, 2.0]);
+
+        // Evenly spread samples land in each piece in proportion to its pdf
+        let count = 800;
+        let mut counts = [0; 4];
+        for i in 0..count {
+            let (value, pdf, index) = distribution.sample_continuous((i as f32 + 0.5) / count as f32);
+            assert_eq!(index, (value * 4.0) as usize);
+            assert_eq!(pdf, distribution.pdf(index));
+            assert!(pdf > 0.0, "sampled {} from a piece with nothing in it", value);
+            counts[index] += 1;
+        }
+        for (index, &pieces) in counts.iter().enumerate() {
+            assert_eq!(pieces as f32 / count as f32, pdfs[index] / 4.0);
+        }
+
+        // The boundary before an empty piece belongs to the next piece with something in it
+        assert_eq!(distribution.sample_continuous(0.5), (0.75, 2.0, 3));
+    }
+
+    #[test]
+    fn ignores_values_that_are_not_finite() {
+        let distribution = Distribution1D::new(vec![1.0, f32::NAN, f32::INFINITY, -1.0]);
+        assert_eq!(distribution.integral(), 0.5);
+        assert_eq!(distribution.pdf(1), 0.0);
+        assert_eq!(distribution.pdf(2), 0.0);
+        assert_eq!(distribution.sample_continuous(0.75), (0.875, 2.0, 3));
+        assert_eq!(distribution.sample_continuous(f32::NAN).2, 0);
+
+        let distribution = Distribution2D::new(&[f32::NAN, 2.0, 0.0, f32::NEG_INFINITY], 2, 2);
+        assert_eq!(distribution.sample_continuous([0.5, 0.5]), ([0.75, 0.25], 4.0));
+    }
+}
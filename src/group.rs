@@ -0,0 +1,158 @@
+use aabb::{surrounding_box, AABBVolume};
+use bvh::{BvhStats, CompactBvh, DEFAULT_MAX_LEAF_SIZE};
+use hitable::{HitRecord, Hitable};
+use random::pick;
+use scene::{Entities, MaterialRef};
+use ray::Ray;
+use vec3::Vec3;
+
+// Hitables with their own BVH, so that the scene's BVH only sees the group as a whole. As the geometry of an instance
+// a group is the bottom level of a two level tree, which doesn't need rebuilding when the instances move
+#[derive(Debug)]
+pub struct Group {
+    hitables: Vec<Box<dyn Hitable>>,
+    bvh: CompactBvh,
+    // Times the BVH was built for and the bounds of each hitable over them, so that it is only rebuilt for a new
+    // shutter if something has moved
+    shutter: (f32, f32),
+    bounds: Vec<(usize, AABBVolume)>,
+    // Groups where every hitable has the same material can be sampled as a light
+    material: Option<MaterialRef>,
+}
+
+impl Group {
+    // The BVH is built around where the hitables are while the shutter is open from time0 to time1
+    pub fn new(entities: &Entities, hitables: Vec<Box<dyn Hitable>>, time0: f32, time1: f32) -> Result<Group, String> {
+        if hitables.is_empty() {
+            return Err("a group needs at least one hitable".to_owned());
+        }
+        if let Some(index) = hitables.iter().position(|hitable| hitable.bounding_box(entities, time0, time1).is_none()) {
+            return Err(format!("hitable {} of the group has no bounding box", index));
+        }
+
+        let material = hitables[0].material()
+            .filter(|&material| hitables.iter().all(|hitable| hitable.material() == Some(material)));
+
+        let bounds = Group::bounds(&hitables, entities, time0, time1);
+        Ok(Group {
+            bvh: CompactBvh::from_bounds(bounds.clone(), DEFAULT_MAX_LEAF_SIZE),
+            hitables,
+            shutter: (time0, time1),
+            bounds,
+            material,
+        })
+    }
+
+    fn bounds(hitables: &[Box<dyn Hitable>], entities: &Entities, time0: f32, time1: f32) -> Vec<(usize, AABBVolume)> {
+        hitables.iter()
+            .enumerate()
+            .filter_map(|(index, hitable)| hitable.bounding_box(entities, time0, time1).map(|aabb| (index, aabb)))
+            .collect()
+    }
+
+    pub fn hitable_count(&self) -> usize {
+        self.hitables.len()
+    }
+
+    pub fn bvh_stats(&self) -> BvhStats {
+        self.bvh.stats()
+    }
+}
+
+impl Hitable for Group {
+    fn hit_ptr(&self, entities: &Entities, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
+        self.bvh.hit_with(ray, t_min, t_max, hit_record, |index, t_min, t_max, hit_record| {
+            self.hitables[index].hit_ptr(entities, ray, t_min, t_max, hit_record)
+        })
+    }
+
+    fn bounding_box(&self, entities: &Entities, t_min: f32, t_max: f32) -> Option<AABBVolume> {
+        if (t_min, t_max) == self.shutter {
+            return self.bvh.bounding_box();
+        }
+        self.hitables.iter()
+            .filter_map(|hitable| hitable.bounding_box(entities, t_min, t_max))
+            .fold(None, |bounds, aabb| Some(bounds.map_or(aabb, |bounds| surrounding_box(bounds, aabb))))
+    }
+
+    fn set_shutter(&mut self, entities: &Entities, time0: f32, time1: f32) {
+        for hitable in &mut self.hitables {
+            hitable.set_shutter(entities, time0, time1);
+        }
+
+        let bounds = Group::bounds(&self.hitables, entities, time0, time1);
+        if bounds != self.bounds {
+            self.bvh = CompactBvh::from_bounds(bounds.clone(), DEFAULT_MAX_LEAF_SIZE);
+            self.bounds = bounds;
+        }
+        self.shutter = (time0, time1);
+    }
+
+    fn material(&self) -> Option<MaterialRef> {
+        self.material
+    }
+
+    // A hitable is picked at random and then a direction towards it, so the pdf is the average of their pdfs
+    fn pdf_value(&self, entities: &Entities, origin: &Vec3, direction: &Vec3, time: f32) -> f32 {
+        let total: f32 = self.hitables.iter()
+            .map(|hitable| hitable.pdf_value(entities, origin, direction, time))
+            .sum();
+        total / self.hitables.len() as f32
+    }
+
+    fn random_direction(&self, entities: &Entities, origin: &Vec3, time: f32, sample: [f32; 2]) -> Vec3 {
+        let (index, remapped) = pick(sample[0], self.hitables.len());
+        self.hitables[index].random_direction(entities, origin, time, [remapped, sample[1]])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bvh::{BvhType, SceneBvh};
+    use sphere::Sphere;
+    use transform::{AnimatedTransform, Keyframe};
+
+    // A unit sphere moving along x from 0 at frame 1 to 23 at frame 24, so it is centred on x = 11 at frame 12
+    fn animated_sphere() -> Box<dyn Hitable> {
+        let keyframes = vec![Keyframe::new(1.0), Keyframe::new(24.0).translate(&Vec3::new(23.0, 0.0, 0.0))];
+        Box::new(AnimatedTransform::new(Sphere::new(Vec3::zero(), 1.0, 0), keyframes).unwrap())
+    }
+
+    fn ray_at_frame_12() -> Ray {
+        Ray::new(Vec3::new(11.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0), 12.0)
+    }
+
+    #[test]
+    fn bounds_cover_the_shutter_asked_for() {
+        let entities = Entities::new();
+        let group = Group::new(&entities, vec![animated_sphere()], 0.0, 1.0).unwrap();
+        let aabb = group.bounding_box(&entities, 12.0, 12.5).unwrap();
+        assert!(aabb.min().x() <= 10.0 && aabb.max().x() >= 12.5);
+    }
+
+    #[test]
+    fn hits_an_animated_hitable_after_the_shutter_changes() {
+        let entities = Entities::new();
+        let mut group = Group::new(&entities, vec![animated_sphere()], 0.0, 1.0).unwrap();
+        group.set_shutter(&entities, 12.0, 12.5);
+
+        let mut hit_record = HitRecord::zero();
+        assert!(group.hit_ptr(&entities, &ray_at_frame_12(), 0.001, f32::MAX, &mut hit_record));
+        assert!((hit_record.p - Vec3::new(11.0, 0.0, -1.0)).length() < 1e-3);
+    }
+
+    #[test]
+    fn scene_bvh_finds_a_group_of_an_animated_hitable() {
+        let mut entities = Entities::new();
+        let group = Group::new(&entities, vec![animated_sphere()], 0.0, 1.0).unwrap();
+        let id = entities.new_entity(group);
+        entities.set_shutter(12.0, 12.5);
+
+        for &bvh_type in &[BvhType::Compact, BvhType::Recursive] {
+            let bvh = SceneBvh::new(bvh_type, &entities, 12.0, 12.5, DEFAULT_MAX_LEAF_SIZE).unwrap();
+            let mut hit_record = HitRecord::zero();
+            assert_eq!(bvh.hit_entity(&entities, &ray_at_frame_12(), 0.001, f32::MAX, &mut hit_record), Some(id));
+        }
+    }
+}
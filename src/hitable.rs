@@ -1,8 +1,7 @@
-use aabb::{surrounding_box, AABBVolume};
+use aabb::AABBVolume;
 use ray::Ray;
 use vec3::Vec3;
 
-use std::ops::Index;
 use std::fmt::Debug;
 use scene::{Entities, MaterialRef};
 
@@ -15,16 +14,22 @@ pub struct HitRecord {
     pub u: f32,
     pub v: f32,
     pub normal: Vec3,
+    // Derivatives of p with respect to u and v, they are tangent to the surface and orient normal and bump maps
+    pub dpdu: Vec3,
+    pub dpdv: Vec3,
     pub material: MaterialRef,
 }
 
 impl HitRecord {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         t: f32,
         p: Vec3,
         u: f32,
         v: f32,
         normal: Vec3,
+        dpdu: Vec3,
+        dpdv: Vec3,
         material: MaterialRef,
     ) -> HitRecord {
         HitRecord {
@@ -33,19 +38,67 @@ impl HitRecord {
             u,
             v,
             normal,
+            dpdu,
+            dpdv,
             material,
         }
     }
 
     pub fn zero() -> HitRecord {
-        HitRecord::new(0.0, Vec3::zero(), 0.0, 0.0, Vec3::zero(), 0)
+        HitRecord::new(0.0, Vec3::zero(), 0.0, 0.0, Vec3::zero(), Vec3::zero(), Vec3::zero(), 0)
     }
 }
 
 // TODO: Try making an enum of all hitable things like material and texture?
 pub trait Hitable: Debug {
     fn hit_ptr(&self, entities: &Entities, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool;
-    fn bounding_box(&self, t_min: f32, t_max: f32) -> Option<AABBVolume>;
+    fn bounding_box(&self, entities: &Entities, t_min: f32, t_max: f32) -> Option<AABBVolume>;
+
+    // Called before rendering with the times the shutter is open, so that hitables with their own BVH (such as groups)
+    // can rebuild it around where their contents are over those times. Wrappers pass it on to what they wrap
+    fn set_shutter(&mut self, _entities: &Entities, _time0: f32, _time1: f32) {}
+
+    // Light sampling: hitables that can be sampled return their material so that emissive ones can be found
+    fn material(&self) -> Option<MaterialRef> {
+        None
+    }
+
+    // Solid angle pdf of random_direction returning direction
+    fn pdf_value(&self, _entities: &Entities, _origin: &Vec3, _direction: &Vec3, _time: f32) -> f32 {
+        0.0
+    }
+
+    // Direction from origin towards a point on the hitable picked by sample, doesn't need to be normalised
+    fn random_direction(&self, _entities: &Entities, _origin: &Vec3, _time: f32, _sample: [f32; 2]) -> Vec3 {
+        Vec3::new(1.0, 0.0, 0.0)
+    }
+}
+
+// Lets wrappers such as Translate hold hitables whose type is only known at runtime (e.g. when loaded from a scene file)
+impl<H: Hitable + ?Sized> Hitable for Box<H> {
+    fn hit_ptr(&self, entities: &Entities, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
+        (**self).hit_ptr(entities, ray, t_min, t_max, hit_record)
+    }
+
+    fn bounding_box(&self, entities: &Entities, t_min: f32, t_max: f32) -> Option<AABBVolume> {
+        (**self).bounding_box(entities, t_min, t_max)
+    }
+
+    fn set_shutter(&mut self, entities: &Entities, time0: f32, time1: f32) {
+        (**self).set_shutter(entities, time0, time1)
+    }
+
+    fn material(&self) -> Option<MaterialRef> {
+        (**self).material()
+    }
+
+    fn pdf_value(&self, entities: &Entities, origin: &Vec3, direction: &Vec3, time: f32) -> f32 {
+        (**self).pdf_value(entities, origin, direction, time)
+    }
+
+    fn random_direction(&self, entities: &Entities, origin: &Vec3, time: f32, sample: [f32; 2]) -> Vec3 {
+        (**self).random_direction(entities, origin, time, sample)
+    }
 }
 
 //// TODO: Rework this so that list_as_mut isn't required - Have some kind of NotYetFinalisedHitableList or MutableHitableList that is converted to an ImmutableHitableList
@@ -93,15 +146,15 @@ pub trait Hitable: Debug {
 //        hit_anything
 //    }
 //
-//    fn bounding_box(&self, t0: f32, t1: f32) -> Option<AABBVolume> {
+//    fn bounding_box(&self, entities: &Entities, t0: f32, t1: f32) -> Option<AABBVolume> {
 //        if !self.list.is_empty() {
-//            let mut result = match self.list[0].bounding_box(t0, t1) {
+//            let mut result = match self.list[0].bounding_box(entities, t0, t1) {
 //                Some(bounding_box) => bounding_box,
 //                None => return None,
 //            };
 //
 //            for hitable in &self.list[1..] {
-//                match hitable.bounding_box(t0, t1) {
+//                match hitable.bounding_box(entities, t0, t1) {
 //                    Some(bounding_box) => result = surrounding_box(result, bounding_box),
 //                    None => return None,
 //                }
@@ -1,8 +1,13 @@
-use std::io::Result;
+use std::io::{BufRead, BufReader, BufWriter, Read, Result, Write};
 use imagers::{RgbImage, ImageBuffer, Rgb};
+use imagers::hdr::{HDRDecoder, HDREncoder};
+use std::fs::File;
 use std::path::Path;
 use std::iter::once;
+use tonemap::ToneMapping;
+use vec3::Vec3;
 
+#[allow(clippy::upper_case_acronyms)]
 pub type RGB = Rgb<u8>;
 
 #[inline(always)]
@@ -32,12 +37,6 @@ pub struct Image {
 }
 
 impl Image {
-    pub fn new(width: u32, height: u32) -> Image {
-        Image {
-            image: ImageBuffer::new(width, height)
-        }
-    }
-
     pub fn from_vec(pixels: Vec<RGB>, width: u32, height: u32) -> Image {
         // TODO: Work out a better way to do this and not have to reprocess each pixel
         let pixels: Vec<u8> = pixels.iter()
@@ -53,3 +52,176 @@ impl Image {
         self.image.save(path)
     }
 }
+
+// Linear radiance straight from the renderer, rows are stored top to bottom
+#[derive(Debug, Clone)]
+pub struct FrameBuffer {
+    width: u32,
+    height: u32,
+    pixels: Vec<Vec3>,
+}
+
+impl FrameBuffer {
+    pub fn new(width: u32, height: u32) -> FrameBuffer {
+        FrameBuffer {
+            width,
+            height,
+            pixels: vec![Vec3::zero(); (width * height) as usize],
+        }
+    }
+
+    pub fn from_vec(pixels: Vec<Vec3>, width: u32, height: u32) -> FrameBuffer {
+        assert_eq!(pixels.len(), (width * height) as usize, "expected one pixel per position in a {}x{} image", width, height);
+        FrameBuffer { width, height, pixels }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn pixels(&self) -> &[Vec3] {
+        &self.pixels
+    }
+
+    pub fn set(&mut self, x: u32, y: u32, colour: Vec3) {
+        self.pixels[(y * self.width + x) as usize] = colour;
+    }
+
+    // Tone mapped to 8 bits per channel
+    pub fn to_image(&self, tone_mapping: &ToneMapping) -> Image {
+        let white_point = tone_mapping.white_point_for(&self.pixels);
+        let pixels = self.pixels.iter()
+            .map(|pixel| {
+                let colour = tone_mapping.apply(pixel, white_point);
+                new_rgb(colour.r(), colour.g(), colour.b())
+            })
+            .collect();
+        Image::from_vec(pixels, self.width, self.height)
+    }
+
+    // The format is picked from the extension, anything that isn't a HDR format is tone mapped to an 8 bit image
+    pub fn save<T: AsRef<Path>>(&self, path: T, tone_mapping: &ToneMapping) -> ::std::result::Result<(), String> {
+        let path = path.as_ref();
+        let extension = path.extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_lowercase());
+
+        let result = match extension.as_deref() {
+            Some("hdr") => self.save_hdr(path),
+            Some("pfm") => self.save_pfm(path),
+            Some("exr") => return self.save_exr(path),
+            _ => self.to_image(tone_mapping).save(path),
+        };
+        result.map_err(|e| format!("could not save {}: {}", path.display(), e))
+    }
+
+    // Reads a HDR, PFM or EXR file, picked from the extension
+    pub fn load<T: AsRef<Path>>(path: T) -> ::std::result::Result<FrameBuffer, String> {
+        let path = path.as_ref();
+        let extension = path.extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_lowercase());
+
+        let error = |e: String| format!("could not load {}: {}", path.display(), e);
+        match extension.as_deref() {
+            Some("hdr") => FrameBuffer::load_hdr(path).map_err(|e| error(e.to_string())),
+            Some("pfm") => FrameBuffer::load_pfm(path).map_err(error),
+            Some("exr") => FrameBuffer::load_exr(path).map_err(error),
+            _ => Err(error("only .hdr, .pfm and .exr files can be loaded".to_owned())),
+        }
+    }
+
+    fn load_hdr(path: &Path) -> ::imagers::ImageResult<FrameBuffer> {
+        let decoder = HDRDecoder::new(BufReader::new(File::open(path)?))?;
+        let metadata = decoder.metadata();
+        let pixels = decoder.read_image_hdr()?
+            .iter()
+            .map(|pixel| Vec3::new(pixel.data[0], pixel.data[1], pixel.data[2]))
+            .collect();
+        Ok(FrameBuffer::from_vec(pixels, metadata.width, metadata.height))
+    }
+
+    fn load_pfm(path: &Path) -> ::std::result::Result<FrameBuffer, String> {
+        let mut reader = BufReader::new(File::open(path).map_err(|e| e.to_string())?);
+        let mut read_line = || -> ::std::result::Result<String, String> {
+            let mut line = String::new();
+            reader.read_line(&mut line).map_err(|e| e.to_string())?;
+            Ok(line.trim().to_owned())
+        };
+
+        // Greyscale "Pf" files aren't supported
+        if read_line()? != "PF" {
+            return Err("not a colour PFM file".to_owned());
+        }
+        let size = read_line()?;
+        let dimensions: Vec<u32> = size.split_whitespace().filter_map(|value| value.parse().ok()).collect();
+        if dimensions.len() != 2 {
+            return Err(format!("bad size '{}'", size));
+        }
+        let scale: f32 = read_line()?.parse().map_err(|_| "bad scale".to_owned())?;
+        let (width, height) = (dimensions[0], dimensions[1]);
+
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes).map_err(|e| e.to_string())?;
+        if bytes.len() != width as usize * height as usize * 12 {
+            return Err(format!("expected {} bytes of pixels but found {}", width as usize * height as usize * 12, bytes.len()));
+        }
+
+        // A negative scale means little endian
+        let channel = |chunk: &[u8]| {
+            let word = [chunk[0], chunk[1], chunk[2], chunk[3]];
+            if scale < 0.0 { f32::from_le_bytes(word) } else { f32::from_be_bytes(word) }
+        };
+        let rows: Vec<Vec3> = bytes.chunks(12)
+            .map(|pixel| Vec3::new(channel(&pixel[0..4]), channel(&pixel[4..8]), channel(&pixel[8..12])))
+            .collect();
+        let pixels = rows.chunks(width as usize).rev().flat_map(|row| row.iter().cloned()).collect();
+        Ok(FrameBuffer::from_vec(pixels, width, height))
+    }
+
+    fn load_exr(path: &Path) -> ::std::result::Result<FrameBuffer, String> {
+        let image = ::exr::prelude::read_first_rgba_layer_from_file(
+            path,
+            |resolution, _| FrameBuffer::new(resolution.width() as u32, resolution.height() as u32),
+            |framebuffer: &mut FrameBuffer, position, (r, g, b, _): (f32, f32, f32, f32)| {
+                framebuffer.set(position.x() as u32, position.y() as u32, Vec3::new(r, g, b))
+            },
+        ).map_err(|e| e.to_string())?;
+        Ok(image.layer_data.channel_data.pixels)
+    }
+
+    // Radiance RGBE
+    fn save_hdr(&self, path: &Path) -> Result<()> {
+        let pixels: Vec<Rgb<f32>> = self.pixels.iter()
+            .map(|pixel| Rgb { data: [pixel.r(), pixel.g(), pixel.b()] })
+            .collect();
+        let writer = BufWriter::new(File::create(path)?);
+        HDREncoder::new(writer).encode(&pixels, self.width as usize, self.height as usize)
+    }
+
+    // Portable float map, little endian with rows stored bottom to top
+    fn save_pfm(&self, path: &Path) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        write!(writer, "PF\n{} {}\n-1.0\n", self.width, self.height)?;
+        for row in self.pixels.chunks(self.width as usize).rev() {
+            for pixel in row {
+                for channel in &[pixel.r(), pixel.g(), pixel.b()] {
+                    writer.write_all(&channel.to_le_bytes())?;
+                }
+            }
+        }
+        writer.flush()
+    }
+
+    // OpenEXR with 32 bit float channels
+    fn save_exr(&self, path: &Path) -> ::std::result::Result<(), String> {
+        ::exr::prelude::write_rgb_file(path, self.width as usize, self.height as usize, |x, y| {
+            let pixel = self.pixels[y * self.width as usize + x];
+            (pixel.r(), pixel.g(), pixel.b())
+        }).map_err(|e| format!("could not save {}: {}", path.display(), e))
+    }
+}
@@ -0,0 +1,90 @@
+use aabb::AABBVolume;
+use hitable::{HitRecord, Hitable};
+use scene::{Entities, GeometryRef, MaterialRef};
+use ray::Ray;
+use transform::Affine;
+use vec3::Vec3;
+
+// A copy of shared geometry with its own transformation and optionally its own material. The geometry is only stored
+// once however many instances of it there are
+#[derive(Debug)]
+pub struct Instance {
+    geometry: GeometryRef,
+    transform: Affine,
+    // Replaces the material of every part of the geometry
+    material: Option<MaterialRef>,
+    // The material can't change, so it is kept here rather than looked up in the entities every time
+    geometry_material: Option<MaterialRef>,
+}
+
+impl Instance {
+    pub fn new(entities: &Entities, geometry: GeometryRef, transform: Affine, material: Option<MaterialRef>) -> Instance {
+        let hitable = entities.get_geometry(geometry);
+        Instance {
+            geometry,
+            transform,
+            material,
+            geometry_material: hitable.material(),
+        }
+    }
+}
+
+impl Hitable for Instance {
+    fn hit_ptr(&self, entities: &Entities, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
+        let geometry = entities.get_geometry(self.geometry);
+        if geometry.hit_ptr(entities, &self.transform.object_ray(ray), t_min, t_max, hit_record) {
+            self.transform.world_hit(hit_record);
+            if let Some(material) = self.material {
+                hit_record.material = material;
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    // The geometry's bounds only need transforming to rebuild the top level of the scene's BVH
+    fn bounding_box(&self, entities: &Entities, t_min: f32, t_max: f32) -> Option<AABBVolume> {
+        entities.get_geometry(self.geometry).bounding_box(entities, t_min, t_max)
+            .map(|aabb| self.transform.bounding_box(&aabb))
+    }
+
+    fn material(&self) -> Option<MaterialRef> {
+        self.material.or(self.geometry_material)
+    }
+
+    fn pdf_value(&self, entities: &Entities, origin: &Vec3, direction: &Vec3, time: f32) -> f32 {
+        let geometry = entities.get_geometry(self.geometry);
+        self.transform.world_pdf(origin, direction, |origin, direction| geometry.pdf_value(entities, origin, direction, time))
+    }
+
+    fn random_direction(&self, entities: &Entities, origin: &Vec3, time: f32, sample: [f32; 2]) -> Vec3 {
+        let object_origin = self.transform.inverse().point(origin);
+        let direction = entities.get_geometry(self.geometry).random_direction(entities, &object_origin, time, sample);
+        self.transform.vector(&direction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sphere::Sphere;
+    use transform::{AnimatedTransform, Keyframe};
+
+    #[test]
+    fn bounds_and_hits_animated_geometry_at_any_time() {
+        // A unit sphere moving along x from 0 at frame 1 to 23 at frame 24, placed 100 further along z
+        let keyframes = vec![Keyframe::new(1.0), Keyframe::new(24.0).translate(&Vec3::new(23.0, 0.0, 0.0))];
+        let mut entities = Entities::new();
+        let geometry = entities.new_geometry(AnimatedTransform::new(Sphere::new(Vec3::zero(), 1.0, 0), keyframes).unwrap());
+        let instance = Instance::new(&entities, geometry, Affine::identity().translate(&Vec3::new(0.0, 0.0, 100.0)), None);
+
+        let aabb = instance.bounding_box(&entities, 12.0, 12.5).unwrap();
+        assert!(aabb.min().x() <= 10.0 && aabb.max().x() >= 12.5 && aabb.min().z() <= 99.0);
+
+        let ray = Ray::new(Vec3::new(11.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 1.0), 12.0);
+        let mut hit_record = HitRecord::zero();
+        assert!(instance.hit_ptr(&entities, &ray, 0.001, f32::MAX, &mut hit_record));
+        assert!((hit_record.p - Vec3::new(11.0, 0.0, 99.0)).length() < 1e-3);
+    }
+}
@@ -0,0 +1,184 @@
+use background::Background;
+use random::pick;
+use sampler::Sampler;
+use scene::{Entities, HitableRef};
+use material::Material;
+use vec3::Vec3;
+
+// Power heuristic with an exponent of 2 for weighting two sampling strategies, credit to Veach
+pub fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let pdf_squared = pdf * pdf;
+    let other_squared = other_pdf * other_pdf;
+    if pdf_squared + other_squared > 0.0 {
+        pdf_squared / (pdf_squared + other_squared)
+    } else {
+        0.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Light {
+    Entity(HitableRef),
+    Environment,
+}
+
+// Emissive entities, and the background if it can be sampled, one is picked uniformly at random for each sample
+#[derive(Debug)]
+pub struct Lights {
+    lights: Vec<HitableRef>,
+    // Indexed by hitable id
+    is_light: Vec<bool>,
+    environment: bool,
+}
+
+impl Lights {
+    pub fn new(entities: &Entities, materials: &[Material], background: &Background) -> Lights {
+        let lights: Vec<HitableRef> = entities.entities.iter()
+            .filter(|entity| {
+                entities.get_hitable(entity.hitable_id).material().is_some_and(|material| materials[material].is_emissive())
+            })
+            .map(|entity| entity.hitable_id)
+            .collect();
+
+        let mut is_light = vec![false; entities.entities.iter().map(|entity| entity.hitable_id + 1).max().unwrap_or(0)];
+        for &light in &lights {
+            is_light[light] = true;
+        }
+
+        Lights { lights, is_light, environment: background.is_sampled() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lights.len() + self.environment as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Picks a light and returns it along with a direction from origin towards it
+    pub fn sample(
+        &self,
+        entities: &Entities,
+        background: &Background,
+        origin: &Vec3,
+        time: f32,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(Light, Vec3)> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let (index, _) = pick(sampler.get_1d(), self.len());
+        let sample = sampler.get_2d();
+        match self.lights.get(index) {
+            Some(&light) => {
+                let direction = entities.get_hitable(light).random_direction(entities, origin, time, sample);
+                Some((Light::Entity(light), direction))
+            },
+            None => background.sample(sample).map(|(direction, _)| (Light::Environment, direction)),
+        }
+    }
+
+    // Pdf of sample returning light and direction, zero for entities that aren't lights
+    pub fn pdf_value(&self, entities: &Entities, light: HitableRef, origin: &Vec3, direction: &Vec3, time: f32) -> f32 {
+        if !self.is_light.get(light).cloned().unwrap_or(false) {
+            return 0.0;
+        }
+
+        entities.get_hitable(light).pdf_value(entities, origin, direction, time) / self.len() as f32
+    }
+
+    // Pdf of sample returning the environment and direction
+    pub fn environment_pdf(&self, background: &Background, direction: &Vec3) -> f32 {
+        if self.environment {
+            background.pdf(direction) / self.len() as f32
+        } else {
+            0.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aarect::{XYRect, XZRect};
+    use hitable::Hitable;
+    use sampler::SobolSampler;
+    use sphere::Sphere;
+
+    use std::f32::consts::PI;
+
+    // Integral of the light's pdf over every direction from the origin, on a grid that is even in solid angle.
+    // Lights along z fall between too few rows to integrate accurately, so keep them off the poles
+    fn integrate_pdf(light: &dyn Hitable, entities: &Entities) -> f32 {
+        let (rows, columns) = (400, 800);
+        let mut total = 0.0;
+        for row in 0..rows {
+            let z = 1.0 - 2.0 * (row as f32 + 0.5) / rows as f32;
+            let radius = (1.0 - z * z).sqrt();
+            for column in 0..columns {
+                let phi = 2.0 * PI * (column as f32 + 0.5) / columns as f32;
+                let direction = Vec3::new(radius * phi.cos(), radius * phi.sin(), z);
+                total += light.pdf_value(entities, &Vec3::zero(), &direction, 0.0);
+            }
+        }
+        total * 4.0 * PI / (rows * columns) as f32
+    }
+
+    // Pdfs of directions from random_direction, which should all hit the light
+    fn sampled_pdfs(light: &dyn Hitable, entities: &Entities) -> Vec<f32> {
+        let mut sampler = SobolSampler::new(3);
+        (0..1024).map(|sample| {
+            sampler.start_sample(0, sample);
+            let direction = light.random_direction(entities, &Vec3::zero(), 0.0, sampler.get_2d());
+            light.pdf_value(entities, &Vec3::zero(), &direction, 0.0)
+        }).collect()
+    }
+
+    #[test]
+    fn light_pdfs_integrate_to_one_over_their_sampled_directions() {
+        let entities = Entities::new();
+        let rects: Vec<Box<dyn Hitable>> = vec![
+            Box::new(XZRect::new(-1.0, 2.0, -1.0, 1.0, 1.0, 0)),
+            Box::new(XYRect::new(-3.0, 3.0, -0.5, 0.5, -2.0, 0)),
+        ];
+        for rect in &rects {
+            let total = integrate_pdf(&**rect, &entities);
+            assert!((total - 1.0).abs() < 0.01, "{:?} pdf integrates to {}", rect, total);
+            assert!(sampled_pdfs(&**rect, &entities).iter().all(|&pdf| pdf > 0.0 && pdf.is_finite()), "{:?}", rect);
+        }
+
+        for &(center, radius) in &[(Vec3::new(3.0, 0.0, 0.0), 1.0), (Vec3::new(5.0, 5.0, 0.0), 0.5)] {
+            let sphere = Sphere::new(center, radius, 0);
+            let total = integrate_pdf(&sphere, &entities);
+            assert!((total - 1.0).abs() < 0.01, "{:?} pdf integrates to {}", sphere, total);
+
+            // Directions are uniform over the cone the sphere fills, so the pdf is one over its solid angle everywhere
+            let solid_angle = 2.0 * PI * (1.0 - (1.0 - radius * radius / center.squared_length()).sqrt());
+            for pdf in sampled_pdfs(&sphere, &entities) {
+                assert!((pdf * solid_angle - 1.0).abs() < 1e-3, "{:?} sampled a direction with pdf {}", sphere, pdf);
+            }
+        }
+    }
+
+    #[test]
+    fn lights_with_no_area_have_no_pdf() {
+        let entities = Entities::new();
+        let direction = Vec3::new(0.0, 1.0, 0.0);
+        let line = XZRect::new(-1.0, 1.0, 0.0, 0.0, 1.0, 0);
+        assert_eq!(line.pdf_value(&entities, &Vec3::zero(), &direction, 0.0), 0.0);
+        let speck = Sphere::new(Vec3::new(0.0, 1e5, 0.0), 1e-3, 0);
+        assert_eq!(speck.pdf_value(&entities, &Vec3::zero(), &direction, 0.0), 0.0);
+        assert_eq!(power_heuristic(1.0, line.pdf_value(&entities, &Vec3::zero(), &direction, 0.0)), 1.0);
+    }
+
+    #[test]
+    fn power_heuristic_weights_add_up_to_one() {
+        for &(pdf, other) in &[(1.0, 1.0), (0.25, 3.0), (100.0, 0.01), (2.0, 0.0)] {
+            let total = power_heuristic(pdf, other) + power_heuristic(other, pdf);
+            assert!((total - 1.0).abs() < 1e-6, "weights for {} and {} add up to {}", pdf, other, total);
+        }
+        assert_eq!(power_heuristic(0.0, 0.0), 0.0);
+    }
+}
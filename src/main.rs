@@ -12,154 +12,355 @@ extern crate xorshift;
 extern crate lazy_static;
 extern crate image as imagers;
 extern crate rayon;
+extern crate clap;
+extern crate exr;
 
 extern crate cgmath;
 
 
 mod aabb;
+mod adaptive;
 mod aarect;
+mod background;
 mod bvh;
 mod camera;
+mod checkpoint;
+mod config;
 mod cube;
+mod detail;
+mod distribution;
+mod group;
 mod hitable;
 mod image;
+mod instance;
+mod light;
 mod material;
+mod mesh;
+mod microfacet;
+mod obj;
+mod onb;
 mod perlin;
+mod principled;
 mod random;
+mod sampler;
 mod ray;
 mod scene;
+mod scene_file;
 mod scenes;
+mod sky;
 mod sphere;
 mod texture;
+mod tiles;
+mod tonemap;
 mod transform;
 mod vec3;
 mod volume;
 
-use bvh::{CompactBvh, Bvh};
+use adaptive::PixelStats;
+use bvh::SceneBvh;
+use checkpoint::Checkpoint;
+use config::{Config, SCENE_FILE_EXTENSION};
 use hitable::HitRecord;
-use image::{Image, RGB, new_rgb};
-use random::drand48;
+use image::FrameBuffer;
+use light::{power_heuristic, Light, Lights};
+use material::Material;
+use random::{seed, seed_stream, set_seed, SCENE_STREAM};
+use tiles::TileScheduler;
+use tonemap::ToneMapping;
+use sampler::{bounce_dimension, Sampler, BSDF_DIMENSION, LIGHT_DIMENSION};
 use ray::{Ray, RAY_COUNT};
 use vec3::Vec3;
 
+use std::path::Path;
 use std::sync::atomic::Ordering;
-use std::u16;
 use time::PreciseTime;
 
-use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
 
-use scenes::load_scene;
-use scene::{Window, Scene, Resources};
+use scenes::{load_scene, SCENE_NAMES};
+use scene::{Window, Scene};
+use scene_file::load_scene_file;
 
-const MAX_RAY_DEPTH: u8 = 50;
+// Direct light at hit_record from a randomly chosen light, weighted against finding the light by scattering
+fn sample_light(
+    ray: &Ray,
+    hit_record: &HitRecord,
+    material: &Material,
+    scene: &Scene,
+    bvh: &SceneBvh,
+    lights: &Lights,
+    sampler: &mut dyn Sampler,
+) -> Vec3 {
+    let world = &scene.resources;
+    let (light, direction) = match lights.sample(&world.entities, &scene.background, &hit_record.p, ray.time(), sampler) {
+        Some(sample) => sample,
+        None => return Vec3::zero(),
+    };
+
+    let light_pdf = match light {
+        Light::Entity(entity) => lights.pdf_value(&world.entities, entity, &hit_record.p, &direction, ray.time()),
+        Light::Environment => lights.environment_pdf(&scene.background, &direction),
+    };
+    let scattering_pdf = material.pdf(&world.textures, ray, hit_record, &direction);
+    if light_pdf <= 0.0 || scattering_pdf <= 0.0 {
+        return Vec3::zero();
+    }
+
+    // The light is only visible if it is the first thing the shadow ray hits, or if nothing is hit for the environment
+    let shadow_ray = Ray::new(hit_record.p, direction, ray.time());
+    let mut light_record = HitRecord::zero();
+    let emitted = match (light, bvh.hit_entity(&world.entities, &shadow_ray, 0.001, f32::MAX, &mut light_record)) {
+        (Light::Entity(entity), Some(hit)) if hit == entity => world.get_material(light_record.material)
+            .emitted(&world.textures, light_record.u, light_record.v, &light_record.p),
+        (Light::Environment, None) => scene.background.radiance(&direction),
+        _ => return Vec3::zero(),
+    };
+    let bsdf = material.eval(&world.textures, ray, hit_record, &direction);
+
+    bsdf * emitted * power_heuristic(light_pdf, scattering_pdf) / light_pdf
+}
 
 fn trace_ray(
     ray: &Ray,
-    world: &Resources,
-    bvh: &Bvh,
-//    bvh: &CompactBvh,
-    depth: u8,
+    scene: &Scene,
+    bvh: &SceneBvh,
+    lights: &Lights,
+    max_depth: u8,
+    sampler: &mut dyn Sampler,
 ) -> Vec3 {
-    let mut hit_record = HitRecord::zero();
-    if bvh.hit(&world.entities, ray, 0.001, std::f32::MAX, &mut hit_record) {
-        let mut scattered = Ray::zero();
-        let mut attenuation = Vec3::zero();
-
-        let material = world.get_material(hit_record.material as usize);
+    let world = &scene.resources;
+    let mut colour = Vec3::zero();
+    let mut throughput = Vec3::uniform(1.0);
+    let mut ray = *ray;
+    // Pdf of the scatter that created ray, None for camera rays and specular bounces where lights weren't sampled
+    let mut scattering_pdf: Option<f32> = None;
+
+    for depth in 0..=max_depth {
+        let mut hit_record = HitRecord::zero();
+        let entity = match bvh.hit_entity(&world.entities, &ray, 0.001, f32::MAX, &mut hit_record) {
+            Some(entity) => entity,
+            None => {
+                let weight = match scattering_pdf {
+                    Some(pdf) => power_heuristic(pdf, lights.environment_pdf(&scene.background, &ray.direction())),
+                    None => 1.0,
+                };
+                colour += throughput * scene.background.radiance(&ray.direction()) * weight;
+                break;
+            },
+        };
+
+        let material = world.get_material(hit_record.material);
         let emitted = material.emitted(&world.textures, hit_record.u, hit_record.v, &hit_record.p);
-
-        if depth < MAX_RAY_DEPTH
-            && material.scatter(&world.textures, ray, &hit_record, &mut attenuation, &mut scattered)
-        {
-            emitted + attenuation * trace_ray(&scattered, world, bvh, depth + 1)
-        } else {
-            emitted
-//                        Vec3::zero()
+        let weight = match scattering_pdf {
+            Some(pdf) => power_heuristic(pdf, lights.pdf_value(&world.entities, entity, &ray.origin(), &ray.direction(), ray.time())),
+            None => 1.0,
+        };
+        colour += throughput * emitted * weight;
+
+        if depth == max_depth {
+            break;
         }
-    } else {
-        // This causes earlier scenes to not be visible due to not enough light
-//                let unit_direction = ray.direction().unit();
-//                let t = 0.5 * (unit_direction.y() + 1.0);
-//                (1.0 - t) * Vec3::uniform(1.0) + t * Vec3::new(0.5, 0.7, 1.0)
-        Vec3::zero()
+        sampler.set_dimension(bounce_dimension(depth) + BSDF_DIMENSION);
+        let scatter = material.sample(&world.textures, &ray, &hit_record, sampler);
+
+        // Lights are sampled even if the material didn't scatter, otherwise the light is lost whenever sampling a
+        // rough material fails
+        if material.samples_lights() {
+            sampler.set_dimension(bounce_dimension(depth) + LIGHT_DIMENSION);
+            colour += throughput * sample_light(&ray, &hit_record, material, scene, bvh, lights, sampler);
+        }
+
+        let scatter = match scatter {
+            Some(scatter) => scatter,
+            None => break,
+        };
+        scattering_pdf = if scatter.specular { None } else { Some(scatter.pdf) };
+
+        throughput *= scatter.attenuation;
+        ray = scatter.ray;
     }
+
+    colour
 }
 
-fn gamma(vec: Vec3) -> Vec3 {
-    Vec3::new(vec.x().sqrt(), vec.y().sqrt(), vec.z().sqrt())
+fn is_pixel_finished(stats: &PixelStats, window: &Window, config: &Config) -> bool {
+    stats.count() >= window.samples || config.adaptive.is_some_and(|adaptive| adaptive.is_converged(stats))
 }
 
+// Adds up to samples more samples to stats, carrying on from the samples it already has
+#[allow(clippy::too_many_arguments)]
 fn calculate_pixel(
+    sampler: &mut dyn Sampler,
     index: usize,
+    stats: &mut PixelStats,
+    samples: u32,
     window: &Window,
     scene: &Scene,
-    bvh: &Bvh,
-//    bvh: &CompactBvh,
-) -> RGB {
+    bvh: &SceneBvh,
+    lights: &Lights,
+    config: &Config,
+) {
     let width = window.width as usize;
     let height = window.height as usize;
     let col = index % width;
     let row = height - 1 - (index / width);
 
-    let mut colour = Vec3::zero();
-    for _ in 0..window.samples {
-        let u = (col as f32 + drand48()) / width as f32;
-        let v = (row as f32 + drand48()) / height as f32;
-        let r = window.camera.get_ray(u, v);
+    let end = stats.count().saturating_add(samples).min(window.samples);
+    for sample in stats.count()..end {
+        if is_pixel_finished(stats, window, config) {
+            break;
+        }
 
-        colour += trace_ray(&r, &scene.resources, bvh, 0);
+        // Anything that doesn't go through the sampler, like the distance into a volume, still draws from this stream
+        seed_stream(index as u64, u64::from(sample));
+        sampler.start_sample(index, sample);
+        let [jitter_u, jitter_v] = sampler.get_2d();
+        let u = (col as f32 + jitter_u) / width as f32;
+        let v = (row as f32 + jitter_v) / height as f32;
+        let colour = match window.camera.get_ray(u, v, sampler) {
+            Some(r) => trace_ray(&r, scene, bvh, lights, config.max_depth, sampler),
+            None => Vec3::zero(),
+        };
+
+        stats.add(&colour);
     }
+}
 
-    colour = gamma(colour / window.samples as f32);
+// Frames of an animation get their own files, named by adding the frame number to path, e.g. frame.png becomes
+// frame_0001.png
+fn frame_path(path: &str, frame: Option<u32>) -> String {
+    let number = match frame {
+        Some(number) => number,
+        None => return path.to_owned(),
+    };
+    let path = Path::new(path);
+    let stem = path.file_stem().map_or_else(String::new, |stem| stem.to_string_lossy().into_owned());
+    let name = match path.extension() {
+        Some(extension) => format!("{}_{:04}.{}", stem, number, extension.to_string_lossy()),
+        None => format!("{}_{:04}", stem, number),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
 
-//    RGB::new_scaled(colour.r(), colour.g(), colour.b())
-    new_rgb(colour.r(), colour.g(), colour.b())
+fn checkpoint_settings(window: &Window, config: &Config) -> checkpoint::Settings {
+    checkpoint::Settings {
+        scene: config.scene.clone(),
+        width: window.width,
+        height: window.height,
+        seed: config.seed,
+        sampler: config.sampler,
+        samples: window.samples,
+        max_depth: config.max_depth,
+    }
 }
 
-fn run() -> Result<(), String> {
-    let nx: usize = 400;
-    let ny: usize = 400;
-    let ns: usize = 100;
+// Writes the image so far, plus the checkpoint if there is one
+fn save_snapshot(pixels: &[PixelStats], window: &Window, config: &Config, frame: Option<u32>) -> Result<(), String> {
+    let framebuffer = FrameBuffer::from_vec(pixels.iter().map(PixelStats::mean).collect(), window.width, window.height);
+    framebuffer.save(frame_path(&config.output, frame), &config.tone_mapping)?;
+
+    if let Some(ref path) = config.checkpoint {
+        let path = frame_path(path, frame);
+        let checkpoint = Checkpoint {
+            settings: checkpoint_settings(window, config),
+            pixels: pixels.to_vec(),
+        };
+        checkpoint.save(&path)?;
+    }
 
-    let scene = "simple_light";
-//    let scene = "cornell_box";
-//    let scene = "final_scene";
-    let (mut scene, window) = load_scene(scene, nx as u32, ny as u32, ns as u32)?;
-//    let bvh = CompactBvh::new(&mut scene.resources.entities, 0.0, 1.0);
-    let bvh = Bvh::new(&mut scene.resources.entities, 0.0, 1.0);
+    Ok(())
+}
 
-    assert!(
-        (scene.resources.materials.len() as u16) < u16::MAX,
-//        (scene.materials.len() as u16) < u16::MAX,
-        format!("The maximum supported number of materials is {}", u16::MAX)
+// Adds up to pass_samples more samples to every pixel using rayon's current thread pool
+#[allow(clippy::too_many_arguments)]
+fn render_pass(
+    scheduler: &TileScheduler,
+    pixels: &mut [PixelStats],
+    pass_samples: u32,
+    window: &Window,
+    scene: &Scene,
+    bvh: &SceneBvh,
+    lights: &Lights,
+    config: &Config,
+) {
+    let tile_count = scheduler.tiles().len();
+    scheduler.render(
+        pixels,
+        // Samplers start over for each sample so one can be reused for every pixel a thread renders
+        || config.sampler.create(seed(), window.samples),
+        |sampler, idx, stats| {
+            calculate_pixel(
+                &mut **sampler,
+                idx,
+                stats,
+                pass_samples,
+                window,
+                scene,
+                bvh,
+                lights,
+                config,
+            )
+        },
+        |_, finished| {
+            // Passes are quick when rendering progressively so only a single pass reports its progress
+            if !config.is_progressive() && finished * 10 / tile_count != (finished - 1) * 10 / tile_count {
+                println!("{}% of tiles rendered", finished * 100 / tile_count);
+            }
+        },
     );
+}
+
+// Renders the scene as seen by window's camera, frame is the number of the frame if the scene is animated
+fn render(config: &Config, scene: &Scene, window: &Window, lights: &Lights, frame: Option<u32>) -> Result<(), String> {
+    let nx = config.width as usize;
+    let ny = config.height as usize;
+
+    // Only the top level of the BVH is always rebuilt for each frame, bounding the hitables over its shutter
+    let (time0, time1) = window.camera.shutter();
+    let bvh = SceneBvh::new(config.bvh, &scene.resources.entities, time0, time1, config.leaf_size)?;
+
+    let checkpoint_path = config.checkpoint.as_ref().map(|path| frame_path(path, frame));
+    let mut pixels = match checkpoint_path {
+        Some(ref path) if config.resume && Path::new(path).is_file() => {
+            let checkpoint = Checkpoint::load(path)?;
+            checkpoint.check_matches(&checkpoint_settings(window, config))?;
+            let total: u64 = checkpoint.pixels.iter().map(|stats| u64::from(stats.count())).sum();
+            println!("resuming from {} with {} samples", path, total);
+            checkpoint.pixels
+        },
+        Some(ref path) if config.resume => {
+            println!("no checkpoint at {}, starting from the beginning", path);
+            vec![PixelStats::new(); nx * ny]
+        },
+        _ => vec![PixelStats::new(); nx * ny],
+    };
+
+    // A single pass takes every sample unless snapshots are wanted along the way
+    let pass_samples = if config.is_progressive() { config.pass_samples } else { window.samples };
 
     let start = PreciseTime::now();
+    let mut last_snapshot = start;
+    let mut passes = 0;
+    RAY_COUNT.store(0, Ordering::Relaxed);
 
-    // TODO: Support args for output file name + which scene to run
     // TODO: Implement a version of this that builds buffers of rays to process (maybe store as SoA?)
     // TODO: How to handle multiple types of Hitable object? Turn everything into meshes/triangles? How would spheres be done?
 
-    // TODO: Rewrite this range -> Image logic to allocate the image at the start and write into appropriate cells
-
     // TODO: Work out whether rayon is adding any overhead
-    // Is this the best way to do it? or is parallelism over sub images/tiles better?
-    let indexes: Vec<usize> = (0..nx * ny).collect();
-    let pixels: Vec<RGB> = indexes
-//    let pixels: Vec<RGB> = (0..nx * ny)
-//        .into_iter()
-        .into_par_iter()
-        .map(|idx| {
-            calculate_pixel(
-                idx,
-                &window,
-                &scene,
-                &bvh,
-            )
-        })
-        .collect();
-
-    let image = Image::from_vec(pixels, window.width, window.height);
+    let scheduler = TileScheduler::new(window.width, window.height, config.tile_size, config.tile_order);
+    while pixels.iter().any(|stats| !is_pixel_finished(stats, window, config)) {
+        render_pass(&scheduler, &mut pixels, pass_samples, window, scene, &bvh, lights, config);
+        passes += 1;
+
+        let now = PreciseTime::now();
+        let seconds = last_snapshot.to(now).num_milliseconds() as f32 / 1000.0;
+        if config.snapshot_passes.is_some_and(|every| passes % every == 0)
+            || config.snapshot_seconds.is_some_and(|every| seconds >= every) {
+            save_snapshot(&pixels, window, config, frame)?;
+            last_snapshot = now;
+            println!("saved snapshot after {} passes", passes);
+        }
+    }
 
     // TODO: Work out whether MaterialEnum/TextureEnum are faster than trait solution using the final scene
 
@@ -171,13 +372,146 @@ fn run() -> Result<(), String> {
         RAY_COUNT.load(Ordering::Relaxed) as f32 / duration
     );
 
-    match image.save("images/current_progress.jpg") {
-        Ok(()) => Ok(()),
-        Err(e) => Err(e.to_string())
+    save_snapshot(&pixels, window, config, frame)?;
+
+    if config.adaptive.is_some() {
+        let total: u64 = pixels.iter().map(|stats| u64::from(stats.count())).sum();
+        println!(
+            "adaptive sampling took {:.1} samples per pixel on average (maximum {})",
+            total as f32 / pixels.len() as f32,
+            window.samples
+        );
+    }
+
+    if let Some(ref path) = config.sample_count_output {
+        // Fraction of the maximum sample count, so white pixels never converged
+        let counts = pixels.iter()
+            .map(|stats| Vec3::uniform(stats.count() as f32 / window.samples as f32))
+            .collect();
+        FrameBuffer::from_vec(counts, window.width, window.height).save(frame_path(path, frame), &ToneMapping::default())?;
     }
+
+    Ok(())
+}
+
+// Loads the scene file or built-in scene named by the config, seeding everything it generates from config.seed
+fn load(config: &Config) -> Result<(Scene, Window), String> {
+    set_seed(config.seed);
+    seed_stream(SCENE_STREAM, 0);
+    let (scene, window) = if config.scene.ends_with(SCENE_FILE_EXTENSION) {
+        let (scene, window, stats) = load_scene_file(&config.scene, config.width, config.height, config.samples)?;
+        for mesh in &stats.meshes {
+            println!("loaded {} triangles from {}: {}", mesh.triangles, mesh.path.display(), mesh.bvh);
+        }
+        for group in &stats.groups {
+            println!("created group of {} hitables: {}", group.hitables, group.bvh);
+        }
+        (scene, window)
+    } else {
+        load_scene(&config.scene, config.width, config.height, config.samples)?
+    };
+    perlin::initialise();
+    Ok((scene, window))
+}
+
+fn run(config: &Config) -> Result<(), String> {
+    if config.list_scenes {
+        for name in SCENE_NAMES.iter() {
+            println!("{}", name);
+        }
+        return Ok(());
+    }
+
+    if let Some(threads) = config.threads {
+        ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .map_err(|e| e.to_string())?;
+    }
+
+    let (mut scene, mut window) = load(config)?;
+    let lights = Lights::new(&scene.resources.entities, &scene.resources.materials, &scene.background);
+    println!("sampling {} lights directly", lights.len());
+
+    assert!(
+        (scene.resources.materials.len() as u16) < u16::MAX,
+//        (scene.materials.len() as u16) < u16::MAX,
+        "The maximum supported number of materials is {}", u16::MAX
+    );
+
+    if window.frames.is_empty() {
+        if config.resume && config.checkpoint.is_none() {
+            return Err("--resume needs a --checkpoint to resume from unless the scene is animated".to_owned());
+        }
+        let (time0, time1) = window.camera.shutter();
+        scene.resources.entities.set_shutter(time0, time1);
+        return render(config, &scene, &window, &lights, None);
+    }
+
+    // The scene is only loaded once, each frame just swaps in its own camera
+    let frames = std::mem::take(&mut window.frames);
+    let frame_count = frames.len();
+    for (index, frame) in frames.into_iter().enumerate() {
+        // Frames that were finished before are skipped, unless they have a checkpoint that may have more to do
+        let output = frame_path(&config.output, Some(frame.number));
+        let has_checkpoint = config.checkpoint.as_ref()
+            .is_some_and(|path| Path::new(&frame_path(path, Some(frame.number))).is_file());
+        if config.resume && !has_checkpoint && Path::new(&output).is_file() {
+            println!("frame {} has already been rendered to {}", frame.number, output);
+            continue;
+        }
+
+        println!("rendering frame {} ({} of {}) to {}", frame.number, index + 1, frame_count, output);
+        window.camera = frame.camera;
+        // Groups whose hitables have moved since the last frame rebuild their BVHs, the rest are kept as they are
+        let (time0, time1) = window.camera.shutter();
+        scene.resources.entities.set_shutter(time0, time1);
+        render(config, &scene, &window, &lights, Some(frame.number))?;
+    }
+
+    Ok(())
 }
 
 fn main() -> Result<(), String> {
-    run()
+    run(&Config::from_args())
+
+}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rayon::ThreadPoolBuilder;
+
+    fn render_with_threads(config: &Config, threads: usize) -> Vec<Vec3> {
+        let (mut scene, window) = load(config).unwrap();
+        let lights = Lights::new(&scene.resources.entities, &scene.resources.materials, &scene.background);
+        let (time0, time1) = window.camera.shutter();
+        scene.resources.entities.set_shutter(time0, time1);
+        let bvh = SceneBvh::new(config.bvh, &scene.resources.entities, time0, time1, config.leaf_size).unwrap();
+        let scheduler = TileScheduler::new(window.width, window.height, config.tile_size, config.tile_order);
+
+        let mut pixels = vec![PixelStats::new(); window.width as usize * window.height as usize];
+        let pool = ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+        pool.install(|| {
+            while pixels.iter().any(|stats| !is_pixel_finished(stats, &window, config)) {
+                render_pass(&scheduler, &mut pixels, config.pass_samples, &window, &scene, &bvh, &lights, config);
+            }
+        });
+        pixels.iter().map(PixelStats::mean).collect()
+    }
+
+    #[test]
+    fn renders_the_same_image_for_the_same_seed_whatever_the_threads() {
+        for sampler in &["independent", "stratified"] {
+            let config = Config::from_arg_list(vec![
+                "raytracinginaweekend", "cornell_box", "--width", "16", "--height", "16", "--samples", "8", "--pass-samples", "3",
+                "--seed", "7", "--sampler", sampler, "--tile-size", "4",
+            ]).unwrap();
+
+            let first = render_with_threads(&config, 4);
+            assert_eq!(first, render_with_threads(&config, 4), "{} sampler", sampler);
+            assert_eq!(first, render_with_threads(&config, 1), "{} sampler", sampler);
+        }
+    }
 }
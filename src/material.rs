@@ -1,17 +1,18 @@
+use detail::SurfaceDetail;
 use hitable::HitRecord;
-use random::drand48;
+use microfacet::{fresnel_conductor, TrowbridgeReitz};
+use onb::Onb;
+use principled::Principled;
+use random::{random_cosine_direction, random_sphere_direction};
 use ray::Ray;
+use sampler::Sampler;
 use texture::{TextureRef, Texture};
 use vec3::Vec3;
 
-fn random_in_unit_sphere() -> Vec3 {
-    loop {
-        let p = 2.0 * Vec3::random() - Vec3::uniform(1.0);
+use std::f32::consts::PI;
 
-        if p.squared_length() < 1.0 {
-            return p;
-        }
-    }
+fn random_in_unit_sphere(sampler: &mut dyn Sampler) -> Vec3 {
+    random_sphere_direction(sampler.get_2d()) * sampler.get_1d().cbrt()
 }
 
 fn reflect(v: &Vec3, n: &Vec3) -> Vec3 {
@@ -36,31 +37,91 @@ fn schlick(cosine: f32, ref_idx: f32) -> f32 {
     r0 + (1.0 - r0) * (1.0 - cosine).powi(5)
 }
 
-fn lambert(ray: &Ray, hit_record: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray, albedo: Vec3) -> bool {
-    let target = hit_record.p + hit_record.normal + random_in_unit_sphere();
+// Result of sampling a material for a new direction
+#[derive(Debug, Clone, Copy)]
+pub struct ScatterRecord {
+    pub ray: Ray,
+    // BSDF times the cosine term divided by the pdf, i.e. what the light along ray is multiplied by
+    pub attenuation: Vec3,
+    // Zero for specular samples as they are a single direction that can't be found by any other strategy
+    pub pdf: f32,
+    pub specular: bool,
+}
+
+impl ScatterRecord {
+    fn new(ray: Ray, attenuation: Vec3, pdf: f32) -> ScatterRecord {
+        ScatterRecord { ray, attenuation, pdf, specular: false }
+    }
+
+    fn specular(ray: Ray, attenuation: Vec3) -> ScatterRecord {
+        ScatterRecord { ray, attenuation, pdf: 0.0, specular: true }
+    }
+}
+
+fn albedo(textures: &[Texture], tex_ref: TextureRef, hit_record: &HitRecord) -> Vec3 {
+    textures[tex_ref].value(textures, hit_record.u, hit_record.v, &hit_record.p)
+}
 
-    *scattered = Ray::new(hit_record.p, target - hit_record.p, ray.time());
-    *attenuation = albedo;
-    true
+fn lambert_pdf(hit_record: &HitRecord, direction: &Vec3) -> f32 {
+    let cosine = Vec3::dot(&hit_record.normal, &direction.unit());
+    if cosine > 0.0 { cosine / PI } else { 0.0 }
 }
 
-fn metal(ray: &Ray, hit_record: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray, albedo: &Vec3, fuzz: f32) -> bool {
+// Directions are cosine weighted so that the attenuation is just the albedo
+fn lambert(ray: &Ray, hit_record: &HitRecord, albedo: Vec3, sampler: &mut dyn Sampler) -> Option<ScatterRecord> {
+    let direction = Onb::from_w(&hit_record.normal).local(&random_cosine_direction(sampler.get_2d()));
+    let pdf = lambert_pdf(hit_record, &direction);
+    if pdf <= 0.0 {
+        return None;
+    }
+
+    Some(ScatterRecord::new(Ray::new(hit_record.p, direction, ray.time()), albedo, pdf))
+}
+
+// Directions are the perfect reflection plus a point uniformly distributed in a ball of radius fuzz, so the density of
+// a direction is the part of the ball's volume along it. Directions that end up below the surface are absorbed
+fn metal_pdf(ray: &Ray, hit_record: &HitRecord, fuzz: f32, direction: &Vec3) -> f32 {
+    let direction = direction.unit();
+    let reflected = reflect(&ray.direction().unit(), &hit_record.normal);
+    let cosine = Vec3::dot(&direction, &reflected);
+    // Squared distance from the centre of the ball to the line along direction
+    let distance_squared = Vec3::cross(&direction, &reflected).squared_length();
+    if Vec3::dot(&direction, &hit_record.normal) <= 0.0 || cosine <= 0.0 || distance_squared >= fuzz * fuzz {
+        return 0.0;
+    }
+
+    // The line is inside the ball from near to far, and the volume along it is the integral of t^2 between them
+    let half_chord = (fuzz * fuzz - distance_squared).sqrt();
+    let (near, far) = (cosine - half_chord, cosine + half_chord);
+    2.0 * half_chord * (near * near + near * far + far * far) / (4.0 * PI * fuzz.powi(3))
+}
+
+// Fuzz above 1 is treated as 1, scene files don't allow it at all. A fuzz of 0 is a perfect mirror
+fn metal(ray: &Ray, hit_record: &HitRecord, albedo: &Vec3, fuzz: f32, sampler: &mut dyn Sampler) -> Option<ScatterRecord> {
     let reflected = reflect(&ray.direction().unit(), &hit_record.normal);
 
     let fuzz = if fuzz < 1.0 { fuzz } else { 1.0 };
-    *scattered = Ray::new(
+    let scattered = Ray::new(
         hit_record.p,
-        reflected + fuzz * random_in_unit_sphere(),
+        reflected + fuzz * random_in_unit_sphere(sampler),
         ray.time(),
     );
-    *attenuation = *albedo;
 
-    Vec3::dot(&scattered.direction(), &hit_record.normal) > 0.0
+    if Vec3::dot(&scattered.direction(), &hit_record.normal) <= 0.0 {
+        None
+    } else if fuzz > 0.0 {
+        // Light sampling can find these directions too, so they carry their pdf for MIS
+        let pdf = metal_pdf(ray, hit_record, fuzz, &scattered.direction());
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some(ScatterRecord::new(scattered, *albedo, pdf))
+    } else {
+        Some(ScatterRecord::specular(scattered, *albedo))
+    }
 }
 
-fn dieletric(ray: &Ray, hit_record: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray, ref_idx: f32) -> bool {
-    *attenuation = Vec3::uniform(1.0);
-
+fn dieletric(ray: &Ray, hit_record: &HitRecord, ref_idx: f32, sampler: &mut dyn Sampler) -> Option<ScatterRecord> {
     let (outward_normal, ni_over_nt, cosine) =
         if Vec3::dot(&ray.direction(), &hit_record.normal) > 0.0 {
             (
@@ -85,52 +146,304 @@ fn dieletric(ray: &Ray, hit_record: &HitRecord, attenuation: &mut Vec3, scattere
             (1.0, Vec3::zero())
         };
 
-    if drand48() < reflect_prob {
-        *scattered = Ray::new(hit_record.p, reflected, ray.time());
-    } else {
-        *scattered = Ray::new(hit_record.p, refracted, ray.time());
+    let direction = if sampler.get_1d() < reflect_prob { reflected } else { refracted };
+    Some(ScatterRecord::specular(Ray::new(hit_record.p, direction, ray.time()), Vec3::uniform(1.0)))
+}
+
+// Complex indices of refraction (eta + ik) for the red, green and blue channels of some common metals
+pub const CONDUCTORS: [(&str, [f32; 3], [f32; 3]); 4] = [
+    ("gold", [0.143, 0.374, 1.442], [3.983, 2.385, 1.603]),
+    ("silver", [0.155, 0.117, 0.138], [4.828, 3.122, 2.147]),
+    ("copper", [0.200, 0.924, 1.102], [3.912, 2.452, 2.142]),
+    ("aluminium", [1.657, 0.880, 0.521], [9.224, 6.270, 4.837]),
+];
+
+// Frame with +z along the side of the surface the ray came from, along with the direction back along the ray in it
+// and whether that side is the outside of the surface
+fn microfacet_frame(ray: &Ray, hit_record: &HitRecord) -> (Onb, Vec3, bool) {
+    let wo = -ray.direction().unit();
+    let outside = Vec3::dot(&wo, &hit_record.normal) >= 0.0;
+    let frame = Onb::from_w(&if outside { hit_record.normal } else { -hit_record.normal });
+    let local = frame.world_to_local(&wo);
+    (frame, local, outside)
+}
+
+// Ray along wi, a direction in frame, weighted by the BSDF times the cosine term over the pdf it was picked with
+fn scatter_local(ray: &Ray, hit_record: &HitRecord, frame: &Onb, wi: &Vec3, (bsdf, pdf): (Vec3, f32)) -> Option<ScatterRecord> {
+    if pdf <= 0.0 {
+        return None;
     }
+    Some(ScatterRecord::new(Ray::new(hit_record.p, frame.local(wi), ray.time()), bsdf / pdf, pdf))
+}
+
+// Calls bsdf with the directions in the microfacet frame and whether the ray came from outside
+fn eval_local<F>(ray: &Ray, hit_record: &HitRecord, direction: &Vec3, bsdf: F) -> (Vec3, f32)
+    where F: Fn(&Vec3, &Vec3, bool) -> (Vec3, f32) {
+    let (frame, wo, outside) = microfacet_frame(ray, hit_record);
+    bsdf(&wo, &frame.world_to_local(&direction.unit()), outside)
+}
 
-    true
+// BSDF times the cosine term and the pdf of sampling wi
+fn conductor_local(distribution: &TrowbridgeReitz, wo: &Vec3, wi: &Vec3, eta: &Vec3, k: &Vec3) -> (Vec3, f32) {
+    match distribution.reflection(wo, wi) {
+        Some((h, reflection, pdf)) => (fresnel_conductor(Vec3::dot(wo, &h), eta, k) * reflection, pdf),
+        None => (Vec3::zero(), 0.0),
+    }
 }
 
-fn isotropic(ray: &Ray, hit_record: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray, albedo: Vec3) -> bool {
-    *attenuation = albedo;
-    *scattered = Ray::new(hit_record.p, random_in_unit_sphere(), ray.time());
-    true
+fn conductor(ray: &Ray, hit_record: &HitRecord, eta: &Vec3, k: &Vec3, roughness: f32, sampler: &mut dyn Sampler) -> Option<ScatterRecord> {
+    let (frame, wo, _) = microfacet_frame(ray, hit_record);
+    let distribution = TrowbridgeReitz::new(roughness);
+    let wi = distribution.sample_reflection(&wo, sampler.get_2d())?;
+    scatter_local(ray, hit_record, &frame, &wi, conductor_local(&distribution, &wo, &wi, eta, k))
 }
 
-#[derive(Debug)]
+// Ratio of the index of refraction on the far side of the surface to the one on the side the ray came from
+fn relative_eta(ior: f32, outside: bool) -> f32 {
+    if outside { ior } else { 1.0 / ior }
+}
+
+fn dielectric_local(distribution: &TrowbridgeReitz, wo: &Vec3, wi: &Vec3, eta: f32) -> (Vec3, f32) {
+    let (bsdf, pdf) = distribution.dielectric(wo, wi, eta);
+    (Vec3::uniform(bsdf), pdf)
+}
+
+fn rough_dielectric(ray: &Ray, hit_record: &HitRecord, ior: f32, roughness: f32, sampler: &mut dyn Sampler) -> Option<ScatterRecord> {
+    let (frame, wo, outside) = microfacet_frame(ray, hit_record);
+    let eta = relative_eta(ior, outside);
+    let distribution = TrowbridgeReitz::new(roughness);
+    let wi = distribution.sample_dielectric(&wo, eta, sampler.get_2d(), sampler.get_1d())?;
+    scatter_local(ray, hit_record, &frame, &wi, dielectric_local(&distribution, &wo, &wi, eta))
+}
+
+const ISOTROPIC_PDF: f32 = 1.0 / (4.0 * PI);
+
+fn isotropic(ray: &Ray, hit_record: &HitRecord, albedo: Vec3, sampler: &mut dyn Sampler) -> Option<ScatterRecord> {
+    let scattered = Ray::new(hit_record.p, random_sphere_direction(sampler.get_2d()), ray.time());
+    Some(ScatterRecord::new(scattered, albedo, ISOTROPIC_PDF))
+}
+
+#[derive(Debug, Clone)]
 pub enum Material {
     Lambertian(Vec3),
     LambertianTextured(TextureRef),
     Metal(Vec3, f32),
     Dieletric(f32),
+    // GGX microfacet metal with a complex index of refraction eta + ik for each channel
+    Conductor { eta: Vec3, k: Vec3, roughness: f32 },
+    // GGX microfacet glass that both reflects and transmits
+    RoughDielectric { ior: f32, roughness: f32 },
+    Principled(Principled),
     DiffuseLight(TextureRef),
-    Isotropic(TextureRef)
+    Isotropic(TextureRef),
+    // Another material shaded with the normal from a normal or bump map
+    Detailed(Box<Material>, SurfaceDetail),
 }
 
 impl Material {
-    pub fn scatter(&self, textures: &[Texture], ray: &Ray, hit_record: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray) -> bool {
+    // Picks a direction for the light arriving at hit_record to have come from, None if the path ends here
+    // Uses at most 3 dimensions of sampler
+    pub fn sample(&self, textures: &[Texture], ray: &Ray, hit_record: &HitRecord, sampler: &mut dyn Sampler) -> Option<ScatterRecord> {
         match self {
-            Material::Lambertian(albedo) => lambert(ray, hit_record, attenuation, scattered, *albedo),
-            Material::LambertianTextured(tex_ref) => {
-                let albedo = textures[*tex_ref].value(textures, hit_record.u, hit_record.v, &hit_record.p);
-                lambert(ray, hit_record, attenuation, scattered, albedo)
+            Material::Lambertian(albedo) => lambert(ray, hit_record, *albedo, sampler),
+            Material::LambertianTextured(tex_ref) => lambert(ray, hit_record, albedo(textures, *tex_ref, hit_record), sampler),
+            Material::Metal(albedo, fuzz) => metal(ray, hit_record, albedo, *fuzz, sampler),
+            Material::Dieletric(ref_idx) => dieletric(ray, hit_record, *ref_idx, sampler),
+            Material::Conductor { eta, k, roughness } => conductor(ray, hit_record, eta, k, *roughness, sampler),
+            Material::RoughDielectric { ior, roughness } => rough_dielectric(ray, hit_record, *ior, *roughness, sampler),
+            Material::Principled(principled) => {
+                let (frame, wo, outside) = microfacet_frame(ray, hit_record);
+                let bsdf = principled.bsdf(textures, hit_record, outside);
+                let wi = bsdf.sample(&wo, sampler.get_1d(), sampler.get_2d())?;
+                scatter_local(ray, hit_record, &frame, &wi, bsdf.eval(&wo, &wi))
             },
-            Material::Metal(albedo, fuzz) => metal(ray, hit_record, attenuation, scattered, albedo, *fuzz),
-            Material::Dieletric(ref_idx) => dieletric(ray, hit_record, attenuation, scattered, *ref_idx),
-            Material::DiffuseLight(_) => false,
-            Material::Isotropic(tex_ref) => {
-                let albedo = textures[*tex_ref].value(textures, hit_record.u, hit_record.v, &hit_record.p);
-                isotropic(ray, hit_record, attenuation, scattered, albedo)
-            }
+            Material::DiffuseLight(_) => None,
+            Material::Isotropic(tex_ref) => isotropic(ray, hit_record, albedo(textures, *tex_ref, hit_record), sampler),
+            Material::Detailed(material, detail) => material.sample(textures, ray, &detail.apply(textures, hit_record), sampler),
+        }
+    }
+
+    // BSDF times the cosine term for light arriving from direction, zero for specular materials
+    pub fn eval(&self, textures: &[Texture], ray: &Ray, hit_record: &HitRecord, direction: &Vec3) -> Vec3 {
+        match self {
+            Material::Lambertian(albedo) => *albedo * lambert_pdf(hit_record, direction),
+            Material::LambertianTextured(tex_ref) => albedo(textures, *tex_ref, hit_record) * lambert_pdf(hit_record, direction),
+            Material::Isotropic(tex_ref) => albedo(textures, *tex_ref, hit_record) * ISOTROPIC_PDF,
+            Material::Conductor { .. } | Material::RoughDielectric { .. } | Material::Principled(_) =>
+                self.eval_microfacet(textures, ray, hit_record, direction).0,
+            Material::Metal(albedo, fuzz) if *fuzz > 0.0 => *albedo * metal_pdf(ray, hit_record, fuzz.min(1.0), direction),
+            Material::Detailed(material, detail) => material.eval(textures, ray, &detail.apply(textures, hit_record), direction),
+            Material::Metal(_, _) | Material::Dieletric(_) | Material::DiffuseLight(_) => Vec3::zero(),
+        }
+    }
+
+    // Pdf of sample returning direction, zero for specular materials
+    pub fn pdf(&self, textures: &[Texture], ray: &Ray, hit_record: &HitRecord, direction: &Vec3) -> f32 {
+        match self {
+            Material::Lambertian(_) | Material::LambertianTextured(_) => lambert_pdf(hit_record, direction),
+            Material::Isotropic(_) => ISOTROPIC_PDF,
+            Material::Conductor { .. } | Material::RoughDielectric { .. } | Material::Principled(_) =>
+                self.eval_microfacet(textures, ray, hit_record, direction).1,
+            Material::Metal(_, fuzz) if *fuzz > 0.0 => metal_pdf(ray, hit_record, fuzz.min(1.0), direction),
+            Material::Detailed(material, detail) => material.pdf(textures, ray, &detail.apply(textures, hit_record), direction),
+            Material::Metal(_, _) | Material::Dieletric(_) | Material::DiffuseLight(_) => 0.0,
         }
     }
+
+    // BSDF times the cosine term and pdf for the materials that are evaluated in the microfacet frame
+    fn eval_microfacet(&self, textures: &[Texture], ray: &Ray, hit_record: &HitRecord, direction: &Vec3) -> (Vec3, f32) {
+        match self {
+            Material::Conductor { eta, k, roughness } => eval_local(ray, hit_record, direction, |wo, wi, _| {
+                conductor_local(&TrowbridgeReitz::new(*roughness), wo, wi, eta, k)
+            }),
+            Material::RoughDielectric { ior, roughness } => eval_local(ray, hit_record, direction, |wo, wi, outside| {
+                dielectric_local(&TrowbridgeReitz::new(*roughness), wo, wi, relative_eta(*ior, outside))
+            }),
+            Material::Principled(principled) => eval_local(ray, hit_record, direction, |wo, wi, outside| {
+                principled.bsdf(textures, hit_record, outside).eval(wo, wi)
+            }),
+            _ => (Vec3::zero(), 0.0),
+        }
+    }
+
+    // Whether sampling lights can find the directions this material scatters light from. Perfect mirrors and glass
+    // only scatter in one direction and lights don't scatter at all
+    pub fn samples_lights(&self) -> bool {
+        match self {
+            Material::Detailed(material, _) => material.samples_lights(),
+            Material::Metal(_, fuzz) => *fuzz > 0.0,
+            _ => !matches!(self, Material::Metal(_, _) | Material::Dieletric(_) | Material::DiffuseLight(_)),
+        }
+    }
+
+    pub fn is_emissive(&self) -> bool {
+        match self {
+            Material::Detailed(material, _) => material.is_emissive(),
+            _ => matches!(self, Material::DiffuseLight(_)),
+        }
+    }
+
     pub fn emitted(&self, textures: &[Texture], u: f32, v: f32, p: &Vec3) -> Vec3 {
         match self {
             Material::DiffuseLight(tex_ref) => textures[*tex_ref].value(textures, u, v, p),
+            Material::Detailed(material, _) => material.emitted(textures, u, v, p),
             _ => Vec3::zero()
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sampler::SobolSampler;
+
+    fn hit_record() -> HitRecord {
+        let mut hit_record = HitRecord::zero();
+        hit_record.normal = Vec3::new(0.0, 0.0, 1.0);
+        hit_record
+    }
+
+    #[test]
+    fn fuzzy_metal_samples_match_its_pdf() {
+        let metal = Material::Metal(Vec3::new(0.9, 0.6, 0.3), 0.5);
+        let ray = Ray::new(Vec3::new(-1.0, 0.0, 1.0), Vec3::new(1.0, 0.0, -1.0), 0.0);
+        let hit_record = hit_record();
+        assert!(metal.samples_lights());
+
+        let mut sampler = SobolSampler::new(1);
+        for sample in 0..256 {
+            sampler.start_sample(0, sample);
+            if let Some(scatter) = metal.sample(&[], &ray, &hit_record, &mut sampler) {
+                let direction = scatter.ray.direction();
+                let pdf = metal.pdf(&[], &ray, &hit_record, &direction);
+                assert!(!scatter.specular);
+                assert!((scatter.pdf - pdf).abs() <= 1e-4 * pdf, "sampled pdf {} but pdf is {}", scatter.pdf, pdf);
+                let eval = metal.eval(&[], &ray, &hit_record, &direction);
+                assert!((eval - scatter.attenuation * pdf).length() <= 1e-4 * eval.length());
+            }
+        }
+    }
+
+    #[test]
+    fn fuzzy_metal_pdf_integrates_to_one() {
+        // Straight down, so that every direction is reflected above the surface
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let hit_record = hit_record();
+        for &fuzz in &[0.1, 0.5, 1.0] {
+            let metal = Material::Metal(Vec3::uniform(1.0), fuzz);
+            let steps = 2000;
+            let mut total = 0.0;
+            for i in 0..steps {
+                // Solid angle is uniform in z, and the pdf is the same all the way round the normal
+                let z = (i as f32 + 0.5) / steps as f32;
+                let direction = Vec3::new((1.0 - z * z).sqrt(), 0.0, z);
+                total += metal.pdf(&[], &ray, &hit_record, &direction) * 2.0 * PI / steps as f32;
+            }
+            assert!((total - 1.0).abs() < 0.01, "fuzz {} integrates to {}", fuzz, total);
+        }
+    }
+
+    #[test]
+    fn perfect_mirrors_are_specular() {
+        let metal = Material::Metal(Vec3::uniform(1.0), 0.0);
+        let ray = Ray::new(Vec3::new(-1.0, 0.0, 1.0), Vec3::new(1.0, 0.0, -1.0), 0.0);
+        let scatter = metal.sample(&[], &ray, &hit_record(), &mut SobolSampler::new(1)).unwrap();
+        assert!(scatter.specular);
+        assert!(!metal.samples_lights());
+        assert_eq!(metal.pdf(&[], &ray, &hit_record(), &scatter.ray.direction()), 0.0);
+    }
+
+    fn microfacet_materials() -> Vec<Material> {
+        let (_, [er, eg, eb], [kr, kg, kb]) = CONDUCTORS[0];
+        let mut materials = vec![];
+        for &roughness in &[0.2, 0.8] {
+            materials.push(Material::Conductor { eta: Vec3::new(er, eg, eb), k: Vec3::new(kr, kg, kb), roughness });
+            materials.push(Material::RoughDielectric { ior: 1.5, roughness });
+        }
+        materials
+    }
+
+    #[test]
+    fn microfacet_samples_match_their_pdf() {
+        let hit_record = hit_record();
+        // Grazing and head on from outside, and from inside for the glass to refract back out
+        let rays = [
+            Ray::new(Vec3::new(-1.0, 0.0, 0.1), Vec3::new(1.0, 0.0, -0.1), 0.0),
+            Ray::new(Vec3::new(-0.3, 0.2, 1.0), Vec3::new(0.3, -0.2, -1.0), 0.0),
+            Ray::new(Vec3::new(-0.5, 0.0, -1.0), Vec3::new(0.5, 0.0, 1.0), 0.0),
+        ];
+        for material in microfacet_materials() {
+            assert!(material.samples_lights());
+            let mut sampler = SobolSampler::new(2);
+            for ray in &rays {
+                let mut sampled = 0;
+                for sample in 0..256 {
+                    sampler.start_sample(0, sample);
+                    if let Some(scatter) = material.sample(&[], ray, &hit_record, &mut sampler) {
+                        sampled += 1;
+                        let direction = scatter.ray.direction();
+                        let pdf = material.pdf(&[], ray, &hit_record, &direction);
+                        assert!(!scatter.specular);
+                        assert!((scatter.pdf - pdf).abs() <= 1e-3 * pdf, "{:?} sampled pdf {} but pdf is {}", material, scatter.pdf, pdf);
+                        let eval = material.eval(&[], ray, &hit_record, &direction);
+                        assert!((eval - scatter.attenuation * pdf).length() <= 1e-3 * eval.length(), "{:?}", material);
+                    }
+                }
+                assert!(sampled > 0, "{:?} never scattered {:?}", material, ray);
+            }
+        }
+    }
+
+    #[test]
+    fn conductors_only_reflect_into_the_side_the_ray_came_from() {
+        let hit_record = hit_record();
+        let ray = Ray::new(Vec3::new(-1.0, 0.0, 1.0), Vec3::new(1.0, 0.0, -1.0), 0.0);
+        for material in microfacet_materials().into_iter().filter(|material| matches!(material, Material::Conductor { .. })) {
+            assert!(material.eval(&[], &ray, &hit_record, &Vec3::new(1.0, 0.0, 1.0)).length() > 0.0);
+            for direction in &[Vec3::new(1.0, 0.0, -1.0), Vec3::new(0.0, 0.0, -1.0), Vec3::new(-0.2, 0.5, -0.1)] {
+                assert_eq!(material.eval(&[], &ray, &hit_record, direction), Vec3::zero(), "{:?}", material);
+                assert_eq!(material.pdf(&[], &ray, &hit_record, direction), 0.0, "{:?}", material);
+            }
+        }
+    }
+}
@@ -0,0 +1,318 @@
+use aabb::AABBVolume;
+use bvh::{BvhStats, CompactBvh, DEFAULT_MAX_LEAF_SIZE};
+use hitable::{HitRecord, Hitable};
+use scene::{Entities, MaterialRef};
+use ray::Ray;
+use vec3::Vec3;
+
+// Padding so that axis aligned triangles still get a bounding box with volume
+const BBOX_PADDING: f32 = 0.0001;
+
+#[inline]
+fn max_dimension(v: &Vec3) -> usize {
+    if v.x() > v.y() {
+        if v.x() > v.z() { 0 } else { 2 }
+    } else if v.y() > v.z() {
+        1
+    } else {
+        2
+    }
+}
+
+#[inline]
+fn permute(v: &Vec3, x: usize, y: usize, z: usize) -> Vec3 {
+    Vec3::new(v[x], v[y], v[z])
+}
+
+#[inline]
+fn abs(v: &Vec3) -> Vec3 {
+    Vec3::new(v.x().abs(), v.y().abs(), v.z().abs())
+}
+
+// Watertight ray/triangle intersection, credit to Woop et al. - http://jcgt.org/published/0002/01/05/
+// Rays that hit an edge shared by two triangles always hit at least one of them, so meshes don't leak light. Only rays
+// exactly on the edge hit both, at the same t
+// Returns the ray parameter and barycentric coordinates of the hit
+fn intersect_triangle(ray: &Ray, p0: &Vec3, p1: &Vec3, p2: &Vec3, t_min: f32, t_max: f32) -> Option<(f32, [f32; 3])> {
+    let origin = ray.origin();
+    let direction = ray.direction();
+
+    // Transform the triangle into a space where the ray starts at the origin and points along +z
+    let kz = max_dimension(&abs(&direction));
+    let kx = (kz + 1) % 3;
+    let ky = (kx + 1) % 3;
+    let d = permute(&direction, kx, ky, kz);
+    let mut p0t = permute(&(*p0 - origin), kx, ky, kz);
+    let mut p1t = permute(&(*p1 - origin), kx, ky, kz);
+    let mut p2t = permute(&(*p2 - origin), kx, ky, kz);
+
+    let sx = -d.x() / d.z();
+    let sy = -d.y() / d.z();
+    let sz = 1.0 / d.z();
+    p0t[0] += sx * p0t.z();
+    p0t[1] += sy * p0t.z();
+    p1t[0] += sx * p1t.z();
+    p1t[1] += sy * p1t.z();
+    p2t[0] += sx * p2t.z();
+    p2t[1] += sy * p2t.z();
+
+    let mut e0 = p1t.x() * p2t.y() - p1t.y() * p2t.x();
+    let mut e1 = p2t.x() * p0t.y() - p2t.y() * p0t.x();
+    let mut e2 = p0t.x() * p1t.y() - p0t.y() * p1t.x();
+
+    // Fall back to double precision when the ray passes exactly through an edge
+    if e0 == 0.0 || e1 == 0.0 || e2 == 0.0 {
+        e0 = (f64::from(p1t.x()) * f64::from(p2t.y()) - f64::from(p1t.y()) * f64::from(p2t.x())) as f32;
+        e1 = (f64::from(p2t.x()) * f64::from(p0t.y()) - f64::from(p2t.y()) * f64::from(p0t.x())) as f32;
+        e2 = (f64::from(p0t.x()) * f64::from(p1t.y()) - f64::from(p0t.y()) * f64::from(p1t.x())) as f32;
+    }
+
+    if (e0 < 0.0 || e1 < 0.0 || e2 < 0.0) && (e0 > 0.0 || e1 > 0.0 || e2 > 0.0) {
+        return None;
+    }
+
+    let det = e0 + e1 + e2;
+    if det == 0.0 {
+        return None;
+    }
+
+    let t_scaled = e0 * p0t.z() * sz + e1 * p1t.z() * sz + e2 * p2t.z() * sz;
+    let t = t_scaled / det;
+    if t <= t_min || t >= t_max {
+        return None;
+    }
+
+    Some((t, [e0 / det, e1 / det, e2 / det]))
+}
+
+// Derivatives of the position across a triangle with respect to its texture coordinates. Falls back to the edges from
+// the first vertex when the texture coordinates don't span an area
+fn uv_tangents(positions: &[Vec3; 3], uvs: &[(f32, f32); 3]) -> (Vec3, Vec3) {
+    let [p0, p1, p2] = *positions;
+    let (du02, dv02) = (uvs[0].0 - uvs[2].0, uvs[0].1 - uvs[2].1);
+    let (du12, dv12) = (uvs[1].0 - uvs[2].0, uvs[1].1 - uvs[2].1);
+    let determinant = du02 * dv12 - dv02 * du12;
+    if determinant.abs() < 1e-8 {
+        return (p1 - p0, p2 - p0);
+    }
+
+    let (dp02, dp12) = (p0 - p2, p1 - p2);
+    ((dv12 * dp02 - dv02 * dp12) / determinant, (du02 * dp12 - du12 * dp02) / determinant)
+}
+
+fn triangle_bbox(p0: &Vec3, p1: &Vec3, p2: &Vec3) -> AABBVolume {
+    let min = p0.min(p1).min(p2) - Vec3::uniform(BBOX_PADDING);
+    let max = p0.max(p1).max(p2) + Vec3::uniform(BBOX_PADDING);
+    AABBVolume::new(min, max)
+}
+
+#[derive(Debug, Clone)]
+pub struct Triangle {
+    vertices: [Vec3; 3],
+    normal: Vec3,
+    material: MaterialRef,
+}
+
+impl Triangle {
+    pub fn new(v0: Vec3, v1: Vec3, v2: Vec3, material: MaterialRef) -> Triangle {
+        let normal = Vec3::cross(&(v1 - v0), &(v2 - v0)).unit();
+        Triangle {
+            vertices: [v0, v1, v2],
+            normal,
+            material,
+        }
+    }
+}
+
+impl Hitable for Triangle {
+    fn hit_ptr(&self, _entities: &Entities, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
+        let [v0, v1, v2] = self.vertices;
+        match intersect_triangle(ray, &v0, &v1, &v2, t_min, t_max) {
+            Some((t, barycentric)) => {
+                hit_record.t = t;
+                hit_record.p = ray.point_at_parameter(t);
+                hit_record.u = barycentric[1];
+                hit_record.v = barycentric[2];
+                hit_record.normal = self.normal;
+                hit_record.dpdu = v1 - v0;
+                hit_record.dpdv = v2 - v0;
+                hit_record.material = self.material;
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn bounding_box(&self, _entities: &Entities, _t_min: f32, _t_max: f32) -> Option<AABBVolume> {
+        let [v0, v1, v2] = self.vertices;
+        Some(triangle_bbox(&v0, &v1, &v2))
+    }
+}
+
+// Indexes into the vertex buffers of a TriangleMesh
+#[derive(Debug, Clone, Copy)]
+pub struct MeshTriangle {
+    pub positions: [u32; 3],
+    pub normals: Option<[u32; 3]>,
+    pub uvs: Option<[u32; 3]>,
+    pub material: MaterialRef,
+}
+
+#[derive(Debug)]
+pub struct TriangleMesh {
+    positions: Vec<Vec3>,
+    normals: Vec<Vec3>,
+    uvs: Vec<(f32, f32)>,
+    triangles: Vec<MeshTriangle>,
+    // Triangles get their own tree so a mesh is a single entity in the scene's Bvh
+    bvh: CompactBvh,
+}
+
+impl TriangleMesh {
+    pub fn new(positions: Vec<Vec3>, normals: Vec<Vec3>, uvs: Vec<(f32, f32)>, triangles: Vec<MeshTriangle>) -> Result<TriangleMesh, String> {
+        if triangles.is_empty() {
+            return Err("a mesh needs at least one triangle".to_owned());
+        }
+
+        let in_bounds = |indexes: Option<[u32; 3]>, len: usize| {
+            indexes.is_none_or(|indexes| indexes.iter().all(|&i| (i as usize) < len))
+        };
+        for (index, triangle) in triangles.iter().enumerate() {
+            if !in_bounds(Some(triangle.positions), positions.len())
+                || !in_bounds(triangle.normals, normals.len())
+                || !in_bounds(triangle.uvs, uvs.len())
+            {
+                return Err(format!("triangle {} refers to a vertex that does not exist", index));
+            }
+        }
+
+        let bounds = triangles.iter().enumerate().map(|(index, triangle)| {
+            let [p0, p1, p2] = triangle.positions;
+            (index, triangle_bbox(&positions[p0 as usize], &positions[p1 as usize], &positions[p2 as usize]))
+        }).collect();
+
+        Ok(TriangleMesh {
+            bvh: CompactBvh::from_bounds(bounds, DEFAULT_MAX_LEAF_SIZE),
+            positions,
+            normals,
+            uvs,
+            triangles,
+        })
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.triangles.len()
+    }
+
+    pub fn bvh_stats(&self) -> BvhStats {
+        self.bvh.stats()
+    }
+
+    fn hit_triangle(&self, index: usize, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
+        let triangle = &self.triangles[index];
+        let [i0, i1, i2] = triangle.positions;
+        let p0 = self.positions[i0 as usize];
+        let p1 = self.positions[i1 as usize];
+        let p2 = self.positions[i2 as usize];
+
+        let (t, [b0, b1, b2]) = match intersect_triangle(ray, &p0, &p1, &p2, t_min, t_max) {
+            Some(hit) => hit,
+            None => return false,
+        };
+
+        // Without texture coordinates u and v are the barycentric coordinates of p1 and p2
+        let (u, v, dpdu, dpdv) = match triangle.uvs {
+            Some([t0, t1, t2]) => {
+                let (uv0, uv1, uv2) = (self.uvs[t0 as usize], self.uvs[t1 as usize], self.uvs[t2 as usize]);
+                let (dpdu, dpdv) = uv_tangents(&[p0, p1, p2], &[uv0, uv1, uv2]);
+                (b0 * uv0.0 + b1 * uv1.0 + b2 * uv2.0, b0 * uv0.1 + b1 * uv1.1 + b2 * uv2.1, dpdu, dpdv)
+            },
+            None => (b1, b2, p1 - p0, p2 - p0),
+        };
+
+        let normal = match triangle.normals {
+            Some([n0, n1, n2]) => {
+                (b0 * self.normals[n0 as usize] + b1 * self.normals[n1 as usize] + b2 * self.normals[n2 as usize]).unit()
+            },
+            None => Vec3::cross(&(p1 - p0), &(p2 - p0)).unit(),
+        };
+
+        hit_record.t = t;
+        hit_record.p = ray.point_at_parameter(t);
+        hit_record.u = u;
+        hit_record.v = v;
+        hit_record.normal = normal;
+        hit_record.dpdu = dpdu;
+        hit_record.dpdv = dpdv;
+        hit_record.material = triangle.material;
+        true
+    }
+}
+
+impl Hitable for TriangleMesh {
+    fn hit_ptr(&self, _entities: &Entities, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
+        self.bvh.hit_with(ray, t_min, t_max, hit_record, |index, t_min, t_max, hit_record| {
+            self.hit_triangle(index, ray, t_min, t_max, hit_record)
+        })
+    }
+
+    fn bounding_box(&self, _entities: &Entities, _t_min: f32, _t_max: f32) -> Option<AABBVolume> {
+        self.bvh.bounding_box()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hits(ray: &Ray, triangle: &[Vec3; 3]) -> Option<(f32, [f32; 3])> {
+        intersect_triangle(ray, &triangle[0], &triangle[1], &triangle[2], 0.001, f32::MAX)
+    }
+
+    #[test]
+    fn hits_inside_a_triangle_at_its_barycentric_coordinates() {
+        let triangle = [Vec3::new(0.0, 0.0, 0.0), Vec3::new(4.0, 0.0, 0.0), Vec3::new(0.0, 2.0, 0.0)];
+        let ray = Ray::new(Vec3::new(1.0, 0.5, 3.0), Vec3::new(0.0, 0.0, -2.0), 0.0);
+        let (t, [b0, b1, b2]) = hits(&ray, &triangle).unwrap();
+        assert!((t - 1.5).abs() < 1e-6);
+        assert!((b0 - 0.5).abs() < 1e-6 && (b1 - 0.25).abs() < 1e-6 && (b2 - 0.25).abs() < 1e-6);
+
+        // From behind, outside of [t_min, t_max], parallel to it and outside of it
+        assert!(hits(&Ray::new(Vec3::new(1.0, 0.5, -3.0), Vec3::new(0.0, 0.0, 2.0), 0.0), &triangle).is_some());
+        assert!(intersect_triangle(&ray, &triangle[0], &triangle[1], &triangle[2], 0.001, 1.0).is_none());
+        assert!(hits(&Ray::new(Vec3::new(-1.0, 0.5, 0.0), Vec3::new(1.0, 0.0, 0.0), 0.0), &triangle).is_none());
+        assert!(hits(&Ray::new(Vec3::new(3.0, 1.5, 3.0), Vec3::new(0.0, 0.0, -1.0), 0.0), &triangle).is_none());
+    }
+
+    #[test]
+    fn rays_through_a_shared_edge_hit_a_triangle() {
+        // Two halves of a square split along the diagonal from (0, 0) to (1, 1), at an angle so the edge isn't
+        // axis aligned in the ray's space
+        let corners = [Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.3), Vec3::new(1.0, 1.0, 0.1), Vec3::new(0.0, 1.0, -0.2)];
+        let halves = [[corners[0], corners[1], corners[2]], [corners[0], corners[2], corners[3]]];
+        for step in 1..100 {
+            let s = step as f32 / 100.0;
+            let on_edge = corners[0] + s * (corners[2] - corners[0]);
+            let ray = Ray::new(on_edge + Vec3::new(0.1, -0.2, 2.0), Vec3::new(-0.1, 0.2, -2.0), 0.0);
+            let count = halves.iter().filter(|half| hits(&ray, half).is_some()).count();
+            assert_eq!(count, 1, "a ray through {:?}", on_edge);
+        }
+
+        // Straight down onto a flat square the edge functions are exactly zero along the diagonal, so both are hit
+        let flat = [Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), Vec3::new(1.0, 1.0, 0.0), Vec3::new(0.0, 1.0, 0.0)];
+        let halves = [[flat[0], flat[1], flat[2]], [flat[0], flat[2], flat[3]]];
+        let ray = Ray::new(Vec3::new(0.5, 0.5, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let ts: Vec<f32> = halves.iter().filter_map(|half| hits(&ray, half)).map(|(t, _)| t).collect();
+        assert_eq!(ts, vec![1.0, 1.0]);
+    }
+
+    #[test]
+    fn degenerate_triangles_are_never_hit() {
+        let ray = Ray::new(Vec3::new(0.5, 0.0, 3.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let line = [Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), Vec3::new(2.0, 0.0, 0.0)];
+        let point = [Vec3::new(0.5, 0.0, 0.0); 3];
+        assert!(hits(&ray, &line).is_none());
+        assert!(hits(&ray, &point).is_none());
+        assert!(TriangleMesh::new(line.to_vec(), vec![], vec![], vec![]).is_err());
+    }
+}
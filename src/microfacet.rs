@@ -0,0 +1,273 @@
+// Trowbridge-Reitz (GGX) microfacet distribution and the Fresnel terms used with it
+// Directions are in a local frame with the macro surface normal along +z
+// Credit to Walter et al. - Microfacet Models for Refraction through Rough Surfaces and
+// Heitz - Sampling the GGX Distribution of Visible Normals - https://jcgt.org/published/0007/04/01/
+
+use vec3::Vec3;
+
+use std::f32::consts::PI;
+use std::ops::{Add, Div, Mul, Sub};
+
+// Below this the distribution is too sharp to evaluate reliably
+const MIN_ALPHA: f32 = 1e-3;
+
+#[derive(Debug, Clone, Copy)]
+pub struct TrowbridgeReitz {
+    alpha: f32,
+}
+
+impl TrowbridgeReitz {
+    // roughness is perceptually linear, alpha is its square
+    pub fn new(roughness: f32) -> TrowbridgeReitz {
+        TrowbridgeReitz { alpha: (roughness * roughness).max(MIN_ALPHA) }
+    }
+
+    // Density of microfacet normals h, projected onto the macro surface it integrates to 1
+    pub fn d(&self, h: &Vec3) -> f32 {
+        let alpha2 = self.alpha * self.alpha;
+        let denominator = h.z() * h.z() * (alpha2 - 1.0) + 1.0;
+        alpha2 / (PI * denominator * denominator)
+    }
+
+    // Smith's auxiliary function, the area of backfacing microfacets seen from w relative to the visible area
+    fn lambda(&self, w: &Vec3) -> f32 {
+        let cos2 = w.z() * w.z();
+        if cos2 <= 0.0 {
+            return f32::INFINITY;
+        }
+        let tan2 = (1.0 - cos2).max(0.0) / cos2;
+        ((1.0 + self.alpha * self.alpha * tan2).sqrt() - 1.0) / 2.0
+    }
+
+    // Fraction of microfacets facing w that are visible from it
+    pub fn g1(&self, w: &Vec3) -> f32 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    // Fraction visible from both wo and wi, height correlated
+    pub fn g(&self, wo: &Vec3, wi: &Vec3) -> f32 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    // Density of the normals visible from wo, which is what sample_visible_normal picks from
+    pub fn visible_pdf(&self, wo: &Vec3, h: &Vec3) -> f32 {
+        let cosine = Vec3::dot(wo, h);
+        if cosine <= 0.0 || wo.z() <= 0.0 {
+            return 0.0;
+        }
+        self.g1(wo) * cosine * self.d(h) / wo.z()
+    }
+
+    // Picks a microfacet normal visible from wo, which must be above the surface
+    pub fn sample_visible_normal(&self, wo: &Vec3, sample: [f32; 2]) -> Vec3 {
+        // Stretch to the configuration where the distribution is a hemisphere
+        let vh = Vec3::new(self.alpha * wo.x(), self.alpha * wo.y(), wo.z()).unit();
+
+        let length_squared = vh.x() * vh.x() + vh.y() * vh.y();
+        let t1 = if length_squared > 0.0 {
+            Vec3::new(-vh.y(), vh.x(), 0.0) / length_squared.sqrt()
+        } else {
+            Vec3::new(1.0, 0.0, 0.0)
+        };
+        let t2 = Vec3::cross(&vh, &t1);
+
+        // Uniform point on a disk, squashed onto the half of it that the visible hemisphere projects to
+        let r = sample[0].sqrt();
+        let phi = 2.0 * PI * sample[1];
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + vh.z());
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).max(0.0).sqrt() + s * r * phi.sin();
+
+        let nh = p1 * t1 + p2 * t2 + (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt() * vh;
+        Vec3::new(self.alpha * nh.x(), self.alpha * nh.y(), nh.z().max(1e-6)).unit()
+    }
+
+    // Direction of a reflection off a microfacet visible from wo, None if it goes below the surface
+    pub fn sample_reflection(&self, wo: &Vec3, sample: [f32; 2]) -> Option<Vec3> {
+        if wo.z() <= 0.0 {
+            return None;
+        }
+        let wi = reflect(wo, &self.sample_visible_normal(wo, sample));
+        if wi.z() > 0.0 { Some(wi) } else { None }
+    }
+
+    // The half vector, the BSDF times the cosine term without the Fresnel term and the pdf of sample_reflection
+    pub fn reflection(&self, wo: &Vec3, wi: &Vec3) -> Option<(Vec3, f32, f32)> {
+        if wo.z() <= 0.0 || wi.z() <= 0.0 {
+            return None;
+        }
+        let h = (*wo + *wi).unit();
+        let cosine = Vec3::dot(wo, &h);
+        if cosine <= 0.0 {
+            return None;
+        }
+        Some((h, self.d(&h) * self.g(wo, wi) / (4.0 * wo.z()), self.visible_pdf(wo, &h) / (4.0 * cosine)))
+    }
+
+    // Reflects off or refracts through a visible microfacet of a dielectric interface, choosing by the Fresnel term.
+    // eta is the index of refraction below the surface over the one above it
+    pub fn sample_dielectric(&self, wo: &Vec3, eta: f32, sample: [f32; 2], choice: f32) -> Option<Vec3> {
+        if wo.z() <= 0.0 {
+            return None;
+        }
+        let h = self.sample_visible_normal(wo, sample);
+        let wi = if choice < fresnel_dielectric(Vec3::dot(wo, &h), eta) {
+            reflect(wo, &h)
+        } else {
+            refract(wo, &h, eta)?
+        };
+        // A reflection below the surface or a refraction above it has gone through the microsurface
+        if wi.z() == 0.0 || (wi.z() > 0.0) != (Vec3::dot(&wi, &h) > 0.0) {
+            None
+        } else {
+            Some(wi)
+        }
+    }
+
+    // The BSDF times the cosine term and the pdf of sample_dielectric. Light refracted below the surface is scaled by
+    // 1 / eta^2 as it is squeezed into a smaller solid angle
+    pub fn dielectric(&self, wo: &Vec3, wi: &Vec3, eta: f32) -> (f32, f32) {
+        if wo.z() <= 0.0 || wi.z() == 0.0 {
+            return (0.0, 0.0);
+        }
+
+        let reflection = wi.z() > 0.0;
+        // The generalised half vector, the microfacet normal that scatters wo into wi
+        let h = if reflection { *wo + *wi } else { *wi * eta + *wo };
+        if h.squared_length() == 0.0 {
+            return (0.0, 0.0);
+        }
+        let h = if h.z() < 0.0 { -h.unit() } else { h.unit() };
+
+        // Microfacets facing away from either direction can't connect them
+        let (cos_o, cos_i) = (Vec3::dot(wo, &h), Vec3::dot(wi, &h));
+        if cos_o <= 0.0 || cos_i * wi.z() <= 0.0 {
+            return (0.0, 0.0);
+        }
+
+        let fresnel = fresnel_dielectric(cos_o, eta);
+        let d = self.d(&h);
+        let g = self.g(wo, wi);
+        let visible_pdf = self.visible_pdf(wo, &h);
+        if reflection {
+            (fresnel * d * g / (4.0 * wo.z()), fresnel * visible_pdf / (4.0 * cos_o))
+        } else {
+            let denominator = (cos_i + cos_o / eta).powi(2);
+            (
+                (1.0 - fresnel) * d * g * cos_i.abs() * cos_o / (wo.z() * denominator * eta * eta),
+                (1.0 - fresnel) * visible_pdf * cos_i.abs() / denominator,
+            )
+        }
+    }
+}
+
+pub fn reflect(w: &Vec3, h: &Vec3) -> Vec3 {
+    2.0 * Vec3::dot(w, h) * *h - *w
+}
+
+// Refracts w through the interface with normal h on its side, eta is the index on the far side over the index on w's
+// side. None for total internal reflection
+pub fn refract(w: &Vec3, h: &Vec3, eta: f32) -> Option<Vec3> {
+    let cos_i = Vec3::dot(w, h);
+    let sin2_t = (1.0 - cos_i * cos_i).max(0.0) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(-*w / eta + (cos_i / eta - cos_t) * *h)
+}
+
+// Polynomial approximation of the Fresnel term by Christophe Schlick, f0 is the reflectance at normal incidence
+pub fn schlick(cos_i: f32, f0: &Vec3) -> Vec3 {
+    *f0 + (Vec3::uniform(1.0) - *f0) * (1.0 - cos_i.clamp(0.0, 1.0)).powi(5)
+}
+
+// Unpolarised reflectance of a dielectric interface, eta as for refract
+pub fn fresnel_dielectric(cos_i: f32, eta: f32) -> f32 {
+    let cos_i = cos_i.clamp(0.0, 1.0);
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    (parallel * parallel + perpendicular * perpendicular) / 2.0
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Complex {
+    re: f32,
+    im: f32,
+}
+
+impl Complex {
+    fn new(re: f32, im: f32) -> Complex {
+        Complex { re, im }
+    }
+
+    fn norm_squared(self) -> f32 {
+        self.re * self.re + self.im * self.im
+    }
+
+    // Principal square root
+    fn sqrt(self) -> Complex {
+        let length = self.norm_squared().sqrt();
+        let re = ((length + self.re) / 2.0).max(0.0).sqrt();
+        let im = ((length - self.re) / 2.0).max(0.0).sqrt();
+        Complex::new(re, if self.im < 0.0 { -im } else { im })
+    }
+}
+
+impl Add for Complex {
+    type Output = Complex;
+    fn add(self, other: Complex) -> Complex {
+        Complex::new(self.re + other.re, self.im + other.im)
+    }
+}
+
+impl Sub for Complex {
+    type Output = Complex;
+    fn sub(self, other: Complex) -> Complex {
+        Complex::new(self.re - other.re, self.im - other.im)
+    }
+}
+
+impl Mul for Complex {
+    type Output = Complex;
+    fn mul(self, other: Complex) -> Complex {
+        Complex::new(self.re * other.re - self.im * other.im, self.re * other.im + self.im * other.re)
+    }
+}
+
+impl Div for Complex {
+    type Output = Complex;
+    fn div(self, other: Complex) -> Complex {
+        let scale = 1.0 / other.norm_squared();
+        Complex::new(
+            scale * (self.re * other.re + self.im * other.im),
+            scale * (self.im * other.re - self.re * other.im),
+        )
+    }
+}
+
+fn fresnel_complex_channel(cos_i: f32, eta: f32, k: f32) -> f32 {
+    let eta = Complex::new(eta, k);
+    let cos_i = Complex::new(cos_i.clamp(0.0, 1.0), 0.0);
+    let sin2_i = Complex::new(1.0, 0.0) - cos_i * cos_i;
+    let sin2_t = sin2_i / (eta * eta);
+    let cos_t = (Complex::new(1.0, 0.0) - sin2_t).sqrt();
+
+    let parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    (parallel.norm_squared() + perpendicular.norm_squared()) / 2.0
+}
+
+// Reflectance of a conductor with complex index of refraction eta + ik for each channel
+pub fn fresnel_conductor(cos_i: f32, eta: &Vec3, k: &Vec3) -> Vec3 {
+    Vec3::new(
+        fresnel_complex_channel(cos_i, eta.r(), k.r()),
+        fresnel_complex_channel(cos_i, eta.g(), k.g()),
+        fresnel_complex_channel(cos_i, eta.b(), k.b()),
+    )
+}
@@ -0,0 +1,305 @@
+// Wavefront OBJ/MTL loading
+// Supports v, vt, vn and f (polygons are triangulated as fans) plus mtllib/usemtl. Everything else
+// (groups, smoothing groups, lines, etc.) is ignored.
+
+use material::Material;
+use mesh::{MeshTriangle, TriangleMesh};
+use scene::{MaterialRef, Resources};
+use texture::Texture;
+use vec3::Vec3;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+// Position, uv and normal indexes of one corner of a face
+type FaceVertex = (u32, Option<u32>, Option<u32>);
+
+// Resolves a 1 based (or negative, relative to the end) OBJ index into a 0 based index
+fn resolve_index(token: &str, len: usize) -> Result<u32, String> {
+    let index: i64 = token.parse().map_err(|_| format!("invalid index '{}'", token))?;
+    let resolved = if index > 0 { index - 1 } else { len as i64 + index };
+
+    if index == 0 || resolved < 0 || resolved >= len as i64 {
+        Err(format!("index {} is out of range", index))
+    } else {
+        Ok(resolved as u32)
+    }
+}
+
+fn parse_floats(values: &[&str], count: usize) -> Result<Vec<f32>, String> {
+    if values.len() < count {
+        return Err(format!("expected {} numbers but found {}", count, values.len()));
+    }
+
+    values[..count].iter()
+        .map(|value| value.parse::<f32>().map_err(|_| format!("invalid number '{}'", value)))
+        .collect()
+}
+
+fn parse_vec3(values: &[&str]) -> Result<Vec3, String> {
+    let floats = parse_floats(values, 3)?;
+    Ok(Vec3::new(floats[0], floats[1], floats[2]))
+}
+
+#[derive(Debug)]
+struct MtlEntry {
+    diffuse: Vec3,
+    specular: Vec3,
+    emission: Vec3,
+    shininess: f32,
+    ior: f32,
+    dissolve: f32,
+    illum: u32,
+    diffuse_map: Option<String>,
+}
+
+impl MtlEntry {
+    fn new() -> MtlEntry {
+        MtlEntry {
+            diffuse: Vec3::uniform(0.8),
+            specular: Vec3::zero(),
+            emission: Vec3::zero(),
+            shininess: 0.0,
+            ior: 1.5,
+            dissolve: 1.0,
+            illum: 2,
+            diffuse_map: None,
+        }
+    }
+
+    fn into_material(self, directory: &Path, resources: &mut Resources) -> Result<MaterialRef, String> {
+        let material = if self.emission.max_component() > 0.0 {
+            let emit = resources.new_texture(Texture::Constant(self.emission));
+            Material::DiffuseLight(emit)
+        } else if self.dissolve < 1.0 || [4, 6, 7, 9].contains(&self.illum) {
+            Material::Dieletric(self.ior)
+        } else if [3, 5, 8].contains(&self.illum) && self.specular.max_component() > 0.0 {
+            // Convert the Phong exponent into a roughness
+            Material::Metal(self.specular, (2.0 / (self.shininess + 2.0)).sqrt())
+        } else if let Some(diffuse_map) = self.diffuse_map {
+            let path = directory.join(diffuse_map);
+            let image = ::imagers::open(&path).map_err(|e| format!("could not open image {}: {}", path.display(), e))?;
+            let albedo = resources.new_texture(Texture::Image(image.to_rgb()));
+            Material::LambertianTextured(albedo)
+        } else {
+            Material::Lambertian(self.diffuse)
+        };
+
+        Ok(resources.new_material(material))
+    }
+}
+
+fn load_mtl(path: &Path, resources: &mut Resources, materials: &mut HashMap<String, MaterialRef>) -> Result<(), String> {
+    let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let directory = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut current: Option<(String, MtlEntry)> = None;
+
+    for (line_index, line) in source.lines().enumerate() {
+        let error = |message: String| format!("{}:{}: {}", path.display(), line_index + 1, message);
+        let tokens: Vec<&str> = line.split('#').next().unwrap().split_whitespace().collect();
+        if tokens.is_empty() {
+            continue;
+        }
+
+        if tokens[0] == "newmtl" {
+            if let Some((name, entry)) = current.take() {
+                let material = entry.into_material(directory, resources).map_err(&error)?;
+                materials.insert(name, material);
+            }
+            let name = tokens[1..].join(" ");
+            current = Some((name, MtlEntry::new()));
+            continue;
+        }
+
+        let entry = match current.as_mut() {
+            Some((_, entry)) => entry,
+            None => return Err(error(format!("'{}' appears before any newmtl", tokens[0]))),
+        };
+        let values = &tokens[1..];
+
+        match tokens[0] {
+            "Kd" => entry.diffuse = parse_vec3(values).map_err(&error)?,
+            "Ks" => entry.specular = parse_vec3(values).map_err(&error)?,
+            "Ke" => entry.emission = parse_vec3(values).map_err(&error)?,
+            "Ns" => entry.shininess = parse_floats(values, 1).map_err(&error)?[0],
+            "Ni" => entry.ior = parse_floats(values, 1).map_err(&error)?[0],
+            "d" => entry.dissolve = parse_floats(values, 1).map_err(&error)?[0],
+            "Tr" => entry.dissolve = 1.0 - parse_floats(values, 1).map_err(&error)?[0],
+            "illum" => entry.illum = values.first()
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(|| error("invalid illumination model".to_owned()))?,
+            // Options such as -bm come before the file name so it is always the last value
+            "map_Kd" => entry.diffuse_map = values.last().map(|value| (*value).to_owned()),
+            _ => {},
+        }
+    }
+
+    if let Some((name, entry)) = current.take() {
+        let material = entry.into_material(directory, resources)?;
+        materials.insert(name, material);
+    }
+
+    Ok(())
+}
+
+// Faces without a usemtl use default_material
+pub fn load_obj<P: AsRef<Path>>(path: P, resources: &mut Resources, default_material: MaterialRef) -> Result<TriangleMesh, String> {
+    let path = path.as_ref();
+    let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let directory = path.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut positions = vec![];
+    let mut normals = vec![];
+    let mut uvs = vec![];
+    let mut triangles = vec![];
+    let mut materials = HashMap::new();
+    let mut current_material = default_material;
+
+    for (line_index, line) in source.lines().enumerate() {
+        let error = |message: String| format!("{}:{}: {}", path.display(), line_index + 1, message);
+        let tokens: Vec<&str> = line.split('#').next().unwrap().split_whitespace().collect();
+        if tokens.is_empty() {
+            continue;
+        }
+        let values = &tokens[1..];
+
+        match tokens[0] {
+            "v" => positions.push(parse_vec3(values).map_err(&error)?),
+            "vn" => normals.push(parse_vec3(values).map_err(&error)?.unit()),
+            "vt" => {
+                let uv = parse_floats(values, 2).map_err(&error)?;
+                uvs.push((uv[0], uv[1]));
+            },
+            "f" => {
+                if values.len() < 3 {
+                    return Err(error("a face needs at least 3 vertices".to_owned()));
+                }
+
+                let mut vertices: Vec<FaceVertex> = vec![];
+                for vertex in values {
+                    let mut parts = vertex.split('/');
+                    let position = resolve_index(parts.next().unwrap(), positions.len()).map_err(&error)?;
+                    let uv = match parts.next() {
+                        Some(uv) if !uv.is_empty() => Some(resolve_index(uv, uvs.len()).map_err(&error)?),
+                        _ => None,
+                    };
+                    let normal = match parts.next() {
+                        Some(normal) if !normal.is_empty() => Some(resolve_index(normal, normals.len()).map_err(&error)?),
+                        _ => None,
+                    };
+                    vertices.push((position, uv, normal));
+                }
+
+                for i in 1..vertices.len() - 1 {
+                    let corners = [vertices[0], vertices[i], vertices[i + 1]];
+                    let all = |index: fn(&FaceVertex) -> Option<u32>| {
+                        match (index(&corners[0]), index(&corners[1]), index(&corners[2])) {
+                            (Some(a), Some(b), Some(c)) => Some([a, b, c]),
+                            _ => None,
+                        }
+                    };
+
+                    triangles.push(MeshTriangle {
+                        positions: [corners[0].0, corners[1].0, corners[2].0],
+                        uvs: all(|corner| corner.1),
+                        normals: all(|corner| corner.2),
+                        material: current_material,
+                    });
+                }
+            },
+            "mtllib" => {
+                for library in values {
+                    load_mtl(&directory.join(library), resources, &mut materials)?;
+                }
+            },
+            "usemtl" => {
+                let name = values.join(" ");
+                current_material = *materials.get(&name)
+                    .ok_or_else(|| error(format!("unknown material '{}'", name)))?;
+            },
+            _ => {},
+        }
+    }
+
+    TriangleMesh::new(positions, normals, uvs, triangles).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hitable::{HitRecord, Hitable};
+    use ray::Ray;
+    use scene::Entities;
+    use std::env::temp_dir;
+
+    // A unit square in z = 0 with the default material, then a pentagon in z = 1 given with negative indexes
+    const OBJ: &str = "mtllib test.mtl
+v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+f 1 2 3 4
+usemtl red
+v 0 0 1
+v 2 0 1
+v 2 1 1
+v 1 2 1
+v 0 1 1 # the top
+f -5 -4 -3 -2 -1
+";
+
+    const MTL: &str = "newmtl red
+Kd 0.8 0.1 0.1
+newmtl lamp
+Ke 4 4 4
+";
+
+    // Tests run in parallel so each one loads from its own directory
+    fn load(test: &str, obj: &str) -> Result<(TriangleMesh, Resources), String> {
+        let directory = temp_dir().join(format!("raytracinginaweekend-obj-test-{}-{}", std::process::id(), test));
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("test.mtl"), MTL).unwrap();
+        fs::write(directory.join("test.obj"), obj).unwrap();
+        let mut resources = Resources::new();
+        let default_material = resources.new_material(Material::Lambertian(Vec3::uniform(0.5)));
+        let mesh = load_obj(directory.join("test.obj"), &mut resources, default_material);
+        fs::remove_dir_all(&directory).unwrap();
+        mesh.map(|mesh| (mesh, resources))
+    }
+
+    fn hit(mesh: &TriangleMesh, origin: Vec3, direction: Vec3) -> Option<HitRecord> {
+        let mut hit_record = HitRecord::zero();
+        if mesh.hit_ptr(&Entities::new(), &Ray::new(origin, direction, 0.0), 0.001, f32::MAX, &mut hit_record) {
+            Some(hit_record)
+        } else {
+            None
+        }
+    }
+
+    #[test]
+    fn triangulates_polygons_and_resolves_negative_indexes() {
+        let (mesh, resources) = load("polygons", OBJ).unwrap();
+        assert_eq!(mesh.triangle_count(), 2 + 3);
+        assert_eq!(resources.materials.len(), 3);
+
+        let square = hit(&mesh, Vec3::new(0.25, 0.75, -1.0), Vec3::new(0.0, 0.0, 1.0)).unwrap();
+        assert!((square.t - 1.0).abs() < 1e-6);
+        assert_eq!(square.material, 0);
+
+        // Next to the edge from (0, 1) to (1, 2), which only the last triangle of the fan covers
+        let top = hit(&mesh, Vec3::new(0.5, 1.2, 2.0), Vec3::new(0.0, 0.0, -1.0)).unwrap();
+        assert!((top.p - Vec3::new(0.5, 1.2, 1.0)).length() < 1e-6);
+        assert!(matches!(resources.materials[top.material], Material::Lambertian(kd) if kd == Vec3::new(0.8, 0.1, 0.1)));
+        assert!(hit(&mesh, Vec3::new(1.9, 1.9, 2.0), Vec3::new(0.0, 0.0, -1.0)).is_none());
+    }
+
+    #[test]
+    fn rejects_faces_that_are_too_small_or_out_of_range() {
+        let error = |obj: &str| load("errors", obj).unwrap_err();
+        assert!(error("v 0 0 0\nv 1 0 0\nf 1 2\n").ends_with(":3: a face needs at least 3 vertices"));
+        assert!(error("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 0 1 2\n").ends_with(":4: index 0 is out of range"));
+        assert!(error("v 0 0 0\nv 1 0 0\nv 0 1 0\nf -4 -2 -1\n").ends_with(":4: index -4 is out of range"));
+        assert!(error("v 0 0 0\nv 1 0 0\nv 0 1 0\nusemtl blue\n").ends_with(":4: unknown material 'blue'"));
+    }
+}
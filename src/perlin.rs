@@ -1,6 +1,7 @@
-#![allow(dead_code, unused_variables)]
+#![allow(dead_code, unused_variables, clippy::needless_range_loop)]
 
-use random::drand48;
+use lazy_static::initialize;
+use random::{drand48, seed_stream, PERLIN_STREAM};
 use vec3::Vec3;
 
 //  TODO: Test this module. Eventually replace with SIMD Noise crate?
@@ -13,6 +14,17 @@ lazy_static! {
     static ref PERM_Z: [i32; 256] = perlin_generate_perm();
 }
 
+// The tables are otherwise built by whichever render thread first needs them, leaving them at the mercy of
+// scheduling, so build them up front from their own stream
+pub fn initialise() {
+    seed_stream(PERLIN_STREAM, 0);
+    initialize(&RAND_VEC3);
+    initialize(&RAND_FLOATS);
+    initialize(&PERM_X);
+    initialize(&PERM_Y);
+    initialize(&PERM_Z);
+}
+
 fn generate_vec3() -> [Vec3; 256] {
     let mut vecs = [Vec3::zero(); 256];
 
@@ -43,7 +55,7 @@ fn permute<T: Copy>(values: &mut [T; 256]) {
 
 fn perlin_generate_perm() -> [i32; 256] {
     let mut vals = [0; 256];
-    for (i, value) in vals.iter_mut().enumerate().take(256 as usize) {
+    for (i, value) in vals.iter_mut().enumerate().take(256) {
         *value = i as i32;
     }
 //    for i in 0..256 as usize {
@@ -75,9 +87,9 @@ fn trilinear_interp_vecs(c: [[[Vec3; 2]; 2]; 2], u: f32, v: f32, w: f32) -> f32
 
     let mut accum = 0.0;
 
-    for i in 0 as usize..2 {
-        for j in 0 as usize..2 {
-            for k in 0 as usize..2 {
+    for i in 0..2 {
+        for j in 0..2 {
+            for k in 0..2 {
                 let weight_v = Vec3::new(u - i as f32, v - j as f32, w - k as f32);
                 accum += (i as f32 * u + (1 - i) as f32 * (1.0 - u))
                     * (j as f32 * v + (1 - j) as f32 * (1.0 - v))
@@ -98,9 +110,9 @@ fn trilinear_interp(c: [[[f32; 2]; 2]; 2], u: f32, v: f32, w: f32) -> f32 {
 
     let mut accum = 0.0;
 
-    for i in 0 as usize..2 {
-        for j in 0 as usize..2 {
-            for k in 0 as usize..2 {
+    for i in 0..2 {
+        for j in 0..2 {
+            for k in 0..2 {
                 accum += (i as f32 * u + (1 - i) as f32 * (1.0 - u))
                     * (j as f32 * v + (1 - j) as f32 * (1.0 - v))
                     * (k as f32 * w + (1 - k) as f32 * (1.0 - w))
@@ -0,0 +1,321 @@
+// Principled BSDF, one material whose intuitive parameters cover most real world surfaces
+// Credit to Burley - Physically Based Shading at Disney - https://media.disneyanimation.com/uploads/production/publication_asset/48/asset/s2012_pbs_disney_brdf_notes_v3.pdf
+
+use hitable::HitRecord;
+use microfacet::{schlick, TrowbridgeReitz};
+use random::random_cosine_direction;
+use texture::{Texture, TextureRef};
+use tonemap::luminance;
+use vec3::Vec3;
+
+use std::f32::consts::PI;
+
+const CLEARCOAT_ROUGHNESS: f32 = 0.1;
+// Reflectance at normal incidence of the clearcoat, a layer with an index of refraction of 1.5
+const CLEARCOAT_F0: f32 = 0.04;
+
+// Either a constant or a texture looked up at the hit point. Scalar parameters use the red channel of textures, so a
+// greyscale map works as expected
+#[derive(Debug, Clone, Copy)]
+pub enum Parameter {
+    Constant(Vec3),
+    Texture(TextureRef),
+}
+
+impl Parameter {
+    pub fn scalar(value: f32) -> Parameter {
+        Parameter::Constant(Vec3::uniform(value))
+    }
+
+    fn colour(&self, textures: &[Texture], hit_record: &HitRecord) -> Vec3 {
+        match self {
+            Parameter::Constant(value) => *value,
+            Parameter::Texture(tex_ref) => textures[*tex_ref].value(textures, hit_record.u, hit_record.v, &hit_record.p),
+        }
+    }
+
+    fn value(&self, textures: &[Texture], hit_record: &HitRecord) -> f32 {
+        self.colour(textures, hit_record).r()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Principled {
+    pub base_colour: Parameter,
+    // 0 for dielectrics and 1 for metals, where the base colour is the colour of the reflection
+    pub metallic: Parameter,
+    pub roughness: Parameter,
+    // Strength of the specular reflection of dielectrics, 0.5 is a reflectance of 4% straight on
+    pub specular: Parameter,
+    // How much the specular reflection of dielectrics takes on the hue of the base colour
+    pub specular_tint: Parameter,
+    // Extra reflection at grazing angles, as seen on cloth
+    pub sheen: Parameter,
+    // Strength of a glossy clear layer on top, like varnish
+    pub clearcoat: Parameter,
+    // Fraction of the dielectric part that is transmitted like glass rather than scattered diffusely
+    pub transmission: Parameter,
+    pub ior: Parameter,
+}
+
+impl Default for Principled {
+    fn default() -> Principled {
+        Principled {
+            base_colour: Parameter::scalar(0.8),
+            metallic: Parameter::scalar(0.0),
+            roughness: Parameter::scalar(0.5),
+            specular: Parameter::scalar(0.5),
+            specular_tint: Parameter::scalar(0.0),
+            sheen: Parameter::scalar(0.0),
+            clearcoat: Parameter::scalar(0.0),
+            transmission: Parameter::scalar(0.0),
+            ior: Parameter::scalar(1.5),
+        }
+    }
+}
+
+impl Principled {
+    // The BSDF at hit_record with its parameters looked up. outside is whether the ray came from outside the surface
+    pub fn bsdf(&self, textures: &[Texture], hit_record: &HitRecord, outside: bool) -> PrincipledBsdf {
+        let unit = |parameter: &Parameter| parameter.value(textures, hit_record).clamp(0.0, 1.0);
+
+        let base_colour = self.base_colour.colour(textures, hit_record).max(&Vec3::zero());
+        let metallic = unit(&self.metallic);
+        let roughness = unit(&self.roughness);
+        let transmission = unit(&self.transmission);
+        let ior = self.ior.value(textures, hit_record).max(1.0);
+
+        let base_luminance = luminance(&base_colour);
+        let tint = if base_luminance > 0.0 { base_colour / base_luminance } else { Vec3::uniform(1.0) };
+        let dielectric_f0 = 0.08 * unit(&self.specular) * (Vec3::uniform(1.0) + unit(&self.specular_tint) * (tint - Vec3::uniform(1.0)));
+        let specular_f0 = dielectric_f0 + metallic * (base_colour - dielectric_f0);
+
+        PrincipledBsdf {
+            base_colour,
+            transmission_colour: Vec3::new(base_colour.r().sqrt(), base_colour.g().sqrt(), base_colour.b().sqrt()),
+            roughness,
+            sheen_colour: unit(&self.sheen) * (Vec3::uniform(0.5) + 0.5 * tint),
+            specular_f0,
+            diffuse_weight: (1.0 - metallic) * (1.0 - transmission),
+            // The transmission lobe has its own reflection so it replaces the specular lobe
+            specular_weight: 1.0 - (1.0 - metallic) * transmission,
+            transmission_weight: (1.0 - metallic) * transmission,
+            clearcoat_weight: 0.25 * unit(&self.clearcoat),
+            eta: if outside { ior } else { 1.0 / ior },
+            specular: TrowbridgeReitz::new(roughness),
+            clearcoat: TrowbridgeReitz::new(CLEARCOAT_ROUGHNESS),
+        }
+    }
+}
+
+// Directions are in a frame with +z along the normal on the side the ray came from
+#[derive(Debug)]
+pub struct PrincipledBsdf {
+    base_colour: Vec3,
+    transmission_colour: Vec3,
+    roughness: f32,
+    sheen_colour: Vec3,
+    specular_f0: Vec3,
+    diffuse_weight: f32,
+    specular_weight: f32,
+    transmission_weight: f32,
+    clearcoat_weight: f32,
+    // Index of refraction below the surface over the one above it
+    eta: f32,
+    specular: TrowbridgeReitz,
+    clearcoat: TrowbridgeReitz,
+}
+
+impl PrincipledBsdf {
+    // Chances of sampling the diffuse, specular, transmission and clearcoat lobes, roughly how much each reflects
+    fn lobe_probabilities(&self, wo: &Vec3) -> [f32; 4] {
+        let weights = [
+            self.diffuse_weight * luminance(&self.base_colour),
+            self.specular_weight * luminance(&schlick(wo.z(), &self.specular_f0)),
+            self.transmission_weight,
+            self.clearcoat_weight * schlick(wo.z(), &Vec3::uniform(CLEARCOAT_F0)).x(),
+        ];
+        let total: f32 = weights.iter().sum();
+        if total > 0.0 {
+            [weights[0] / total, weights[1] / total, weights[2] / total, weights[3] / total]
+        } else {
+            [0.0; 4]
+        }
+    }
+
+    // Picks a lobe with choice and then a direction from it with sample
+    pub fn sample(&self, wo: &Vec3, choice: f32, sample: [f32; 2]) -> Option<Vec3> {
+        if wo.z() <= 0.0 {
+            return None;
+        }
+
+        // Falls back to the last lobe that can be picked in case rounding leaves choice past the end
+        let mut picked = None;
+        let mut start = 0.0;
+        for (lobe, &probability) in self.lobe_probabilities(wo).iter().enumerate() {
+            if probability > 0.0 {
+                picked = Some((lobe, start, probability));
+                if choice < start + probability {
+                    break;
+                }
+            }
+            start += probability;
+        }
+        let (lobe, start, probability) = picked?;
+
+        match lobe {
+            0 => Some(random_cosine_direction(sample)),
+            1 => self.specular.sample_reflection(wo, sample),
+            // The dielectric chooses between reflection and refraction with what is left of choice
+            2 => self.specular.sample_dielectric(wo, self.eta, sample, ((choice - start) / probability).clamp(0.0, 1.0)),
+            _ => self.clearcoat.sample_reflection(wo, sample),
+        }
+    }
+
+    // BSDF times the cosine term and the pdf of sample returning wi
+    pub fn eval(&self, wo: &Vec3, wi: &Vec3) -> (Vec3, f32) {
+        if wo.z() <= 0.0 {
+            return (Vec3::zero(), 0.0);
+        }
+
+        let probabilities = self.lobe_probabilities(wo);
+        let mut bsdf = Vec3::zero();
+        let mut pdf = 0.0;
+
+        if self.transmission_weight > 0.0 {
+            let (transmission, transmission_pdf) = self.specular.dielectric(wo, wi, self.eta);
+            // Light passing in and back out of an object picks up the base colour
+            let colour = if wi.z() < 0.0 { self.transmission_colour } else { Vec3::uniform(1.0) };
+            bsdf += self.transmission_weight * transmission * colour;
+            pdf += probabilities[2] * transmission_pdf;
+        }
+
+        if wi.z() > 0.0 {
+            let h = (*wo + *wi).unit();
+            let cos_d = Vec3::dot(wi, &h);
+            let fresnel_weight = (1.0 - cos_d.clamp(0.0, 1.0)).powi(5);
+
+            // Diffuse with retro-reflection at grazing angles on rough surfaces, plus the sheen
+            let fd90 = 0.5 + 2.0 * self.roughness * cos_d * cos_d;
+            let fresnel_wo = 1.0 + (fd90 - 1.0) * (1.0 - wo.z()).powi(5);
+            let fresnel_wi = 1.0 + (fd90 - 1.0) * (1.0 - wi.z()).powi(5);
+            let diffuse = self.base_colour / PI * fresnel_wo * fresnel_wi + self.sheen_colour * fresnel_weight;
+            bsdf += self.diffuse_weight * diffuse * wi.z();
+            pdf += probabilities[0] * wi.z() / PI;
+
+            if let Some((h, reflection, reflection_pdf)) = self.specular.reflection(wo, wi) {
+                bsdf += self.specular_weight * reflection * schlick(Vec3::dot(wo, &h), &self.specular_f0);
+                pdf += probabilities[1] * reflection_pdf;
+            }
+
+            if let Some((h, reflection, reflection_pdf)) = self.clearcoat.reflection(wo, wi) {
+                let fresnel = schlick(Vec3::dot(wo, &h), &Vec3::uniform(CLEARCOAT_F0));
+                bsdf += self.clearcoat_weight * reflection * fresnel;
+                pdf += probabilities[3] * reflection_pdf;
+            }
+        }
+
+        (bsdf, pdf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sampler::{Sampler, SobolSampler};
+
+    const Z_BINS: usize = 16;
+    const PHI_BINS: usize = 8;
+
+    fn bsdf(principled: Principled) -> PrincipledBsdf {
+        principled.bsdf(&[], &HitRecord::zero(), true)
+    }
+
+    fn bin(wi: &Vec3) -> usize {
+        let z = ((wi.z() + 1.0) / 2.0 * Z_BINS as f32) as usize;
+        let phi = (wi.y().atan2(wi.x()) + PI) / (2.0 * PI) * PHI_BINS as f32;
+        z.min(Z_BINS - 1) * PHI_BINS + (phi as usize).min(PHI_BINS - 1)
+    }
+
+    // Integral of eval's pdf over each bin, which are even in solid angle
+    fn expected_histogram(bsdf: &PrincipledBsdf, wo: &Vec3) -> Vec<f32> {
+        let steps = 32;
+        let mut histogram = vec![0.0; Z_BINS * PHI_BINS];
+        for z_step in 0..Z_BINS * steps {
+            let z = -1.0 + 2.0 * (z_step as f32 + 0.5) / (Z_BINS * steps) as f32;
+            let radius = (1.0 - z * z).sqrt();
+            for phi_step in 0..PHI_BINS * steps {
+                let phi = -PI + 2.0 * PI * (phi_step as f32 + 0.5) / (PHI_BINS * steps) as f32;
+                let wi = Vec3::new(radius * phi.cos(), radius * phi.sin(), z);
+                histogram[bin(&wi)] += bsdf.eval(wo, &wi).1 * 4.0 * PI / (Z_BINS * PHI_BINS * steps * steps) as f32;
+            }
+        }
+        histogram
+    }
+
+    // Fraction of samples in each bin, with the samples that returned None in none of them
+    fn sampled_histogram(bsdf: &PrincipledBsdf, wo: &Vec3) -> Vec<f32> {
+        let samples = 1 << 16;
+        let mut histogram = vec![0.0; Z_BINS * PHI_BINS];
+        let mut sampler = SobolSampler::new(4);
+        for sample in 0..samples {
+            sampler.start_sample(0, sample);
+            let choice = sampler.get_1d();
+            if let Some(wi) = bsdf.sample(wo, choice, sampler.get_2d()) {
+                assert!(bsdf.eval(wo, &wi).1 > 0.0, "sampled {:?} which has no pdf", wi);
+                histogram[bin(&wi.unit())] += 1.0 / samples as f32;
+            }
+        }
+        histogram
+    }
+
+    #[test]
+    fn nothing_is_scattered_from_below_the_surface() {
+        let bsdf = bsdf(Principled { transmission: Parameter::scalar(0.5), ..Default::default() });
+        for wo in &[Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.6, 0.0, -0.8), Vec3::new(1.0, 0.0, 0.0)] {
+            assert!(bsdf.sample(wo, 0.5, [0.5, 0.5]).is_none());
+            let (value, pdf) = bsdf.eval(wo, &Vec3::new(0.0, 0.0, 1.0));
+            assert_eq!((value, pdf), (Vec3::zero(), 0.0));
+        }
+    }
+
+    #[test]
+    fn lobe_probabilities_add_up_to_one() {
+        let wo = Vec3::new(0.6, 0.0, 0.8);
+        let parameters = [
+            Principled::default(),
+            Principled { metallic: Parameter::scalar(1.0), ..Default::default() },
+            Principled { transmission: Parameter::scalar(0.7), clearcoat: Parameter::scalar(1.0), ..Default::default() },
+            Principled { base_colour: Parameter::scalar(0.0), ..Default::default() },
+        ];
+        for principled in parameters.iter().cloned() {
+            let total: f32 = bsdf(principled).lobe_probabilities(&wo).iter().sum();
+            assert!((total - 1.0).abs() < 1e-6, "probabilities add up to {}", total);
+        }
+
+        // Schlick's approximation only reflects nothing with no specular straight on
+        let black = bsdf(Principled { base_colour: Parameter::scalar(0.0), specular: Parameter::scalar(0.0), ..Default::default() });
+        let wo = Vec3::new(0.0, 0.0, 1.0);
+        assert_eq!(black.lobe_probabilities(&wo), [0.0; 4]);
+        assert!(black.sample(&wo, 0.5, [0.5, 0.5]).is_none());
+    }
+
+    #[test]
+    fn sampled_directions_follow_the_pdf() {
+        let wo = Vec3::new(0.6, 0.0, 0.8);
+        let parameters = [
+            Principled { metallic: Parameter::scalar(1.0), roughness: Parameter::scalar(0.4), ..Default::default() },
+            Principled { transmission: Parameter::scalar(1.0), roughness: Parameter::scalar(0.3), ..Default::default() },
+            Principled { clearcoat: Parameter::scalar(1.0), sheen: Parameter::scalar(1.0), ..Default::default() },
+        ];
+        for principled in parameters.iter().cloned() {
+            let bsdf = bsdf(principled);
+            let expected = expected_histogram(&bsdf, &wo);
+            let sampled = sampled_histogram(&bsdf, &wo);
+            for (bin, (&expected, &sampled)) in expected.iter().zip(&sampled).enumerate() {
+                assert!((expected - sampled).abs() < 0.004 + 0.05 * expected,
+                    "{:?} bin {} expected {} but sampled {}", bsdf, bin, expected, sampled);
+            }
+        }
+    }
+}
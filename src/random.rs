@@ -1,16 +1,55 @@
 //use super::rand::random;
-use std::cell::{RefCell, Cell};
-use time::precise_time_ns;
+use std::cell::Cell;
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicU64, Ordering};
+use vec3::Vec3;
 use xorshift::{Rng, SeedableRng};
 use xorshift::xoroshiro128::Xoroshiro128;
 
+// Streams that aren't tied to a pixel, pixel streams use the pixel index so these sit at the top of the range
+pub const SCENE_STREAM: u64 = u64::MAX;
+pub const PERLIN_STREAM: u64 = u64::MAX - 1;
+
+static SEED: AtomicU64 = AtomicU64::new(0);
+
 thread_local! {
     // TODO: Avoid using RefCell - slows down drand by about 2x
-    static XOR_SHIFT: Cell<Xoroshiro128> = {
-        let now = precise_time_ns();
-        let states = [now, now];
-        Cell::new(SeedableRng::from_seed(&states[..]))
-    };
+    static XOR_SHIFT: Cell<Xoroshiro128> = Cell::new(stream_rng(SEED.load(Ordering::Relaxed), 0, 0));
+}
+
+// Credit to Sebastiano Vigna - http://prng.di.unimi.it/splitmix64.c
+fn splitmix64(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+// Well mixed bits for a (seed, stream, index) triple
+pub fn hash(seed: u64, stream: u64, index: u64) -> u64 {
+    splitmix64(splitmix64(splitmix64(seed) ^ stream) ^ index)
+}
+
+fn stream_rng(seed: u64, stream: u64, index: u64) -> Xoroshiro128 {
+    let hash = hash(seed, stream, index);
+    // Xoroshiro128 must not be seeded with all zeros
+    let states = [hash, splitmix64(hash) | 1];
+    SeedableRng::from_seed(&states[..])
+}
+
+// Seed that every stream is derived from, must be set before seed_stream is called
+pub fn set_seed(seed: u64) {
+    SEED.store(seed, Ordering::Relaxed);
+}
+
+pub fn seed() -> u64 {
+    SEED.load(Ordering::Relaxed)
+}
+
+// Restarts this thread's generator at the sequence for (seed, stream, index), e.g. a pixel and a sample within it
+// so results don't depend on which thread does the work or what it did before
+pub fn seed_stream(stream: u64, index: u64) {
+    XOR_SHIFT.with(|rng| rng.set(stream_rng(SEED.load(Ordering::Relaxed), stream, index)));
 }
 
 pub fn drand48() -> f32 {
@@ -43,3 +82,56 @@ pub fn drand48_3() -> [f32; 3] {
 //        rng.borrow_mut().next_f32()
     })
 }
+
+// The functions below turn a 2D sample from a Sampler into the named distribution
+
+// Direction around +z with a pdf of cos(theta) / PI
+pub fn random_cosine_direction(sample: [f32; 2]) -> Vec3 {
+    let [r1, r2] = sample;
+    let phi = 2.0 * PI * r1;
+    let r = r2.sqrt();
+    Vec3::new(phi.cos() * r, phi.sin() * r, (1.0 - r2).sqrt())
+}
+
+// Direction around +z towards a sphere of the given radius whose center is distance_squared away
+// Uniform over the cone of directions that hit the sphere
+pub fn random_to_sphere(radius: f32, distance_squared: f32, sample: [f32; 2]) -> Vec3 {
+    let [r1, r2] = sample;
+    let z = 1.0 + r2 * ((1.0 - radius * radius / distance_squared).sqrt() - 1.0);
+    let phi = 2.0 * PI * r1;
+    let sin_theta = (1.0 - z * z).sqrt();
+    Vec3::new(phi.cos() * sin_theta, phi.sin() * sin_theta, z)
+}
+
+// Direction with a pdf of 1 / (4 * PI)
+pub fn random_sphere_direction(sample: [f32; 2]) -> Vec3 {
+    let [r1, r2] = sample;
+    let z = 1.0 - 2.0 * r2;
+    let phi = 2.0 * PI * r1;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    Vec3::new(phi.cos() * r, phi.sin() * r, z)
+}
+
+// Point in the unit disk in the xy plane, the concentric mapping keeps strata intact unlike rejection sampling
+// Credit to Shirley and Chiu - A Low Distortion Map Between Disk and Square
+pub fn random_in_unit_disk(sample: [f32; 2]) -> Vec3 {
+    let a = 2.0 * sample[0] - 1.0;
+    let b = 2.0 * sample[1] - 1.0;
+    if a == 0.0 && b == 0.0 {
+        return Vec3::zero();
+    }
+
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, PI / 4.0 * (b / a))
+    } else {
+        (b, PI / 2.0 - PI / 4.0 * (a / b))
+    };
+    Vec3::new(r * theta.cos(), r * theta.sin(), 0.0)
+}
+
+// Picks one of count choices with sample and rescales it back to [0, 1) so it can be reused
+pub fn pick(sample: f32, count: usize) -> (usize, f32) {
+    let scaled = sample * count as f32;
+    let index = (scaled as usize).min(count - 1);
+    (index, (scaled - index as f32).min(1.0 - f32::EPSILON / 2.0))
+}
@@ -0,0 +1,443 @@
+// Samplers hand out the random numbers for a path, one dimension at a time
+// Dimensions are requested in the same order for every sample so that low discrepancy samplers can spread each
+// dimension evenly over a pixel's samples
+
+use random::{drand48, drand48_2, hash};
+
+use std::fmt;
+use std::str::FromStr;
+
+// Pixel jitter, lens and time
+pub const CAMERA_DIMENSIONS: u32 = 5;
+// Picking a lobe and direction for the BSDF sample, then a light and a point on it
+pub const BOUNCE_DIMENSIONS: u32 = 6;
+pub const BSDF_DIMENSION: u32 = 0;
+pub const LIGHT_DIMENSION: u32 = 3;
+
+pub fn bounce_dimension(depth: u8) -> u32 {
+    CAMERA_DIMENSIONS + u32::from(depth) * BOUNCE_DIMENSIONS
+}
+
+pub trait Sampler {
+    // Starts sample number sample of pixel at dimension 0
+    fn start_sample(&mut self, pixel: usize, sample: u32);
+    // Skips to dimension so that each part of a path uses the same dimensions whatever came before it
+    fn set_dimension(&mut self, dimension: u32);
+    fn get_1d(&mut self) -> f32;
+    fn get_2d(&mut self) -> [f32; 2];
+}
+
+// Largest f32 below 1, as rounding a u32 up to f32 can otherwise produce 1.0
+const ONE_MINUS_EPSILON: f32 = 1.0 - f32::EPSILON / 2.0;
+
+#[inline]
+fn to_unit_float(value: u64) -> f32 {
+    ((value >> 40) as f32 * (1.0 / (1u64 << 24) as f32)).min(ONE_MINUS_EPSILON)
+}
+
+// Seed for scrambling one dimension of one pixel
+#[inline]
+fn dimension_seed(seed: u64, pixel: usize, dimension: u32) -> u64 {
+    hash(seed, pixel as u64, u64::from(dimension))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SamplerType {
+    Independent,
+    Stratified,
+    Halton,
+    Sobol,
+}
+
+impl SamplerType {
+    pub const NAMES: [&'static str; 4] = ["independent", "stratified", "halton", "sobol"];
+
+    pub fn create(self, seed: u64, samples_per_pixel: u32) -> Box<dyn Sampler> {
+        match self {
+            SamplerType::Independent => Box::new(IndependentSampler),
+            SamplerType::Stratified => Box::new(StratifiedSampler::new(seed, samples_per_pixel)),
+            SamplerType::Halton => Box::new(HaltonSampler::new(seed)),
+            SamplerType::Sobol => Box::new(SobolSampler::new(seed)),
+        }
+    }
+}
+
+impl FromStr for SamplerType {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<SamplerType, String> {
+        match name {
+            "independent" => Ok(SamplerType::Independent),
+            "stratified" => Ok(SamplerType::Stratified),
+            "halton" => Ok(SamplerType::Halton),
+            "sobol" => Ok(SamplerType::Sobol),
+            _ => Err(format!("unknown sampler '{}' (expected one of {})", name, SamplerType::NAMES.join(", "))),
+        }
+    }
+}
+
+impl fmt::Display for SamplerType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let index = match self {
+            SamplerType::Independent => 0,
+            SamplerType::Stratified => 1,
+            SamplerType::Halton => 2,
+            SamplerType::Sobol => 3,
+        };
+        write!(f, "{}", SamplerType::NAMES[index])
+    }
+}
+
+// Uniform random numbers from the thread's generator, which is seeded for each sample by the caller
+#[derive(Debug)]
+pub struct IndependentSampler;
+
+impl Sampler for IndependentSampler {
+    fn start_sample(&mut self, _pixel: usize, _sample: u32) {}
+
+    fn set_dimension(&mut self, _dimension: u32) {}
+
+    fn get_1d(&mut self) -> f32 {
+        drand48()
+    }
+
+    fn get_2d(&mut self) -> [f32; 2] {
+        drand48_2()
+    }
+}
+
+// Permutes i within 0..length differently for each pattern, credit to Kensler - Correlated Multi-Jittered Sampling
+fn permute(mut i: u32, length: u32, pattern: u32) -> u32 {
+    let mut w = length - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+
+    loop {
+        i ^= pattern;
+        i = i.wrapping_mul(0xe170_893d);
+        i ^= pattern >> 16;
+        i ^= (i & w) >> 4;
+        i ^= pattern >> 8;
+        i = i.wrapping_mul(0x0929_eb3f);
+        i ^= pattern >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | pattern >> 27);
+        i = i.wrapping_mul(0x6935_fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dc_b303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e50_1cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860_a3df);
+        i &= w;
+        i ^= i >> 5;
+
+        if i < length {
+            break;
+        }
+    }
+
+    (i.wrapping_add(pattern)) % length
+}
+
+// Jittered strata, each 1D dimension is split into one stratum per sample and each 2D dimension into a grid with at
+// least one cell per sample. The order strata are visited in is shuffled per pixel and dimension so that
+// dimensions aren't correlated with each other. Samples past samples_per_pixel fall back to uniform random numbers
+#[derive(Debug)]
+pub struct StratifiedSampler {
+    seed: u64,
+    samples_per_pixel: u32,
+    grid_size: u32,
+    pixel: usize,
+    sample: u32,
+    dimension: u32,
+}
+
+impl StratifiedSampler {
+    pub fn new(seed: u64, samples_per_pixel: u32) -> StratifiedSampler {
+        StratifiedSampler {
+            seed,
+            samples_per_pixel,
+            grid_size: (samples_per_pixel as f32).sqrt().ceil() as u32,
+            pixel: 0,
+            sample: 0,
+            dimension: 0,
+        }
+    }
+
+    fn jitter(&self, dimension: u32) -> f32 {
+        to_unit_float(hash(dimension_seed(self.seed, self.pixel, dimension), u64::from(self.sample), 0))
+    }
+
+    fn pattern(&self, dimension: u32) -> u32 {
+        dimension_seed(self.seed, self.pixel, dimension) as u32
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_sample(&mut self, pixel: usize, sample: u32) {
+        self.pixel = pixel;
+        self.sample = sample;
+        self.dimension = 0;
+    }
+
+    fn set_dimension(&mut self, dimension: u32) {
+        self.dimension = dimension;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let dimension = self.dimension;
+        self.dimension += 1;
+
+        if self.sample >= self.samples_per_pixel {
+            return self.jitter(dimension);
+        }
+
+        let stratum = permute(self.sample, self.samples_per_pixel, self.pattern(dimension));
+        ((stratum as f32 + self.jitter(dimension)) / self.samples_per_pixel as f32).min(ONE_MINUS_EPSILON)
+    }
+
+    fn get_2d(&mut self) -> [f32; 2] {
+        let dimension = self.dimension;
+        self.dimension += 2;
+
+        if self.sample >= self.samples_per_pixel {
+            return [self.jitter(dimension), self.jitter(dimension + 1)];
+        }
+
+        let cell = permute(self.sample, self.grid_size * self.grid_size, self.pattern(dimension));
+        let size = self.grid_size as f32;
+        [
+            (((cell % self.grid_size) as f32 + self.jitter(dimension)) / size).min(ONE_MINUS_EPSILON),
+            (((cell / self.grid_size) as f32 + self.jitter(dimension + 1)) / size).min(ONE_MINUS_EPSILON),
+        ]
+    }
+}
+
+const PRIMES: [u32; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53,
+    59, 61, 67, 71, 73, 79, 83, 89, 97, 101, 103, 107, 109, 113, 127, 131,
+    137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193, 197, 199, 211, 223,
+    227, 229, 233, 239, 241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307, 311,
+];
+
+fn radical_inverse(base: u32, mut index: u32) -> f32 {
+    let inverse_base = 1.0 / base as f64;
+    let mut reversed = 0u64;
+    let mut inverse_base_n = 1.0;
+    while index > 0 {
+        let next = index / base;
+        reversed = reversed * u64::from(base) + u64::from(index - next * base);
+        inverse_base_n *= inverse_base;
+        index = next;
+    }
+    ((reversed as f64 * inverse_base_n) as f32).min(ONE_MINUS_EPSILON)
+}
+
+// Halton sequence with a prime base per dimension. Every pixel uses the same points, so each dimension is shifted by a
+// random offset per pixel (Cranley-Patterson rotation) to stop neighbouring pixels having the same error.
+// Dimensions past the table of primes are uniform random numbers
+#[derive(Debug)]
+pub struct HaltonSampler {
+    seed: u64,
+    pixel: usize,
+    sample: u32,
+    dimension: u32,
+}
+
+impl HaltonSampler {
+    pub fn new(seed: u64) -> HaltonSampler {
+        HaltonSampler { seed, pixel: 0, sample: 0, dimension: 0 }
+    }
+
+    fn sample_dimension(&self, dimension: u32) -> f32 {
+        let offset = to_unit_float(dimension_seed(self.seed, self.pixel, dimension));
+        match PRIMES.get(dimension as usize) {
+            Some(&base) => {
+                let value = radical_inverse(base, self.sample) + offset;
+                (if value >= 1.0 { value - 1.0 } else { value }).min(ONE_MINUS_EPSILON)
+            },
+            None => to_unit_float(hash(dimension_seed(self.seed, self.pixel, dimension), u64::from(self.sample), 0)),
+        }
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start_sample(&mut self, pixel: usize, sample: u32) {
+        self.pixel = pixel;
+        self.sample = sample;
+        self.dimension = 0;
+    }
+
+    fn set_dimension(&mut self, dimension: u32) {
+        self.dimension = dimension;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let value = self.sample_dimension(self.dimension);
+        self.dimension += 1;
+        value
+    }
+
+    fn get_2d(&mut self) -> [f32; 2] {
+        let value = [self.sample_dimension(self.dimension), self.sample_dimension(self.dimension + 1)];
+        self.dimension += 2;
+        value
+    }
+}
+
+// First two dimensions of the Sobol sequence, which together form a (0, 2)-sequence
+fn sobol_2d(index: u32) -> [u32; 2] {
+    let mut x = 0;
+    let mut y = 0;
+    let mut v = 1 << 31;
+    let mut index = index.reverse_bits();
+    // Both dimensions are built from the highest bit down, v walks the direction numbers of the second dimension
+    for bit in 0..32 {
+        if index & (1 << 31) != 0 {
+            x ^= 1 << (31 - bit);
+            y ^= v;
+        }
+        index <<= 1;
+        v ^= v >> 1;
+    }
+    [x, y]
+}
+
+// Credit to Burley - Practical Hash-based Owen Scrambling - http://www.jcgt.org/published/0009/04/01/
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50_b47c);
+    x ^= x.wrapping_mul(0xb82f_1e52);
+    x ^= x.wrapping_mul(0xc7af_e638);
+    x ^= x.wrapping_mul(0x8d22_f6e6);
+    x
+}
+
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+// Owen scrambled Sobol points. Each 1D or 2D request is its own (0, 2)-sequence with its own scrambling and a shuffled
+// sample order, which keeps the dimensions of a path from being correlated with each other
+#[derive(Debug)]
+pub struct SobolSampler {
+    seed: u64,
+    pixel: usize,
+    sample: u32,
+    dimension: u32,
+}
+
+impl SobolSampler {
+    pub fn new(seed: u64) -> SobolSampler {
+        SobolSampler { seed, pixel: 0, sample: 0, dimension: 0 }
+    }
+
+    fn sample_pattern(&mut self, dimensions: u32) -> [f32; 2] {
+        let seed = dimension_seed(self.seed, self.pixel, self.dimension);
+        self.dimension += dimensions;
+
+        let index = nested_uniform_scramble(self.sample, seed as u32);
+        let [x, y] = sobol_2d(index);
+        let x = nested_uniform_scramble(x, (seed >> 32) as u32);
+        let y = nested_uniform_scramble(y, hash(seed, 0, 0) as u32);
+        [
+            to_unit_float(u64::from(x) << 32),
+            to_unit_float(u64::from(y) << 32),
+        ]
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn start_sample(&mut self, pixel: usize, sample: u32) {
+        self.pixel = pixel;
+        self.sample = sample;
+        self.dimension = 0;
+    }
+
+    fn set_dimension(&mut self, dimension: u32) {
+        self.dimension = dimension;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        self.sample_pattern(1)[0]
+    }
+
+    fn get_2d(&mut self) -> [f32; 2] {
+        self.sample_pattern(2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLERS: [SamplerType; 4] =
+        [SamplerType::Independent, SamplerType::Stratified, SamplerType::Halton, SamplerType::Sobol];
+
+    // Every dimension of every sample of a few pixels, 1D and 2D requests alternating
+    fn sequence(sampler_type: SamplerType, seed: u64, samples_per_pixel: u32) -> Vec<f32> {
+        let mut sampler = sampler_type.create(seed, samples_per_pixel);
+        let mut values = vec![];
+        for pixel in 0..4 {
+            // Past the end of the stratified sampler's strata as well
+            for sample in 0..samples_per_pixel + 2 {
+                sampler.start_sample(pixel, sample);
+                // 75 dimensions, past the end of the Halton sampler's primes as well
+                for _ in 0..25 {
+                    values.push(sampler.get_1d());
+                    values.extend_from_slice(&sampler.get_2d());
+                }
+            }
+        }
+        values
+    }
+
+    #[test]
+    fn samples_are_in_the_unit_interval() {
+        for &sampler_type in SAMPLERS.iter() {
+            for &samples_per_pixel in &[1, 10, 16] {
+                for value in sequence(sampler_type, 3, samples_per_pixel) {
+                    assert!((0.0..1.0).contains(&value), "{} sampler gave {}", sampler_type, value);
+                }
+            }
+        }
+    }
+
+    // The independent sampler draws from the thread's generator so it is repeatable only as far as the caller
+    // reseeds that for each sample
+    #[test]
+    fn the_same_seed_gives_the_same_samples() {
+        for &sampler_type in SAMPLERS[1..].iter() {
+            let first = sequence(sampler_type, 3, 10);
+            assert_eq!(first, sequence(sampler_type, 3, 10), "{} sampler", sampler_type);
+            assert_ne!(first, sequence(sampler_type, 4, 10), "{} sampler", sampler_type);
+        }
+    }
+
+    #[test]
+    fn stratified_samples_fall_in_each_stratum_once() {
+        for &samples_per_pixel in &[10, 16] {
+            let mut sampler = StratifiedSampler::new(3, samples_per_pixel);
+            let grid_size = sampler.grid_size;
+            for pixel in 0..4 {
+                let mut strata = vec![0; samples_per_pixel as usize];
+                let mut cells = vec![0; (grid_size * grid_size) as usize];
+                for sample in 0..samples_per_pixel {
+                    sampler.start_sample(pixel, sample);
+                    sampler.set_dimension(7);
+                    strata[(sampler.get_1d() * samples_per_pixel as f32) as usize] += 1;
+                    let [u, v] = sampler.get_2d();
+                    let cell = (v * grid_size as f32) as u32 * grid_size + (u * grid_size as f32) as u32;
+                    cells[cell as usize] += 1;
+                }
+
+                assert!(strata.iter().all(|&count| count == 1), "{} samples gave strata {:?}", samples_per_pixel, strata);
+                assert!(cells.iter().all(|&count| count <= 1), "{} samples gave cells {:?}", samples_per_pixel, cells);
+            }
+        }
+    }
+}
@@ -1,17 +1,25 @@
+use background::Background;
 use camera::Camera;
 use hitable::Hitable;
 use material::Material;
 use texture::Texture;
 
+use std::mem;
+
 // TODO: These world and material collections should be more generic (a slice) to allow for array usage instead of always Vec
 // TODO: Should also make them use a series of typed arrays/vecs instead - e.g. Map<T, [T]>
 pub struct Scene {
-    pub resources: Resources
+    pub resources: Resources,
+    pub background: Background,
 }
 
 impl Scene {
     pub fn new(resources: Resources) -> Scene {
-        Scene { resources }
+        Scene::with_background(resources, Background::default())
+    }
+
+    pub fn with_background(resources: Resources, background: Background) -> Scene {
+        Scene { resources, background }
     }
 }
 
@@ -25,6 +33,8 @@ pub struct Window {
     pub height: u32,
     pub samples: u32,
     pub camera: Camera,
+    // Empty unless the scene is animated, in which case each frame is rendered in turn with its own camera
+    pub frames: Vec<Frame>,
 }
 
 impl Window {
@@ -34,51 +44,92 @@ impl Window {
             height,
             samples,
             camera,
+            frames: vec![],
         }
     }
+
+    pub fn animated(width: u32, height: u32, samples: u32, camera: Camera, frames: Vec<Frame>) -> Window {
+        Window { frames, ..Window::new(width, height, samples, camera) }
+    }
+}
+
+// One numbered frame of an animation, with the camera's shutter open over the time the frame covers
+#[derive(Debug)]
+pub struct Frame {
+    pub number: u32,
+    pub camera: Camera,
 }
 
 unsafe impl Send for Window {}
 unsafe impl Sync for Window {}
 
-pub type EntityRef = usize;
 pub type HitableRef = usize;
 pub type MaterialRef = usize;
 pub type TextureRef = usize;
+pub type GeometryRef = usize;
 
 #[derive(Debug)]
 pub struct Entity {
     pub hitable_id: HitableRef,
-    ptr: Box<Hitable>
+    ptr: Box<dyn Hitable>
 }
 
 #[derive(Debug)]
 pub struct Entities {
     pub entities: Vec<Entity>,
+    // Shared by instances rather than being in the world itself
+    geometry: Vec<Box<dyn Hitable>>,
 }
 
 impl Entities {
     pub fn new() -> Entities {
         Entities {
-            entities: vec![]
+            entities: vec![],
+            geometry: vec![],
         }
     }
 
     pub fn new_entity<T: 'static + Hitable>(&mut self, hitable: T) -> HitableRef {
+        self.new_boxed_entity(Box::new(hitable))
+    }
+
+    pub fn new_boxed_entity(&mut self, hitable: Box<dyn Hitable>) -> HitableRef {
         let id = self.entities.len();
-        self.entities.push(Entity { hitable_id: id, ptr: Box::new(hitable) });
+        self.entities.push(Entity { hitable_id: id, ptr: hitable });
         id
     }
 
-    pub fn get_entity(&self, id: EntityRef) -> &Entity {
-        &self.entities[id]
+    pub fn new_geometry<T: 'static + Hitable>(&mut self, hitable: T) -> GeometryRef {
+        self.new_boxed_geometry(Box::new(hitable))
+    }
+
+    pub fn new_boxed_geometry(&mut self, hitable: Box<dyn Hitable>) -> GeometryRef {
+        self.geometry.push(hitable);
+        self.geometry.len() - 1
+    }
+
+    pub fn get_geometry(&self, id: GeometryRef) -> &dyn Hitable {
+        &*self.geometry[id]
     }
 
-    pub fn get_hitable(&self, id: HitableRef) -> &Box<Hitable> {
-        let entity = self.entities.iter()
-            .find(|&entity| entity.hitable_id == id)
-            .unwrap();
-        &entity.ptr
+    // Lets hitables rebuild anything bounded over the shutter before rendering with it. Geometry can be made of
+    // instances of geometry added before it, so each piece is updated with only the pieces before it in place
+    pub fn set_shutter(&mut self, time0: f32, time1: f32) {
+        for mut hitable in mem::take(&mut self.geometry) {
+            hitable.set_shutter(self, time0, time1);
+            self.geometry.push(hitable);
+        }
+
+        let mut entities = mem::take(&mut self.entities);
+        for entity in &mut entities {
+            entity.ptr.set_shutter(self, time0, time1);
+        }
+        self.entities = entities;
+    }
+
+    // Entities are stored at the index of their id, so the top level of the BVH can find them without a search
+    pub fn get_hitable(&self, id: HitableRef) -> &dyn Hitable {
+        &*self.entities[id].ptr
     }
 
     pub fn len(&self) -> usize {
@@ -111,6 +162,18 @@ impl Resources {
         self.entities.new_entity(hitable)
     }
 
+    pub fn new_boxed_entity(&mut self, hitable: Box<dyn Hitable>) -> HitableRef {
+        self.entities.new_boxed_entity(hitable)
+    }
+
+    pub fn new_geometry<T: 'static + Hitable>(&mut self, hitable: T) -> GeometryRef {
+        self.entities.new_geometry(hitable)
+    }
+
+    pub fn new_boxed_geometry(&mut self, hitable: Box<dyn Hitable>) -> GeometryRef {
+        self.entities.new_boxed_geometry(hitable)
+    }
+
     pub fn new_material(&mut self, material: Material) -> MaterialRef {
         // TODO: assert that textures exist
         self.materials.push(material);
@@ -122,15 +185,7 @@ impl Resources {
         self.textures.len() - 1
     }
 
-    pub fn get_entity(&self, id: HitableRef) -> &Box<Hitable> {
-        self.entities.get_hitable(id)
-    }
-
     pub fn get_material(&self, id: MaterialRef) -> &Material {
         &self.materials[id]
     }
-
-    pub fn get_texture(&self, id: TextureRef) -> &Texture {
-        &self.textures[id]
-    }
 }
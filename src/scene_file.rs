@@ -0,0 +1,1355 @@
+// Loader for text based scene descriptions.
+//
+// A scene file is a list of statements. `#` starts a comment that runs to the end of the line.
+//
+//   texture <name> = <texture>
+//   material <name> = <material>
+//   hitable <name> = <hitable>     (named geometry that is only added to the world when used by an entity, or
+//                                  shared by every instance(name, ...) of it)
+//   entity <hitable>
+//   camera <camera>
+//   background <background>        (optional, black if not given)
+//   animation frames(first, last, shutter)
+//                                  (optional, renders a numbered image of each frame from first to last rather than a
+//                                  single image. Time is measured in frames and each frame's shutter is open from its
+//                                  number for shutter frames, 0.5 by default)
+//
+// Values are numbers, "strings", vectors written as [x y z], names of earlier definitions or
+// constructor calls such as sphere([0 1 0], 0.5, glass). Constructor arguments can be given in order
+// or by name (e.g. perspective(lookfrom = [13 2 3], vfov = 20)). Anywhere a texture or material is
+// expected an inline constructor can be used instead of a name, and a single number can be used
+// anywhere a vector is expected. In an animation any number or vector of the camera can be a
+// track(key(frame, value), key(frame, value), ...), which is interpolated between the keys for each frame.
+//
+// See the scenes directory for an example of each of the built-in scenes.
+
+use aarect::{XYRect, XZRect, YZRect};
+use background::{Background, EnvironmentMap};
+use bvh::BvhStats;
+use camera::Camera;
+use cube::Cube;
+use detail::SurfaceDetail;
+use group::Group;
+use hitable::Hitable;
+use instance::Instance;
+use material::{Material, CONDUCTORS};
+use mesh::Triangle;
+use obj::load_obj;
+use principled::{Parameter, Principled};
+use scene::{Frame, GeometryRef, MaterialRef, Resources, Scene, TextureRef, Window};
+use sky::Sky;
+use sphere::{MovingSphere, Sphere};
+use texture::Texture;
+use transform::{Affine, AnimatedTransform, FlipNormals, Keyframe, RotateY, Transform, Translate};
+use vec3::Vec3;
+use volume::ConstantMedium;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::ops::{Add, Mul, Sub};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug)]
+pub struct SceneFileError {
+    pub position: Position,
+    pub message: String,
+}
+
+impl SceneFileError {
+    fn new<S: Into<String>>(position: Position, message: S) -> SceneFileError {
+        SceneFileError {
+            position,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for SceneFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.position.line, self.position.column, self.message)
+    }
+}
+
+type ParseResult<T> = Result<T, SceneFileError>;
+
+#[derive(Debug)]
+pub struct MeshStats {
+    pub path: PathBuf,
+    pub triangles: usize,
+    pub bvh: BvhStats,
+}
+
+#[derive(Debug)]
+pub struct GroupStats {
+    pub hitables: usize,
+    pub bvh: BvhStats,
+}
+
+// What was built while loading the scene, for the caller to report
+#[derive(Debug, Default)]
+pub struct SceneStats {
+    pub meshes: Vec<MeshStats>,
+    pub groups: Vec<GroupStats>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    Ident(String),
+    Number(f32),
+    Str(String),
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    Comma,
+    Equals,
+    Eof,
+}
+
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TokenKind::Ident(name) => write!(f, "'{}'", name),
+            TokenKind::Number(number) => write!(f, "number {}", number),
+            TokenKind::Str(string) => write!(f, "string \"{}\"", string),
+            TokenKind::LParen => write!(f, "'('"),
+            TokenKind::RParen => write!(f, "')'"),
+            TokenKind::LBracket => write!(f, "'['"),
+            TokenKind::RBracket => write!(f, "']'"),
+            TokenKind::Comma => write!(f, "','"),
+            TokenKind::Equals => write!(f, "'='"),
+            TokenKind::Eof => write!(f, "end of file"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    position: Position,
+}
+
+fn tokenize(source: &str) -> ParseResult<Vec<Token>> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    let mut line = 1;
+    let mut column = 1;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let position = Position { line, column };
+
+        if c == '\n' {
+            i += 1;
+            line += 1;
+            column = 1;
+            continue;
+        }
+
+        if c.is_whitespace() {
+            i += 1;
+            column += 1;
+            continue;
+        }
+
+        if c == '#' {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+            continue;
+        }
+
+        let start = i;
+        let kind = match c {
+            '(' => { i += 1; TokenKind::LParen },
+            ')' => { i += 1; TokenKind::RParen },
+            '[' => { i += 1; TokenKind::LBracket },
+            ']' => { i += 1; TokenKind::RBracket },
+            ',' => { i += 1; TokenKind::Comma },
+            '=' => { i += 1; TokenKind::Equals },
+            '"' => {
+                i += 1;
+                while i < chars.len() && chars[i] != '"' && chars[i] != '\n' {
+                    i += 1;
+                }
+                if i >= chars.len() || chars[i] != '"' {
+                    return Err(SceneFileError::new(position, "unterminated string"));
+                }
+                i += 1;
+                TokenKind::Str(chars[start + 1..i - 1].iter().collect())
+            },
+            c if c.is_ascii_digit() || c == '-' || c == '+' || c == '.' => {
+                i += 1;
+                while i < chars.len() {
+                    let n = chars[i];
+                    let is_exponent_sign = (n == '-' || n == '+') && (chars[i - 1] == 'e' || chars[i - 1] == 'E');
+                    if n.is_ascii_digit() || n == '.' || n == 'e' || n == 'E' || is_exponent_sign {
+                        i += 1;
+                    } else {
+                        break;
+                    }
+                }
+                let text: String = chars[start..i].iter().collect();
+                match text.parse::<f32>() {
+                    Ok(number) => TokenKind::Number(number),
+                    Err(_) => return Err(SceneFileError::new(position, format!("invalid number '{}'", text))),
+                }
+            },
+            c if c.is_alphabetic() || c == '_' => {
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                TokenKind::Ident(chars[start..i].iter().collect())
+            },
+            c => return Err(SceneFileError::new(position, format!("unexpected character '{}'", c))),
+        };
+
+        column += i - start;
+        tokens.push(Token { kind, position });
+    }
+
+    tokens.push(Token {
+        kind: TokenKind::Eof,
+        position: Position { line, column },
+    });
+
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    Number(f32),
+    Vector(Vec3),
+    Str(String),
+    Name(String),
+    Call(String, Vec<Arg>),
+}
+
+#[derive(Debug, Clone)]
+struct Expr {
+    value: Value,
+    position: Position,
+}
+
+impl Expr {
+    fn describe(&self) -> String {
+        match &self.value {
+            Value::Number(_) => "a number".to_owned(),
+            Value::Vector(_) => "a vector".to_owned(),
+            Value::Str(_) => "a string".to_owned(),
+            Value::Name(name) => format!("'{}'", name),
+            Value::Call(name, _) => format!("{}(...)", name),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Arg {
+    name: Option<(String, Position)>,
+    expr: Expr,
+}
+
+#[derive(Debug)]
+enum Statement {
+    Texture(String, Expr),
+    Material(String, Expr),
+    Hitable(String, Expr),
+    Entity(Expr),
+    Camera(Expr),
+    Background(Expr),
+    Animation(Expr),
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    index: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.index]
+    }
+
+    fn peek_next(&self) -> &Token {
+        let index = (self.index + 1).min(self.tokens.len() - 1);
+        &self.tokens[index]
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.index].clone();
+        if token.kind != TokenKind::Eof {
+            self.index += 1;
+        }
+        token
+    }
+
+    fn expect(&mut self, kind: TokenKind) -> ParseResult<Token> {
+        let token = self.next();
+        if token.kind == kind {
+            Ok(token)
+        } else {
+            Err(SceneFileError::new(token.position, format!("expected {} but found {}", kind, token.kind)))
+        }
+    }
+
+    fn ident(&mut self, what: &str) -> ParseResult<(String, Position)> {
+        let token = self.next();
+        match token.kind {
+            TokenKind::Ident(name) => Ok((name, token.position)),
+            kind => Err(SceneFileError::new(token.position, format!("expected {} but found {}", what, kind))),
+        }
+    }
+
+    fn statements(&mut self) -> ParseResult<Vec<Statement>> {
+        let mut statements = vec![];
+
+        while self.peek().kind != TokenKind::Eof {
+            let (keyword, position) = self.ident(
+                "'texture', 'material', 'hitable', 'entity', 'camera', 'background' or 'animation'")?;
+            let statement = match keyword.as_str() {
+                "texture" | "material" | "hitable" => {
+                    let (name, _) = self.ident(&format!("a {} name", keyword))?;
+                    self.expect(TokenKind::Equals)?;
+                    let expr = self.expr()?;
+                    match keyword.as_str() {
+                        "texture" => Statement::Texture(name, expr),
+                        "material" => Statement::Material(name, expr),
+                        _ => Statement::Hitable(name, expr),
+                    }
+                },
+                "entity" => Statement::Entity(self.expr()?),
+                "camera" => Statement::Camera(self.expr()?),
+                "background" => Statement::Background(self.expr()?),
+                "animation" => Statement::Animation(self.expr()?),
+                _ => return Err(SceneFileError::new(position, format!(
+                    "expected 'texture', 'material', 'hitable', 'entity', 'camera', 'background' or 'animation' but found '{}'",
+                    keyword))),
+            };
+            statements.push(statement);
+        }
+
+        Ok(statements)
+    }
+
+    fn expr(&mut self) -> ParseResult<Expr> {
+        let token = self.next();
+        let position = token.position;
+
+        let value = match token.kind {
+            TokenKind::Number(number) => Value::Number(number),
+            TokenKind::Str(string) => Value::Str(string),
+            TokenKind::LBracket => {
+                let mut components = [0.0; 3];
+                for component in components.iter_mut() {
+                    let token = self.next();
+                    match token.kind {
+                        TokenKind::Number(number) => *component = number,
+                        kind => return Err(SceneFileError::new(token.position, format!(
+                            "expected a number in vector but found {}", kind))),
+                    }
+                }
+                self.expect(TokenKind::RBracket)?;
+                Value::Vector(Vec3::new(components[0], components[1], components[2]))
+            },
+            TokenKind::Ident(name) => {
+                if self.peek().kind == TokenKind::LParen {
+                    self.next();
+                    Value::Call(name, self.args()?)
+                } else {
+                    Value::Name(name)
+                }
+            },
+            kind => return Err(SceneFileError::new(position, format!("expected a value but found {}", kind))),
+        };
+
+        Ok(Expr { value, position })
+    }
+
+    fn args(&mut self) -> ParseResult<Vec<Arg>> {
+        let mut args = vec![];
+
+        if self.peek().kind == TokenKind::RParen {
+            self.next();
+            return Ok(args);
+        }
+
+        loop {
+            let name = match (&self.peek().kind, &self.peek_next().kind) {
+                (TokenKind::Ident(_), TokenKind::Equals) => {
+                    let name = self.ident("an argument name")?;
+                    self.next();
+                    Some(name)
+                },
+                _ => None,
+            };
+            args.push(Arg { name, expr: self.expr()? });
+
+            let token = self.next();
+            match token.kind {
+                TokenKind::Comma => continue,
+                TokenKind::RParen => return Ok(args),
+                kind => return Err(SceneFileError::new(token.position, format!("expected ',' or ')' but found {}", kind))),
+            }
+        }
+    }
+}
+
+// Arguments of a constructor call matched up against the constructor's parameter names
+struct Args<'a> {
+    call: &'a str,
+    position: Position,
+    params: &'static [&'static str],
+    values: Vec<Option<&'a Expr>>,
+    // Frame that tracks are interpolated at, None where tracks can't be used
+    frame: Option<f32>,
+}
+
+impl<'a> Args<'a> {
+    fn bind(call: &'a str, position: Position, args: &'a [Arg], params: &'static [&'static str]) -> ParseResult<Args<'a>> {
+        let mut values = vec![None; params.len()];
+        let mut next_positional = 0;
+
+        for arg in args {
+            let index = match &arg.name {
+                Some((name, name_position)) => match params.iter().position(|param| param == name) {
+                    Some(index) => index,
+                    None => return Err(SceneFileError::new(*name_position, format!(
+                        "{} has no parameter '{}' (expected one of: {})", call, name, params.join(", ")))),
+                },
+                None => {
+                    if next_positional >= params.len() {
+                        return Err(SceneFileError::new(arg.expr.position, format!(
+                            "too many arguments for {} (expected {})", call, params.join(", "))));
+                    }
+                    next_positional += 1;
+                    next_positional - 1
+                },
+            };
+
+            if values[index].is_some() {
+                return Err(SceneFileError::new(arg.expr.position, format!(
+                    "{} was given more than once in {}", params[index], call)));
+            }
+            values[index] = Some(&arg.expr);
+        }
+
+        Ok(Args { call, position, params, values, frame: None })
+    }
+
+    fn at_frame(self, frame: Option<f32>) -> Args<'a> {
+        Args { frame, ..self }
+    }
+
+    fn get(&self, param: &str) -> Option<&'a Expr> {
+        let index = self.params.iter().position(|&p| p == param).expect("unknown parameter");
+        self.values[index]
+    }
+
+    fn required(&self, param: &str) -> ParseResult<&'a Expr> {
+        self.get(param).ok_or_else(|| SceneFileError::new(self.position, format!(
+            "missing argument '{}' for {}", param, self.call)))
+    }
+
+    fn number(&self, param: &str) -> ParseResult<f32> {
+        self.tracked(self.required(param)?, expect_number)
+    }
+
+    fn number_or(&self, param: &str, default: f32) -> ParseResult<f32> {
+        self.get(param).map_or(Ok(default), |expr| self.tracked(expr, expect_number))
+    }
+
+    fn vector(&self, param: &str) -> ParseResult<Vec3> {
+        self.tracked(self.required(param)?, expect_vector)
+    }
+
+    fn vector_or(&self, param: &str, default: Vec3) -> ParseResult<Vec3> {
+        self.get(param).map_or(Ok(default), |expr| self.tracked(expr, expect_vector))
+    }
+
+    // The value of expr, or if it is a track the value interpolated between its keys at the frame. Before the first
+    // key and after the last the track holds its value
+    fn tracked<T>(&self, expr: &Expr, parse: fn(&Expr) -> ParseResult<T>) -> ParseResult<T>
+        where T: Copy + Add<Output = T> + Sub<Output = T>, f32: Mul<T, Output = T> {
+        let args = match &expr.value {
+            Value::Call(name, args) if name == "track" => args,
+            _ => return parse(expr),
+        };
+        let frame = self.frame.ok_or_else(|| SceneFileError::new(
+            expr.position, "tracks can only be used by the camera of an animated scene"))?;
+
+        let mut keys = Vec::with_capacity(args.len());
+        for arg in args {
+            if let Some((_, name_position)) = &arg.name {
+                return Err(SceneFileError::new(*name_position, "the keys of a track can't be named"));
+            }
+            let (name, key_args) = expect_call(&arg.expr, "key")?;
+            if name != "key" {
+                return Err(SceneFileError::new(arg.expr.position, format!(
+                    "expected a key but found {}", arg.expr.describe())));
+            }
+            let key = Args::bind(name, arg.expr.position, key_args, &["frame", "value"])?;
+            keys.push((key.number("frame")?, parse(key.required("value")?)?));
+        }
+        if keys.is_empty() {
+            return Err(SceneFileError::new(expr.position, "a track needs at least one key"));
+        }
+        keys.sort_by(|a, b| a.0.total_cmp(&b.0));
+        if let Some(pair) = keys.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+            return Err(SceneFileError::new(expr.position, format!(
+                "the track has more than one key at frame {}", pair[0].0)));
+        }
+
+        Ok(match keys.iter().position(|key| key.0 > frame) {
+            Some(0) => keys[0].1,
+            Some(index) => {
+                let ((frame0, value0), (frame1, value1)) = (keys[index - 1], keys[index]);
+                value0 + (frame - frame0) / (frame1 - frame0) * (value1 - value0)
+            },
+            None => keys[keys.len() - 1].1,
+        })
+    }
+
+    fn string(&self, param: &str) -> ParseResult<&'a str> {
+        let expr = self.required(param)?;
+        match &expr.value {
+            Value::Str(string) => Ok(string),
+            _ => Err(SceneFileError::new(expr.position, format!("expected a string but found {}", expr.describe()))),
+        }
+    }
+}
+
+// The transformation given by the scale, rotate_x, rotate_y, rotate_z, axis, angle, lookfrom, lookat, vup and translate
+// arguments of args. They are applied in that order, with lookfrom and lookat placing the hitable at lookfrom facing
+// lookat
+fn affine(args: &Args) -> ParseResult<Affine> {
+    let scale = args.vector_or("scale", Vec3::uniform(1.0))?;
+    if scale.x() == 0.0 || scale.y() == 0.0 || scale.z() == 0.0 {
+        return Err(SceneFileError::new(args.position, "scale can't be zero along any axis"));
+    }
+    let mut transform = Affine::identity()
+        .scale(&scale)
+        .rotate_x(args.number_or("rotate_x", 0.0)?)
+        .rotate_y(args.number_or("rotate_y", 0.0)?)
+        .rotate_z(args.number_or("rotate_z", 0.0)?);
+
+    match (args.get("axis"), args.get("angle")) {
+        (Some(_), Some(_)) => transform = transform.rotate(&args.vector("axis")?, args.number("angle")?),
+        (None, None) => {},
+        _ => return Err(SceneFileError::new(args.position, "axis and angle have to be given together")),
+    }
+
+    match (args.get("lookfrom"), args.get("lookat")) {
+        (Some(_), Some(_)) => {
+            let (lookfrom, lookat) = (args.vector("lookfrom")?, args.vector("lookat")?);
+            let vup = args.vector_or("vup", Vec3::new(0.0, 1.0, 0.0))?;
+            if (lookat - lookfrom).squared_length() == 0.0 || Vec3::cross(&(lookat - lookfrom), &vup).squared_length() == 0.0 {
+                return Err(SceneFileError::new(args.position, "lookat has to be away from lookfrom and not along vup"));
+            }
+            transform = transform.look_at(&lookfrom, &lookat, &vup);
+        },
+        (None, None) if args.get("vup").is_none() => {},
+        _ => return Err(SceneFileError::new(args.position, "lookfrom and lookat have to be given together, along with any vup")),
+    }
+
+    Ok(transform.translate(&args.vector_or("translate", Vec3::zero())?))
+}
+
+// keyframe(time, scale, rotate_x, rotate_y, rotate_z, axis, angle, translate) in the keyframes of animate, which are
+// applied in that order like the arguments of transform
+fn keyframe(expr: &Expr) -> ParseResult<Keyframe> {
+    let (name, args) = expect_call(expr, "keyframe")?;
+    if name != "keyframe" {
+        return Err(SceneFileError::new(expr.position, format!("expected a keyframe but found {}", expr.describe())));
+    }
+    let args = Args::bind(name, expr.position, args, &["time", "scale", "rotate_x", "rotate_y", "rotate_z", "axis",
+        "angle", "translate"])?;
+
+    let mut keyframe = Keyframe::new(args.number("time")?)
+        .scale(&args.vector_or("scale", Vec3::uniform(1.0))?)
+        .rotate(&Vec3::new(1.0, 0.0, 0.0), args.number_or("rotate_x", 0.0)?)
+        .rotate(&Vec3::new(0.0, 1.0, 0.0), args.number_or("rotate_y", 0.0)?)
+        .rotate(&Vec3::new(0.0, 0.0, 1.0), args.number_or("rotate_z", 0.0)?);
+
+    match (args.get("axis"), args.get("angle")) {
+        (Some(_), Some(_)) => keyframe = keyframe.rotate(&args.vector("axis")?, args.number("angle")?),
+        (None, None) => {},
+        _ => return Err(SceneFileError::new(args.position, "axis and angle have to be given together")),
+    }
+
+    Ok(keyframe.translate(&args.vector_or("translate", Vec3::zero())?))
+}
+
+fn expect_number(expr: &Expr) -> ParseResult<f32> {
+    match expr.value {
+        Value::Number(number) => Ok(number),
+        _ => Err(SceneFileError::new(expr.position, format!("expected a number but found {}", expr.describe()))),
+    }
+}
+
+fn expect_vector(expr: &Expr) -> ParseResult<Vec3> {
+    match expr.value {
+        Value::Vector(vector) => Ok(vector),
+        Value::Number(number) => Ok(Vec3::uniform(number)),
+        _ => Err(SceneFileError::new(expr.position, format!("expected a vector but found {}", expr.describe()))),
+    }
+}
+
+fn expect_call<'a>(expr: &'a Expr, what: &str) -> ParseResult<(&'a str, &'a [Arg])> {
+    match &expr.value {
+        Value::Call(name, args) => Ok((name, args)),
+        _ => Err(SceneFileError::new(expr.position, format!("expected a {} but found {}", what, expr.describe()))),
+    }
+}
+
+fn unknown(expr: &Expr, what: &str, name: &str, known: &[&str]) -> SceneFileError {
+    SceneFileError::new(expr.position, format!("unknown {} '{}' (expected one of: {})", what, name, known.join(", ")))
+}
+
+const TEXTURES: [&str; 6] = ["constant", "checker", "perlin", "scaled_perlin", "turbulence", "image"];
+const MATERIALS: [&str; 10] = [
+    "lambertian", "metal", "dielectric", "conductor", "rough_dielectric", "principled", "diffuse_light", "isotropic",
+    "normal_map", "bump_map",
+];
+const HITABLES: [&str; 16] = [
+    "sphere", "moving_sphere", "xy_rect", "xz_rect", "yz_rect", "cube", "triangle", "mesh", "group",
+    "constant_medium", "translate", "rotate_y", "transform", "animate", "instance", "flip_normals",
+];
+const ANIMATIONS: [&str; 1] = ["frames"];
+const CAMERAS: [&str; 4] = ["perspective", "orthographic", "fisheye", "equirectangular"];
+const BACKGROUNDS: [&str; 4] = ["constant", "gradient", "environment", "sky"];
+
+struct Builder<'a> {
+    directory: &'a Path,
+    width: u32,
+    height: u32,
+    resources: Resources,
+    textures: HashMap<String, TextureRef>,
+    materials: HashMap<String, MaterialRef>,
+    hitables: HashMap<String, Expr>,
+    // Named hitables that have been built once to be shared by instances
+    geometry: HashMap<String, GeometryRef>,
+    // Names of the hitables being built, innermost last, so that a definition using itself is an error
+    resolving: Vec<String>,
+    // Built once all the statements are known, once for each frame of an animation
+    camera: Option<Expr>,
+    background: Option<Background>,
+    animation: Option<Animation>,
+    // Shutter of the first image to be rendered, which hitables with a BVH of their own are first built around
+    shutter: (f32, f32),
+    stats: SceneStats,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Animation {
+    first: u32,
+    last: u32,
+    shutter: f32,
+}
+
+impl<'a> Builder<'a> {
+    fn statement(&mut self, statement: Statement) -> ParseResult<()> {
+        match statement {
+            Statement::Texture(name, expr) => {
+                let texture = self.texture(&expr)?;
+                self.textures.insert(name, texture);
+            },
+            Statement::Material(name, expr) => {
+                let material = self.material(&expr)?;
+                self.materials.insert(name, material);
+            },
+            Statement::Hitable(name, expr) => {
+                // Built each time it is used so that every entity owns its own copy
+                self.hitables.insert(name, expr);
+            },
+            Statement::Entity(expr) => {
+                let hitable = self.hitable(&expr)?;
+                self.resources.new_boxed_entity(hitable);
+            },
+            Statement::Camera(expr) => {
+                if self.camera.is_some() {
+                    return Err(SceneFileError::new(expr.position, "the camera has already been defined"));
+                }
+                self.camera = Some(expr);
+            },
+            Statement::Background(expr) => {
+                if self.background.is_some() {
+                    return Err(SceneFileError::new(expr.position, "the background has already been defined"));
+                }
+                self.background = Some(self.background(&expr)?);
+            },
+            Statement::Animation(expr) => {
+                if self.animation.is_some() {
+                    return Err(SceneFileError::new(expr.position, "the animation has already been defined"));
+                }
+                self.animation = Some(self.animation(&expr)?);
+            },
+        }
+
+        Ok(())
+    }
+
+    fn texture(&mut self, expr: &Expr) -> ParseResult<TextureRef> {
+        match &expr.value {
+            Value::Name(name) => return self.textures.get(name).cloned().ok_or_else(|| {
+                SceneFileError::new(expr.position, format!("no texture named '{}' has been defined", name))
+            }),
+            Value::Number(_) | Value::Vector(_) => {
+                let albedo = expect_vector(expr)?;
+                return Ok(self.resources.new_texture(Texture::Constant(albedo)));
+            },
+            _ => {},
+        }
+
+        let (name, args) = expect_call(expr, "texture")?;
+        let texture = match name {
+            "constant" => {
+                let args = Args::bind(name, expr.position, args, &["albedo"])?;
+                Texture::Constant(args.vector("albedo")?)
+            },
+            "checker" => {
+                let args = Args::bind(name, expr.position, args, &["odd", "even"])?;
+                let odd = self.texture(args.required("odd")?)?;
+                let even = self.texture(args.required("even")?)?;
+                Texture::Checker(odd, even)
+            },
+            "perlin" => {
+                Args::bind(name, expr.position, args, &[])?;
+                Texture::Perlin
+            },
+            "scaled_perlin" => {
+                let args = Args::bind(name, expr.position, args, &["scale"])?;
+                Texture::ScaledPerlin(args.number("scale")?)
+            },
+            "turbulence" => {
+                let args = Args::bind(name, expr.position, args, &["scale"])?;
+                Texture::ScaledTurbulencePerlin(args.number("scale")?)
+            },
+            "image" => {
+                let args = Args::bind(name, expr.position, args, &["path"])?;
+                let path = self.directory.join(args.string("path")?);
+                match ::imagers::open(&path) {
+                    Ok(image) => Texture::Image(image.to_rgb()),
+                    Err(e) => return Err(SceneFileError::new(expr.position, format!(
+                        "could not open image {}: {}", path.display(), e))),
+                }
+            },
+            _ => return Err(unknown(expr, "texture", name, &TEXTURES)),
+        };
+
+        Ok(self.resources.new_texture(texture))
+    }
+
+    fn material(&mut self, expr: &Expr) -> ParseResult<MaterialRef> {
+        if let Value::Name(name) = &expr.value {
+            return self.materials.get(name).cloned().ok_or_else(|| {
+                SceneFileError::new(expr.position, format!("no material named '{}' has been defined", name))
+            });
+        }
+
+        let (name, args) = expect_call(expr, "material")?;
+        let material = match name {
+            "lambertian" => {
+                let args = Args::bind(name, expr.position, args, &["albedo"])?;
+                let albedo = args.required("albedo")?;
+                match albedo.value {
+                    Value::Number(_) | Value::Vector(_) => Material::Lambertian(expect_vector(albedo)?),
+                    _ => Material::LambertianTextured(self.texture(albedo)?),
+                }
+            },
+            "metal" => {
+                let args = Args::bind(name, expr.position, args, &["albedo", "fuzz"])?;
+                let fuzz = args.number_or("fuzz", 0.0)?;
+                if !(0.0..=1.0).contains(&fuzz) {
+                    return Err(SceneFileError::new(expr.position, format!("fuzz must be between 0 and 1 but was {}", fuzz)));
+                }
+                Material::Metal(args.vector("albedo")?, fuzz)
+            },
+            "dielectric" => {
+                let args = Args::bind(name, expr.position, args, &["ref_idx"])?;
+                Material::Dieletric(args.number("ref_idx")?)
+            },
+            // Either one of the named metals or its complex index of refraction
+            "conductor" => {
+                let args = Args::bind(name, expr.position, args, &["metal", "roughness", "eta", "k"])?;
+                let (eta, k) = match args.get("metal") {
+                    Some(_) if args.get("eta").is_some() || args.get("k").is_some() => {
+                        return Err(SceneFileError::new(expr.position, "conductor takes either metal or eta and k, not both"));
+                    },
+                    Some(_) => {
+                        let metal = args.string("metal")?;
+                        let names: Vec<&str> = CONDUCTORS.iter().map(|&(name, _, _)| name).collect();
+                        match CONDUCTORS.iter().find(|&&(name, _, _)| name == metal) {
+                            Some(&(_, [er, eg, eb], [kr, kg, kb])) => (Vec3::new(er, eg, eb), Vec3::new(kr, kg, kb)),
+                            None => return Err(unknown(expr, "metal", metal, &names)),
+                        }
+                    },
+                    None => (args.vector("eta")?, args.vector("k")?),
+                };
+                Material::Conductor { eta, k, roughness: args.number_or("roughness", 0.0)? }
+            },
+            "rough_dielectric" => {
+                let args = Args::bind(name, expr.position, args, &["ior", "roughness"])?;
+                Material::RoughDielectric { ior: args.number_or("ior", 1.5)?, roughness: args.number_or("roughness", 0.0)? }
+            },
+            // Every parameter can be a number, a colour or a texture
+            "principled" => {
+                let args = Args::bind(name, expr.position, args, &["base_colour", "metallic", "roughness", "specular",
+                    "specular_tint", "sheen", "clearcoat", "transmission", "ior"])?;
+                let defaults = Principled::default();
+                Material::Principled(Principled {
+                    base_colour: self.parameter(&args, "base_colour", defaults.base_colour)?,
+                    metallic: self.parameter(&args, "metallic", defaults.metallic)?,
+                    roughness: self.parameter(&args, "roughness", defaults.roughness)?,
+                    specular: self.parameter(&args, "specular", defaults.specular)?,
+                    specular_tint: self.parameter(&args, "specular_tint", defaults.specular_tint)?,
+                    sheen: self.parameter(&args, "sheen", defaults.sheen)?,
+                    clearcoat: self.parameter(&args, "clearcoat", defaults.clearcoat)?,
+                    transmission: self.parameter(&args, "transmission", defaults.transmission)?,
+                    ior: self.parameter(&args, "ior", defaults.ior)?,
+                })
+            },
+            "diffuse_light" => {
+                let args = Args::bind(name, expr.position, args, &["emit"])?;
+                Material::DiffuseLight(self.texture(args.required("emit")?)?)
+            },
+            "isotropic" => {
+                let args = Args::bind(name, expr.position, args, &["albedo"])?;
+                Material::Isotropic(self.texture(args.required("albedo")?)?)
+            },
+            // Wrap a copy of another material so that it can still be used without the detail
+            "normal_map" => {
+                let args = Args::bind(name, expr.position, args, &["material", "texture", "strength"])?;
+                let material = self.material(args.required("material")?)?;
+                let detail = SurfaceDetail::NormalMap {
+                    texture: self.texture(args.required("texture")?)?,
+                    strength: args.number_or("strength", 1.0)?,
+                };
+                Material::Detailed(Box::new(self.resources.get_material(material).clone()), detail)
+            },
+            "bump_map" => {
+                let args = Args::bind(name, expr.position, args, &["material", "height", "scale"])?;
+                let material = self.material(args.required("material")?)?;
+                let detail = SurfaceDetail::BumpMap {
+                    height: self.texture(args.required("height")?)?,
+                    scale: args.number_or("scale", 1.0)?,
+                };
+                Material::Detailed(Box::new(self.resources.get_material(material).clone()), detail)
+            },
+            _ => return Err(unknown(expr, "material", name, &MATERIALS)),
+        };
+
+        Ok(self.resources.new_material(material))
+    }
+
+    fn parameter(&mut self, args: &Args, param: &str, default: Parameter) -> ParseResult<Parameter> {
+        match args.get(param) {
+            Some(expr) => match expr.value {
+                Value::Number(_) | Value::Vector(_) => Ok(Parameter::Constant(expect_vector(expr)?)),
+                _ => Ok(Parameter::Texture(self.texture(expr)?)),
+            },
+            None => Ok(default),
+        }
+    }
+
+    // Shared geometry for a named hitable, built the first time it is instanced
+    fn geometry(&mut self, expr: &Expr) -> ParseResult<GeometryRef> {
+        let name = match &expr.value {
+            Value::Name(name) => name,
+            _ => return Err(SceneFileError::new(expr.position, format!(
+                "expected the name of a hitable but found {}", expr.describe()))),
+        };
+        if let Some(&geometry) = self.geometry.get(name) {
+            return Ok(geometry);
+        }
+
+        let hitable = self.hitable(expr)?;
+        let geometry = self.resources.new_boxed_geometry(hitable);
+        self.geometry.insert(name.clone(), geometry);
+        Ok(geometry)
+    }
+
+    fn hitable(&mut self, expr: &Expr) -> ParseResult<Box<dyn Hitable>> {
+        if let Value::Name(name) = &expr.value {
+            let definition = match self.hitables.get(name).cloned() {
+                Some(definition) => definition,
+                None => return Err(SceneFileError::new(expr.position, format!("no hitable named '{}' has been defined", name))),
+            };
+            if let Some(start) = self.resolving.iter().position(|resolving| resolving == name) {
+                let cycle: Vec<&str> = self.resolving[start..].iter().map(String::as_str).chain(Some(name.as_str())).collect();
+                return Err(SceneFileError::new(expr.position, format!(
+                    "hitable '{}' is defined in terms of itself ({})", name, cycle.join(" -> "))));
+            }
+
+            self.resolving.push(name.clone());
+            let hitable = self.hitable(&definition);
+            self.resolving.pop();
+            return hitable;
+        }
+
+        let (name, args) = expect_call(expr, "hitable")?;
+        let hitable: Box<dyn Hitable> = match name {
+            "sphere" => {
+                let args = Args::bind(name, expr.position, args, &["center", "radius", "material"])?;
+                let material = self.material(args.required("material")?)?;
+                Box::new(Sphere::new(args.vector("center")?, args.number("radius")?, material))
+            },
+            "moving_sphere" => {
+                let args = Args::bind(name, expr.position, args,
+                                      &["center0", "center1", "time0", "time1", "radius", "material"])?;
+                let material = self.material(args.required("material")?)?;
+                Box::new(MovingSphere::new(
+                    args.vector("center0")?,
+                    args.vector("center1")?,
+                    args.number_or("time0", 0.0)?,
+                    args.number_or("time1", 1.0)?,
+                    args.number("radius")?,
+                    material,
+                ))
+            },
+            "xy_rect" => {
+                let args = Args::bind(name, expr.position, args, &["x0", "x1", "y0", "y1", "k", "material"])?;
+                let material = self.material(args.required("material")?)?;
+                Box::new(XYRect::new(args.number("x0")?, args.number("x1")?, args.number("y0")?,
+                                     args.number("y1")?, args.number("k")?, material))
+            },
+            "xz_rect" => {
+                let args = Args::bind(name, expr.position, args, &["x0", "x1", "z0", "z1", "k", "material"])?;
+                let material = self.material(args.required("material")?)?;
+                Box::new(XZRect::new(args.number("x0")?, args.number("x1")?, args.number("z0")?,
+                                     args.number("z1")?, args.number("k")?, material))
+            },
+            "yz_rect" => {
+                let args = Args::bind(name, expr.position, args, &["y0", "y1", "z0", "z1", "k", "material"])?;
+                let material = self.material(args.required("material")?)?;
+                Box::new(YZRect::new(args.number("y0")?, args.number("y1")?, args.number("z0")?,
+                                     args.number("z1")?, args.number("k")?, material))
+            },
+            "cube" => {
+                let args = Args::bind(name, expr.position, args, &["min", "max", "material"])?;
+                let material = self.material(args.required("material")?)?;
+                Box::new(Cube::new(args.vector("min")?, args.vector("max")?, material))
+            },
+            "triangle" => {
+                let args = Args::bind(name, expr.position, args, &["v0", "v1", "v2", "material"])?;
+                let material = self.material(args.required("material")?)?;
+                Box::new(Triangle::new(args.vector("v0")?, args.vector("v1")?, args.vector("v2")?, material))
+            },
+            "mesh" => {
+                // The material is used for any faces that don't have a material in the OBJ's MTL file
+                let args = Args::bind(name, expr.position, args, &["path", "material"])?;
+                let material = match args.get("material") {
+                    Some(material) => self.material(material)?,
+                    None => self.resources.new_material(Material::Lambertian(Vec3::uniform(0.73))),
+                };
+                let path = self.directory.join(args.string("path")?);
+                let mesh = load_obj(&path, &mut self.resources, material)
+                    .map_err(|e| SceneFileError::new(expr.position, format!("could not load mesh: {}", e)))?;
+                self.stats.meshes.push(MeshStats { path, triangles: mesh.triangle_count(), bvh: mesh.bvh_stats() });
+                Box::new(mesh)
+            },
+            // Any number of hitables given in order, they get a BVH of their own
+            "group" => {
+                let mut hitables = Vec::with_capacity(args.len());
+                for arg in args {
+                    if let Some((_, name_position)) = &arg.name {
+                        return Err(SceneFileError::new(*name_position, "the hitables of a group can't be named"));
+                    }
+                    hitables.push(self.hitable(&arg.expr)?);
+                }
+                let (time0, time1) = self.shutter;
+                let group = Group::new(&self.resources.entities, hitables, time0, time1)
+                    .map_err(|e| SceneFileError::new(expr.position, e))?;
+                self.stats.groups.push(GroupStats { hitables: group.hitable_count(), bvh: group.bvh_stats() });
+                Box::new(group)
+            },
+            "constant_medium" => {
+                let args = Args::bind(name, expr.position, args, &["boundary", "density", "phase_function"])?;
+                let boundary = self.hitable(args.required("boundary")?)?;
+                let phase_function = self.material(args.required("phase_function")?)?;
+                Box::new(ConstantMedium::new(boundary, args.number("density")?, phase_function))
+            },
+            "translate" => {
+                let args = Args::bind(name, expr.position, args, &["hitable", "offset"])?;
+                let hitable = self.hitable(args.required("hitable")?)?;
+                Box::new(Translate::new(hitable, args.vector("offset")?))
+            },
+            "rotate_y" => {
+                let args = Args::bind(name, expr.position, args, &["hitable", "angle"])?;
+                let hitable = self.hitable(args.required("hitable")?)?;
+                Box::new(RotateY::new(hitable, args.number("angle")?))
+            },
+            "transform" => {
+                let args = Args::bind(name, expr.position, args, &["hitable", "scale", "rotate_x", "rotate_y", "rotate_z",
+                    "axis", "angle", "lookfrom", "lookat", "vup", "translate"])?;
+                let hitable = self.hitable(args.required("hitable")?)?;
+                Box::new(Transform::new(hitable, affine(&args)?))
+            },
+            // A hitable followed by any number of keyframes, between which it moves over the camera's shutter
+            "animate" => {
+                let hitable = match args.first() {
+                    Some(Arg { name: None, expr }) => self.hitable(expr)?,
+                    _ => return Err(SceneFileError::new(expr.position, "animate needs a hitable as its first argument")),
+                };
+                let mut keyframes = Vec::with_capacity(args.len() - 1);
+                for arg in &args[1..] {
+                    if let Some((_, name_position)) = &arg.name {
+                        return Err(SceneFileError::new(*name_position, "the keyframes of animate can't be named"));
+                    }
+                    keyframes.push(keyframe(&arg.expr)?);
+                }
+                Box::new(AnimatedTransform::new(hitable, keyframes).map_err(|e| SceneFileError::new(expr.position, e))?)
+            },
+            // A copy of a named hitable that shares its geometry with every other instance of it, placed by the same
+            // arguments as transform and with the hitable's materials replaced by material if it is given
+            "instance" => {
+                let args = Args::bind(name, expr.position, args, &["hitable", "material", "scale", "rotate_x", "rotate_y",
+                    "rotate_z", "axis", "angle", "lookfrom", "lookat", "vup", "translate"])?;
+                let geometry = self.geometry(args.required("hitable")?)?;
+                let material = match args.get("material") {
+                    Some(material) => Some(self.material(material)?),
+                    None => None,
+                };
+                Box::new(Instance::new(&self.resources.entities, geometry, affine(&args)?, material))
+            },
+            "flip_normals" => {
+                let args = Args::bind(name, expr.position, args, &["hitable"])?;
+                Box::new(FlipNormals::new(self.hitable(args.required("hitable")?)?))
+            },
+            _ => return Err(unknown(expr, "hitable", name, &HITABLES)),
+        };
+
+        Ok(hitable)
+    }
+
+    // The camera for frame of an animation, or for a still scene if frame is None
+    fn camera(&self, expr: &Expr, frame: Option<u32>) -> ParseResult<Camera> {
+        let (name, args) = expect_call(expr, "camera")?;
+        let frame = frame.map(|frame| frame as f32);
+        match name {
+            "perspective" => {
+                let args = Args::bind(name, expr.position, args, &[
+                    "lookfrom", "lookat", "vup", "vfov", "aperture", "focus_dist", "time0", "time1",
+                ])?.at_frame(frame);
+                let lookfrom = args.vector("lookfrom")?;
+                let lookat = args.vector("lookat")?;
+                let (time0, time1) = self.shutter(&args)?;
+                Ok(Camera::new(
+                    lookfrom,
+                    lookat,
+                    args.vector_or("vup", Vec3::new(0.0, 1.0, 0.0))?,
+                    args.number("vfov")?,
+                    self.width as f32 / self.height as f32,
+                    args.number_or("aperture", 0.0)?,
+                    args.number_or("focus_dist", (lookfrom - lookat).length())?,
+                    time0,
+                    time1,
+                ))
+            },
+            "orthographic" => {
+                let args = Args::bind(name, expr.position, args, &[
+                    "lookfrom", "lookat", "vup", "view_height", "time0", "time1",
+                ])?.at_frame(frame);
+                let (time0, time1) = self.shutter(&args)?;
+                Ok(Camera::orthographic(
+                    args.vector("lookfrom")?,
+                    args.vector("lookat")?,
+                    args.vector_or("vup", Vec3::new(0.0, 1.0, 0.0))?,
+                    args.number("view_height")?,
+                    self.width as f32 / self.height as f32,
+                    time0,
+                    time1,
+                ))
+            },
+            "fisheye" => {
+                let args = Args::bind(name, expr.position, args, &["lookfrom", "lookat", "vup", "fov", "time0", "time1"])?
+                    .at_frame(frame);
+                let (time0, time1) = self.shutter(&args)?;
+                Ok(Camera::fisheye(
+                    args.vector("lookfrom")?,
+                    args.vector("lookat")?,
+                    args.vector_or("vup", Vec3::new(0.0, 1.0, 0.0))?,
+                    args.number_or("fov", 180.0)?,
+                    self.width as f32 / self.height as f32,
+                    time0,
+                    time1,
+                ))
+            },
+            "equirectangular" => {
+                let args = Args::bind(name, expr.position, args, &["lookfrom", "lookat", "vup", "time0", "time1"])?
+                    .at_frame(frame);
+                let (time0, time1) = self.shutter(&args)?;
+                Ok(Camera::equirectangular(
+                    args.vector("lookfrom")?,
+                    args.vector("lookat")?,
+                    args.vector_or("vup", Vec3::new(0.0, 1.0, 0.0))?,
+                    time0,
+                    time1,
+                ))
+            },
+            _ => Err(unknown(expr, "camera", name, &CAMERAS)),
+        }
+    }
+
+    // A frame's shutter opens at the frame's number, otherwise it is given by time0 and time1
+    fn shutter(&self, args: &Args) -> ParseResult<(f32, f32)> {
+        match (args.frame, self.animation) {
+            (Some(frame), Some(animation)) => {
+                if args.get("time0").is_some() || args.get("time1").is_some() {
+                    return Err(SceneFileError::new(args.position, "time0 and time1 are set by the animation's shutter"));
+                }
+                Ok((frame, frame + animation.shutter))
+            },
+            _ => Ok((args.number_or("time0", 0.0)?, args.number_or("time1", 1.0)?)),
+        }
+    }
+
+    fn animation(&self, expr: &Expr) -> ParseResult<Animation> {
+        let (name, args) = expect_call(expr, "animation")?;
+        match name {
+            "frames" => {
+                let args = Args::bind(name, expr.position, args, &["first", "last", "shutter"])?;
+                let frame = |param: &str, default: Option<f32>| {
+                    let number = match default {
+                        Some(default) => args.number_or(param, default)?,
+                        None => args.number(param)?,
+                    };
+                    if number < 0.0 || number.fract() != 0.0 {
+                        return Err(SceneFileError::new(expr.position, format!(
+                            "{} has to be a whole number of frames but was {}", param, number)));
+                    }
+                    Ok(number as u32)
+                };
+                let (first, last) = (frame("first", Some(1.0))?, frame("last", None)?);
+                if last < first {
+                    return Err(SceneFileError::new(expr.position, "the last frame can't come before the first"));
+                }
+                let shutter = args.number_or("shutter", 0.5)?;
+                if !(0.0..=1.0).contains(&shutter) {
+                    return Err(SceneFileError::new(expr.position, format!(
+                        "shutter must be between 0 and 1 frames but was {}", shutter)));
+                }
+                Ok(Animation { first, last, shutter })
+            },
+            _ => Err(unknown(expr, "animation", name, &ANIMATIONS)),
+        }
+    }
+
+    fn background(&mut self, expr: &Expr) -> ParseResult<Background> {
+        let (name, args) = expect_call(expr, "background")?;
+        match name {
+            "constant" => {
+                let args = Args::bind(name, expr.position, args, &["colour"])?;
+                Ok(Background::Constant(args.vector("colour")?))
+            },
+            // Defaults to the sky from the first book
+            "gradient" => {
+                let args = Args::bind(name, expr.position, args, &["bottom", "top"])?;
+                Ok(Background::Gradient {
+                    bottom: args.vector_or("bottom", Vec3::uniform(1.0))?,
+                    top: args.vector_or("top", Vec3::new(0.5, 0.7, 1.0))?,
+                })
+            },
+            "environment" => {
+                let args = Args::bind(name, expr.position, args, &["path", "rotation", "intensity"])?;
+                let path = self.directory.join(args.string("path")?);
+                let map = EnvironmentMap::load(&path, args.number_or("rotation", 0.0)?, args.number_or("intensity", 1.0)?)
+                    .map_err(|e| SceneFileError::new(expr.position, e))?;
+                Ok(Background::Environment(map))
+            },
+            // The sun is placed by sun_direction if it's given, otherwise by its elevation and azimuth in degrees
+            "sky" => {
+                let args = Args::bind(name, expr.position, args,
+                    &["elevation", "azimuth", "turbidity", "ground_albedo", "intensity", "sun_direction"])?;
+                let sun_direction = match args.get("sun_direction") {
+                    Some(direction) => expect_vector(direction)?,
+                    None => Sky::sun_direction(args.number_or("elevation", 45.0)?, args.number_or("azimuth", 0.0)?),
+                };
+                let turbidity = args.number_or("turbidity", 3.0)?;
+                if !(2.0..=10.0).contains(&turbidity) {
+                    return Err(SceneFileError::new(expr.position, format!(
+                        "turbidity must be between 2 and 10 but was {}", turbidity)));
+                }
+                Ok(Background::Sky(Sky::new(
+                    sun_direction,
+                    turbidity,
+                    args.vector_or("ground_albedo", Vec3::uniform(0.3))?,
+                    args.number_or("intensity", 0.05)?,
+                )))
+            },
+            _ => Err(unknown(expr, "background", name, &BACKGROUNDS)),
+        }
+    }
+}
+
+// Image paths inside the scene are relative to the directory of the scene file
+pub fn parse_scene(
+    source: &str,
+    directory: &Path,
+    width: u32,
+    height: u32,
+    samples: u32,
+) -> ParseResult<(Scene, Window, SceneStats)> {
+    let tokens = tokenize(source)?;
+    let end = tokens[tokens.len() - 1].position;
+    let statements = Parser { tokens, index: 0 }.statements()?;
+
+    let mut builder = Builder {
+        directory,
+        width,
+        height,
+        resources: Resources::new(),
+        textures: HashMap::new(),
+        materials: HashMap::new(),
+        hitables: HashMap::new(),
+        geometry: HashMap::new(),
+        resolving: vec![],
+        camera: None,
+        background: None,
+        animation: None,
+        shutter: (0.0, 1.0),
+        stats: SceneStats::default(),
+    };
+
+    // The camera and animation come first wherever they are in the file, as they decide the shutter
+    let (timing, statements): (Vec<_>, Vec<_>) = statements.into_iter()
+        .partition(|statement| matches!(statement, Statement::Camera(_) | Statement::Animation(_)));
+    for statement in timing {
+        builder.statement(statement)?;
+    }
+    if let Some(ref camera) = builder.camera {
+        builder.shutter = builder.camera(camera, builder.animation.map(|animation| animation.first))?.shutter();
+    }
+
+    for statement in statements {
+        builder.statement(statement)?;
+    }
+
+    let camera = builder.camera.take().ok_or_else(|| SceneFileError::new(end, "the scene does not define a camera"))?;
+    if builder.resources.entities.len() == 0 {
+        return Err(SceneFileError::new(end, "the scene does not contain any entities"));
+    }
+
+    let window = match builder.animation {
+        Some(animation) => {
+            let mut frames = Vec::with_capacity((animation.last - animation.first + 1) as usize);
+            for number in animation.first..=animation.last {
+                frames.push(Frame { number, camera: builder.camera(&camera, Some(number))? });
+            }
+            let first = builder.camera(&camera, Some(animation.first))?;
+            Window::animated(width, height, samples, first, frames)
+        },
+        None => Window::new(width, height, samples, builder.camera(&camera, None)?),
+    };
+
+    let background = builder.background.unwrap_or_default();
+    Ok((Scene::with_background(builder.resources, background), window, builder.stats))
+}
+
+pub fn load_scene_file<P: AsRef<Path>>(
+    path: P,
+    width: u32,
+    height: u32,
+    samples: u32,
+) -> Result<(Scene, Window, SceneStats), String> {
+    let path = path.as_ref();
+    let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let directory = path.parent().map_or_else(PathBuf::new, Path::to_path_buf);
+
+    parse_scene(&source, &directory, width, height, samples).map_err(|e| format!("{}:{}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAMERA: &str = "camera perspective(lookfrom = [0 0 5], lookat = [0 0 0], vfov = 40)\n";
+
+    fn parse(source: &str) -> ParseResult<(Scene, Window, SceneStats)> {
+        parse_scene(source, Path::new(""), 4, 3, 1)
+    }
+
+    fn parse_error(source: &str) -> SceneFileError {
+        match parse(source) {
+            Ok(_) => panic!("parsed a scene that should have been rejected:\n{}", source),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn parses_a_valid_scene() {
+        let source = format!("{}{}", CAMERA, r#"
+            # Comments run to the end of the line
+            texture checks = checker(constant(0.1), constant(0.9))
+            material ground = lambertian(checks)
+            material glass = dielectric(1.5)
+            hitable ball = sphere([0 1 0], radius = 1, material = glass)
+
+            entity sphere([0 -1000 0], 1000, ground)
+            entity ball
+            entity translate(ball, [2 0 0])
+            entity xz_rect(-1, 1, -1, 1, 3, diffuse_light(constant(4)))
+            entity group(sphere([-2 0 0], 0.5, ground), sphere([-3 0 0], 0.5, glass), ball)
+        "#);
+        let (scene, window, stats) = match parse(&source) {
+            Ok(parsed) => parsed,
+            Err(e) => panic!("{}", e),
+        };
+
+        assert_eq!(scene.resources.entities.len(), 5);
+        assert_eq!(scene.resources.materials.len(), 3);
+        assert_eq!((window.width, window.height, window.samples), (4, 3, 1));
+        assert!(window.frames.is_empty());
+        assert!(stats.meshes.is_empty());
+        assert_eq!(stats.groups.len(), 1);
+        assert_eq!(stats.groups[0].hitables, 3);
+    }
+
+    #[test]
+    fn reports_an_unknown_identifier() {
+        let error = parse_error(&format!("{}entity spere([0 0 0], 1, lambertian(constant(0.5)))", CAMERA));
+        assert_eq!(error.position, Position { line: 2, column: 8 });
+        assert!(error.message.starts_with("unknown hitable 'spere'"), "{}", error);
+    }
+
+    #[test]
+    fn reports_an_argument_of_the_wrong_type() {
+        let error = parse_error(&format!("{}entity sphere(\"centre\", 1, lambertian(constant(0.5)))", CAMERA));
+        assert_eq!(error.position, Position { line: 2, column: 15 });
+        assert_eq!(error.message, "expected a vector but found a string");
+    }
+
+    #[test]
+    fn reports_an_undefined_material() {
+        let error = parse_error(&format!("{}\nentity sphere([0 0 0], 1, red)", CAMERA));
+        assert_eq!(error.position, Position { line: 3, column: 27 });
+        assert_eq!(error.message, "no material named 'red' has been defined");
+        assert_eq!(error.to_string(), "3:27: no material named 'red' has been defined");
+    }
+
+    #[test]
+    fn reports_a_hitable_defined_in_terms_of_itself() {
+        let error = parse_error(&format!("{}hitable a = translate(a, [1 0 0])\nentity a", CAMERA));
+        assert_eq!(error.position, Position { line: 2, column: 23 });
+        assert_eq!(error.message, "hitable 'a' is defined in terms of itself (a -> a)");
+
+        let error = parse_error(&format!(
+            "{}hitable a = translate(b, [1 0 0])\nhitable b = rotate_y(instance(a), 90)\nentity b", CAMERA));
+        assert_eq!(error.position, Position { line: 2, column: 23 });
+        assert_eq!(error.message, "hitable 'b' is defined in terms of itself (b -> a -> b)");
+    }
+
+    #[test]
+    fn rejects_metal_fuzz_above_one() {
+        let error = parse_error(&format!("{}entity sphere([0 0 0], 1, metal([0.8 0.8 0.8], 1.5))", CAMERA));
+        assert_eq!(error.position, Position { line: 2, column: 27 });
+        assert_eq!(error.message, "fuzz must be between 0 and 1 but was 1.5");
+    }
+}
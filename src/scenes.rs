@@ -1,7 +1,9 @@
 use aarect::{XYRect, XZRect, YZRect};
+use background::Background;
 use camera::Camera;
 use cube::Cube;
-use transform::{FlipNormals, RotateY, Translate};
+use instance::Instance;
+use transform::{Affine, FlipNormals, RotateY, Translate};
 use vec3::Vec3;
 
 use scene::{Scene, Window};
@@ -12,6 +14,18 @@ use sphere::{Sphere, MovingSphere};
 use volume::ConstantMedium;
 use random::drand48;
 
+pub const SCENE_NAMES: [&str; 10] = [
+    "default_scene",
+    "random_scene",
+    "random_moving_scene",
+    "two_spheres",
+    "earth",
+    "two_perlin_spheres",
+    "simple_light",
+    "cornell_box",
+    "cornell_smoke",
+    "final_scene",
+];
 
 pub fn load_scene(name: &str, width: u32, height: u32, samples: u32) -> Result<(Scene, Window), String> {
     match name {
@@ -25,7 +39,7 @@ pub fn load_scene(name: &str, width: u32, height: u32, samples: u32) -> Result<(
         "cornell_box" => Ok(make_cornell_box(width, height, samples)),
         "cornell_smoke" => Ok(make_cornell_smoke(width, height, samples)),
         "final_scene" => Ok(make_final_scene(width, height, samples)),
-        _ => Err("Unknown scene!".to_owned())
+        _ => Err(format!("Unknown scene '{}'. Use --list-scenes to see the available scenes", name))
     }
 }
 
@@ -84,7 +98,7 @@ pub fn make_scene(nx: u32, ny: u32, samples: u32) -> (Scene, Window) {
     resources.new_entity(Sphere::new(Vec3::new(-1.0, 0.0, -1.0), -0.45, 3));
 
     (
-        Scene::new(resources),
+        Scene::with_background(resources, Background::sky()),
         Window::new(nx, ny, samples, make_default_camera(nx, ny)),
     )
 }
@@ -124,7 +138,7 @@ pub fn make_random_scene(nx: u32, ny: u32, samples: u32) -> (Scene, Window) {
     }
 
     (
-        Scene::new(resources),
+        Scene::with_background(resources, Background::sky()),
         Window::new(nx, ny, samples, make_default_camera(nx, ny)),
     )
 }
@@ -170,7 +184,7 @@ pub fn make_random_moving_scene(nx: u32, ny: u32, samples: u32) -> (Scene, Windo
     }
 
     (
-        Scene::new(resources),
+        Scene::with_background(resources, Background::sky()),
         Window::new(nx, ny, samples, make_default_camera(nx, ny)),
     )
 }
@@ -187,7 +201,7 @@ pub fn make_two_spheres_scene(nx: u32, ny: u32, samples: u32) -> (Scene, Window)
     resources.new_entity(Sphere::new(Vec3::new(0.0, 10.0, 0.0), 10.0, 0));
 
     (
-        Scene::new(resources),
+        Scene::with_background(resources, Background::sky()),
         Window::new(nx, ny, samples, make_default_camera(nx, ny)),
     )
 }
@@ -203,7 +217,7 @@ pub fn make_two_perlin_spheres_scene(nx: u32, ny: u32, samples: u32) -> (Scene,
     resources.new_entity(Sphere::new(Vec3::new(0.0, -1000.0, 0.0), 1000.0, perlin_mat));
     resources.new_entity(Sphere::new(Vec3::new(0.0, 2.0, 0.0), 2.0, perlin_mat));
     (
-        Scene::new(resources),
+        Scene::with_background(resources, Background::sky()),
         Window::new(nx, ny, samples, make_default_camera(nx, ny)),
     )
 }
@@ -216,7 +230,7 @@ pub fn make_earth_scene(nx: u32, ny: u32, samples: u32) -> (Scene, Window) {
     resources.new_entity(Sphere::new(Vec3::zero(), 2.0, 0));
 
     (
-        Scene::new(resources),
+        Scene::with_background(resources, Background::sky()),
         Window::new(nx, ny, samples, make_default_camera(nx, ny)),
     )
 }
@@ -244,8 +258,8 @@ pub fn make_simple_light_scene(nx: u32, ny: u32, samples: u32) -> (Scene, Window
                 Vec3::new(18.0, 5.0, 3.0),
                 Vec3::new(0.0, 0.0, 0.0),
                 40.0,
-                nx as u32,
-                ny as u32,
+                nx,
+                ny,
                 0.1,
                 10.0,
             ),
@@ -292,8 +306,8 @@ pub fn make_cornell_box(nx: u32, ny: u32, samples: u32) -> (Scene, Window) {
                 Vec3::new(278.0, 278.0, -800.0),
                 Vec3::new(278.0, 278.0, 0.0),
                 40.0,
-                nx as u32,
-                ny as u32,
+                nx,
+                ny,
                 0.0,
                 1.0,
             ),
@@ -357,8 +371,8 @@ pub fn make_cornell_smoke(nx: u32, ny: u32, samples: u32) -> (Scene, Window) {
                 Vec3::new(278.0, 278.0, -800.0),
                 Vec3::new(278.0, 278.0, 0.0),
                 40.0,
-                nx as u32,
-                ny as u32,
+                nx,
+                ny,
                 0.0,
                 1.0,
             ),
@@ -380,7 +394,7 @@ pub fn make_final_scene(nx: u32, ny: u32, samples: u32) -> (Scene, Window) {
     let brown = resources.new_material(Material::LambertianTextured(tex));
 
     let glass = resources.new_material(Material::Dieletric(1.5));
-    let metal = resources.new_material(Material::Metal(Vec3::new(0.8, 0.8, 0.9), 10.0));
+    let metal = resources.new_material(Material::Metal(Vec3::new(0.8, 0.8, 0.9), 1.0));
 
     let tex = resources.new_texture(Texture::Constant(Vec3::new(0.2, 0.4, 0.9)));
     let blue = resources.new_material(Material::Isotropic(tex));
@@ -421,9 +435,15 @@ pub fn make_final_scene(nx: u32, ny: u32, samples: u32) -> (Scene, Window) {
     resources.new_entity(Sphere::new(Vec3::new(400.0, 200.0, 400.0), 100.0, earthmap));
     resources.new_entity(Sphere::new(Vec3::new(220.0, 280.0, 300.0), 80.0, perlin));
 
+    // Every particle is an instance of the same sphere
+    let particle = resources.new_geometry(Sphere::new(Vec3::zero(), 10.0, white));
     for _i in 0..1000 {
-        let particle = Sphere::new(Vec3::random() * 165.0, 10.0, white);
-        resources.new_entity(Translate::new(RotateY::new(particle, 15.0), Vec3::new(-100.0, 270.0, 395.0)));
+        let transform = Affine::identity()
+            .translate(&(Vec3::random() * 165.0))
+            .rotate_y(15.0)
+            .translate(&Vec3::new(-100.0, 270.0, 395.0));
+        let instance = Instance::new(&resources.entities, particle, transform, None);
+        resources.new_entity(instance);
     }
 
     (
@@ -436,8 +456,8 @@ pub fn make_final_scene(nx: u32, ny: u32, samples: u32) -> (Scene, Window) {
                 Vec3::new(278.0, 278.0, -800.0),
                 Vec3::new(278.0, 278.0, 0.0),
                 40.0,
-                nx as u32,
-                ny as u32,
+                nx,
+                ny,
                 0.0,
                 10.0,
             ),
@@ -0,0 +1,199 @@
+// Clear daylight sky and sun
+// Credit to Preetham, Shirley and Smits - A Practical Analytic Model for Daylight - https://dl.acm.org/doi/10.1145/311535.311545
+
+use onb::Onb;
+use random::random_to_sphere;
+use vec3::Vec3;
+
+use std::f32::consts::PI;
+
+// Angle the sun's disk covers seen from the earth
+const SUN_ANGULAR_RADIUS: f32 = 0.004_65;
+// Luminance of the sun outside the atmosphere in kcd/m^2, the same units as the sky
+const SUN_LUMINANCE: f32 = 1.6e6;
+// Wavelengths in micrometres used for the red, green and blue transmittance of the atmosphere
+const WAVELENGTHS: [f32; 3] = [0.68, 0.55, 0.44];
+// Keeps the model, which is only fitted for the sun above the horizon, away from its singularity
+const MAX_SUN_THETA: f32 = PI / 2.0 - 0.01;
+
+// 2 * PI * (1 - cos(radius)) written so that it doesn't lose all its precision for such a small angle
+fn sun_solid_angle() -> f32 {
+    4.0 * PI * (SUN_ANGULAR_RADIUS / 2.0).sin().powi(2)
+}
+
+// Perez et al.'s luminance distribution for angle theta from the zenith and gamma from the sun
+fn perez(coefficients: &[f32; 5], theta: f32, gamma: f32) -> f32 {
+    let [a, b, c, d, e] = *coefficients;
+    (1.0 + a * (b / theta.cos().max(0.001)).exp()) * (1.0 + c * (d * gamma).exp() + e * gamma.cos().powi(2))
+}
+
+fn xyy_to_rgb(x: f32, y: f32, luminance: f32) -> Vec3 {
+    if y <= 0.0 {
+        return Vec3::zero();
+    }
+
+    let big_x = x / y * luminance;
+    let big_z = (1.0 - x - y) / y * luminance;
+    // Linear sRGB primaries
+    Vec3::new(
+        (3.2406 * big_x - 1.5372 * luminance - 0.4986 * big_z).max(0.0),
+        (-0.9689 * big_x + 1.8758 * luminance + 0.0415 * big_z).max(0.0),
+        (0.0557 * big_x - 0.2040 * luminance + 1.0570 * big_z).max(0.0),
+    )
+}
+
+// Fraction of sunlight that makes it through the atmosphere at each wavelength, from the paper's appendix
+fn sun_transmittance(theta: f32, turbidity: f32) -> Vec3 {
+    let degrees = theta * 180.0 / PI;
+    let relative_mass = 1.0 / (theta.cos() + 0.15 * (93.885 - degrees).max(0.001).powf(-1.253));
+    let beta = 0.046_08 * turbidity - 0.045_86;
+    let alpha = 1.3;
+
+    let channel = |lambda: f32| {
+        let rayleigh = (-0.008_735 * lambda.powf(-4.08) * relative_mass).exp();
+        let aerosol = (-beta * lambda.powf(-alpha) * relative_mass).exp();
+        rayleigh * aerosol
+    };
+    Vec3::new(channel(WAVELENGTHS[0]), channel(WAVELENGTHS[1]), channel(WAVELENGTHS[2]))
+}
+
+#[derive(Debug)]
+pub struct Sky {
+    sun_direction: Vec3,
+    sun_radiance: Vec3,
+    cos_sun_radius: f32,
+    // Zenith values and Perez coefficients for luminance and the x and y chromaticities
+    zenith: [f32; 3],
+    coefficients: [[f32; 5]; 3],
+    // Normalises perez so that it gives the zenith value at the zenith
+    normalisation: [f32; 3],
+    // Below the horizon is a diffuse ground lit by the sky and sun
+    ground_radiance: Vec3,
+    intensity: f32,
+}
+
+impl Sky {
+    // sun_direction points towards the sun, turbidity is the haziness of the atmosphere from 2 (very clear) to 10
+    // (hazy). The model's luminances are in kcd/m^2 and are multiplied by intensity to bring them into scene units
+    pub fn new(sun_direction: Vec3, turbidity: f32, ground_albedo: Vec3, intensity: f32) -> Sky {
+        let sun_direction = sun_direction.unit();
+        let sun_theta = sun_direction.y().clamp(-1.0, 1.0).acos().min(MAX_SUN_THETA);
+        let t = turbidity;
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * sun_theta);
+        let zenith_luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+
+        let (theta, theta2, theta3) = (sun_theta, sun_theta * sun_theta, sun_theta * sun_theta * sun_theta);
+        let zenith_x = t * t * (0.00166 * theta3 - 0.00375 * theta2 + 0.00209 * theta)
+            + t * (-0.02903 * theta3 + 0.06377 * theta2 - 0.03202 * theta + 0.00394)
+            + (0.11693 * theta3 - 0.21196 * theta2 + 0.06052 * theta + 0.25886);
+        let zenith_y = t * t * (0.00275 * theta3 - 0.00610 * theta2 + 0.00317 * theta)
+            + t * (-0.04214 * theta3 + 0.08970 * theta2 - 0.04153 * theta + 0.00516)
+            + (0.15346 * theta3 - 0.26756 * theta2 + 0.06670 * theta + 0.26688);
+
+        let coefficients = [
+            [0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251, 0.1206 * t - 2.5771, -0.0670 * t + 0.3703],
+            [-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125, -0.0641 * t - 0.8989, -0.0033 * t + 0.0452],
+            [-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102, -0.0441 * t - 1.6537, -0.0109 * t + 0.0529],
+        ];
+        let normalisation = [
+            perez(&coefficients[0], 0.0, sun_theta),
+            perez(&coefficients[1], 0.0, sun_theta),
+            perez(&coefficients[2], 0.0, sun_theta),
+        ];
+
+        let cos_sun_radius = SUN_ANGULAR_RADIUS.cos();
+        let sun_above_horizon = sun_direction.y() > 0.0;
+        let sun_radiance = if sun_above_horizon {
+            // Outside the atmosphere the sun is white, so its luminance is shared equally between the channels
+            sun_transmittance(sun_theta, turbidity) * SUN_LUMINANCE
+        } else {
+            Vec3::zero()
+        };
+
+        let mut sky = Sky {
+            sun_direction,
+            sun_radiance,
+            cos_sun_radius,
+            zenith: [zenith_luminance, zenith_x, zenith_y],
+            coefficients,
+            normalisation,
+            ground_radiance: Vec3::zero(),
+            intensity,
+        };
+
+        // Irradiance on the ground from the sky by the midpoint rule, plus the sun's disk
+        let (steps_theta, steps_phi) = (16, 32);
+        let (d_theta, d_phi) = (PI / 2.0 / steps_theta as f32, 2.0 * PI / steps_phi as f32);
+        let mut irradiance = Vec3::zero();
+        for i in 0..steps_theta {
+            let theta = (i as f32 + 0.5) * d_theta;
+            for j in 0..steps_phi {
+                let phi = (j as f32 + 0.5) * d_phi;
+                let direction = Vec3::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin());
+                irradiance += sky.sky_radiance(&direction) * theta.cos() * theta.sin() * d_theta * d_phi;
+            }
+        }
+        irradiance += sun_radiance * sun_solid_angle() * sun_direction.y().max(0.0);
+        sky.ground_radiance = ground_albedo * irradiance / PI;
+
+        sky
+    }
+
+    // elevation above the horizon and azimuth clockwise from -z (towards +x) are in degrees
+    pub fn sun_direction(elevation: f32, azimuth: f32) -> Vec3 {
+        let (elevation, azimuth) = (elevation * PI / 180.0, azimuth * PI / 180.0);
+        Vec3::new(elevation.cos() * azimuth.sin(), elevation.sin(), -elevation.cos() * azimuth.cos())
+    }
+
+    // Unscaled radiance of the sky above the horizon, not including the sun
+    fn sky_radiance(&self, direction: &Vec3) -> Vec3 {
+        let direction = direction.unit();
+        let theta = direction.y().clamp(0.0, 1.0).acos();
+        let gamma = Vec3::dot(&direction, &self.sun_direction).clamp(-1.0, 1.0).acos();
+
+        let value = |i: usize| self.zenith[i] * perez(&self.coefficients[i], theta, gamma) / self.normalisation[i];
+        xyy_to_rgb(value(1), value(2), value(0))
+    }
+
+    fn in_sun(&self, direction: &Vec3) -> bool {
+        Vec3::dot(&direction.unit(), &self.sun_direction) >= self.cos_sun_radius
+    }
+
+    pub fn radiance(&self, direction: &Vec3) -> Vec3 {
+        if direction.y() < 0.0 {
+            return self.ground_radiance * self.intensity;
+        }
+
+        let sun = if self.in_sun(direction) { self.sun_radiance } else { Vec3::zero() };
+        (self.sky_radiance(direction) + sun) * self.intensity
+    }
+
+    fn sun_visible(&self) -> bool {
+        self.sun_radiance.x() > 0.0 || self.sun_radiance.y() > 0.0 || self.sun_radiance.z() > 0.0
+    }
+
+    // Only the sun is sampled directly, the rest of the sky is dim enough to be found by scattering
+    pub fn sample(&self, sample: [f32; 2]) -> Option<(Vec3, f32)> {
+        if !self.sun_visible() {
+            return None;
+        }
+
+        // random_to_sphere samples the cone of a sphere at distance 1 whose radius is the sine of the cone angle
+        let sin_radius = SUN_ANGULAR_RADIUS.sin();
+        let direction = Onb::from_w(&self.sun_direction).local(&random_to_sphere(sin_radius, 1.0, sample));
+        Some((direction, self.sun_pdf()))
+    }
+
+    fn sun_pdf(&self) -> f32 {
+        1.0 / sun_solid_angle()
+    }
+
+    pub fn pdf(&self, direction: &Vec3) -> f32 {
+        if self.sun_visible() && direction.y() >= 0.0 && self.in_sun(direction) {
+            self.sun_pdf()
+        } else {
+            0.0
+        }
+    }
+}
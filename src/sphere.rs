@@ -1,10 +1,13 @@
 use aabb::{surrounding_box, AABBVolume};
 use hitable::{HitRecord, Hitable};
+use onb::Onb;
+use random::random_to_sphere;
 use scene::Entities;
 use scene::MaterialRef;
 use ray::Ray;
 use vec3::Vec3;
 
+use std::f32;
 use std::f32::consts::{FRAC_PI_2, PI};
 
 fn get_sphere_uv(p: &Vec3) -> (f32, f32) {
@@ -13,6 +16,48 @@ fn get_sphere_uv(p: &Vec3) -> (f32, f32) {
     (1.0 - (phi + PI) / (2.0 * PI), (theta + FRAC_PI_2) / PI)
 }
 
+// Derivatives of the point with unit normal n with respect to the u and v of get_sphere_uv. dpdv is undefined at the
+// poles and left as zero there
+fn get_sphere_tangents(n: &Vec3, radius: f32) -> (Vec3, Vec3) {
+    let dpdu = 2.0 * PI * radius * Vec3::new(n.z(), 0.0, -n.x());
+    let cos_theta = (n.x() * n.x() + n.z() * n.z()).sqrt();
+    let dpdv = if cos_theta > 0.0 {
+        PI * radius * Vec3::new(-n.y() * n.x() / cos_theta, cos_theta, -n.y() * n.z() / cos_theta)
+    } else {
+        Vec3::zero()
+    };
+    (dpdu, dpdv)
+}
+
+// Spheres are sampled uniformly over the cone of directions from origin that hit them
+fn sphere_pdf_value<H: Hitable>(sphere: &H, center: &Vec3, radius: f32, entities: &Entities, origin: &Vec3, direction: &Vec3, time: f32) -> f32 {
+    let distance_squared = (*center - *origin).squared_length();
+    if distance_squared <= radius * radius {
+        return 0.0;
+    }
+
+    let mut hit_record = HitRecord::zero();
+    if !sphere.hit_ptr(entities, &Ray::new(*origin, *direction, time), 0.001, f32::MAX, &mut hit_record) {
+        return 0.0;
+    }
+
+    // Spheres that are tiny or far away can round to no solid angle at all
+    let cos_theta_max = (1.0 - radius * radius / distance_squared).sqrt();
+    let solid_angle = 2.0 * PI * (1.0 - cos_theta_max);
+    if solid_angle > 0.0 { 1.0 / solid_angle } else { 0.0 }
+}
+
+fn sphere_random_direction(center: &Vec3, radius: f32, origin: &Vec3, sample: [f32; 2]) -> Vec3 {
+    let direction = *center - *origin;
+    let distance_squared = direction.squared_length();
+    if distance_squared <= radius * radius {
+        // Inside the sphere so there is no cone to sample, the pdf is zero for any direction
+        return direction;
+    }
+
+    Onb::from_w(&direction).local(&random_to_sphere(radius, distance_squared, sample))
+}
+
 #[derive(Debug, Clone)]
 pub struct Sphere {
     center: Vec3,
@@ -38,12 +83,15 @@ impl Sphere {
         hit_record.u = u;
         hit_record.v = v;
         hit_record.normal = (p - self.center) / self.radius;
+        let (dpdu, dpdv) = get_sphere_tangents(&hit_record.normal, self.radius);
+        hit_record.dpdu = dpdu;
+        hit_record.dpdv = dpdv;
         hit_record.material = self.material;
     }
 }
 
 impl Hitable for Sphere {
-    fn hit_ptr(&self, entities: &Entities, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
+    fn hit_ptr(&self, _entities: &Entities, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
         let oc = ray.origin() - self.center;
         let ray_direction = ray.direction();
         let a = ray_direction.squared_length();
@@ -69,12 +117,24 @@ impl Hitable for Sphere {
         false
     }
 
-    fn bounding_box(&self, _t_min: f32, _t_max: f32) -> Option<AABBVolume> {
+    fn bounding_box(&self, _entities: &Entities, _t_min: f32, _t_max: f32) -> Option<AABBVolume> {
         Some(AABBVolume::new(
             self.center - Vec3::uniform(self.radius),
             self.center + Vec3::uniform(self.radius),
         ))
     }
+
+    fn material(&self) -> Option<MaterialRef> {
+        Some(self.material)
+    }
+
+    fn pdf_value(&self, entities: &Entities, origin: &Vec3, direction: &Vec3, time: f32) -> f32 {
+        sphere_pdf_value(self, &self.center, self.radius, entities, origin, direction, time)
+    }
+
+    fn random_direction(&self, _entities: &Entities, origin: &Vec3, _time: f32, sample: [f32; 2]) -> Vec3 {
+        sphere_random_direction(&self.center, self.radius, origin, sample)
+    }
 }
 
 #[derive(Debug)]
@@ -115,6 +175,9 @@ impl MovingSphere {
         hit_record.u = u;
         hit_record.v = v;
         hit_record.normal = (p - self.center(ray.time())) / self.radius;
+        let (dpdu, dpdv) = get_sphere_tangents(&hit_record.normal, self.radius);
+        hit_record.dpdu = dpdu;
+        hit_record.dpdv = dpdv;
         hit_record.material = self.material;
     }
 
@@ -126,7 +189,7 @@ impl MovingSphere {
 }
 
 impl Hitable for MovingSphere {
-    fn hit_ptr(&self, entities: &Entities, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
+    fn hit_ptr(&self, _entities: &Entities, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
         let oc = ray.origin() - self.center(ray.time());
         let ray_direction = ray.direction();
         let a = ray_direction.squared_length();
@@ -152,7 +215,7 @@ impl Hitable for MovingSphere {
         false
     }
 
-    fn bounding_box(&self, t_min: f32, t_max: f32) -> Option<AABBVolume> {
+    fn bounding_box(&self, _entities: &Entities, t_min: f32, t_max: f32) -> Option<AABBVolume> {
         let center_min = self.center(t_min);
         let center_max = self.center(t_max);
         let box0 = AABBVolume::new(
@@ -166,4 +229,16 @@ impl Hitable for MovingSphere {
 
         Some(surrounding_box(box0, box1))
     }
+
+    fn material(&self) -> Option<MaterialRef> {
+        Some(self.material)
+    }
+
+    fn pdf_value(&self, entities: &Entities, origin: &Vec3, direction: &Vec3, time: f32) -> f32 {
+        sphere_pdf_value(self, &self.center(time), self.radius, entities, origin, direction, time)
+    }
+
+    fn random_direction(&self, _entities: &Entities, origin: &Vec3, time: f32, sample: [f32; 2]) -> Vec3 {
+        sphere_random_direction(&self.center(time), self.radius, origin, sample)
+    }
 }
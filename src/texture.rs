@@ -21,10 +21,10 @@ fn image(u: f32, v: f32, image: &RgbImage) -> Vec3 {
     let mut i = (u * nx as f32) as u32;
     let mut j = ((1.0 - v) * ny as f32 - 0.001) as u32;
 
-    i = i.min(nx - 1).max(0);
-    j = j.min(ny - 1).max(0);
+    i = i.min(nx - 1);
+    j = j.min(ny - 1);
 
-    let pixel = image[(i as u32, j as u32)];
+    let pixel = image[(i, j)];
 
     let r = f32::from(pixel[0]) / 255.0;
     let g = f32::from(pixel[1]) / 255.0;
@@ -0,0 +1,255 @@
+// Splits the image into square tiles that are rendered in parallel straight into the framebuffer
+// Neighbouring pixels follow similar paths through the BVH so rendering them together keeps it in cache
+
+use rayon::prelude::*;
+
+use std::fmt;
+use std::mem;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub const DEFAULT_TILE_SIZE: u32 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TileOrder {
+    Scanline,
+    // Outwards from the centre, where the subject usually is
+    Spiral,
+    // Along a Hilbert curve so that consecutive tiles are usually neighbours, grids that aren't a power of two across
+    // skip the parts of the curve outside them
+    Hilbert,
+}
+
+impl TileOrder {
+    pub const NAMES: [&'static str; 3] = ["scanline", "spiral", "hilbert"];
+}
+
+impl FromStr for TileOrder {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<TileOrder, String> {
+        match name {
+            "scanline" => Ok(TileOrder::Scanline),
+            "spiral" => Ok(TileOrder::Spiral),
+            "hilbert" => Ok(TileOrder::Hilbert),
+            _ => Err(format!("unknown tile order '{}' (expected one of {})", name, TileOrder::NAMES.join(", "))),
+        }
+    }
+}
+
+impl fmt::Display for TileOrder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let index = match self {
+            TileOrder::Scanline => 0,
+            TileOrder::Spiral => 1,
+            TileOrder::Hilbert => 2,
+        };
+        write!(f, "{}", TileOrder::NAMES[index])
+    }
+}
+
+// Pixels x..x + width of rows y..y + height, rows are counted from the top of the image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tile {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+// Distance along a Hilbert curve covering a size x size grid, size must be a power of two
+// Credit to https://en.wikipedia.org/wiki/Hilbert_curve
+fn hilbert_distance(size: u32, mut x: u32, mut y: u32) -> u64 {
+    let mut distance = 0;
+    let mut s = size / 2;
+    while s > 0 {
+        let rx = (x & s > 0) as u32;
+        let ry = (y & s > 0) as u32;
+        distance += u64::from(s) * u64::from(s) * u64::from((3 * rx) ^ ry);
+
+        // Rotate the quadrant so the curve inside it lines up with the curve as a whole
+        if ry == 0 {
+            if rx == 1 {
+                x = size - 1 - x;
+                y = size - 1 - y;
+            }
+            mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    distance
+}
+
+// Grid positions in the order of a square spiral starting at the centre, skipping those outside the grid
+fn spiral(columns: u32, rows: u32) -> Vec<(u32, u32)> {
+    let count = (columns * rows) as usize;
+    let mut positions = Vec::with_capacity(count);
+    let (mut x, mut y) = ((columns as i64 - 1) / 2, (rows as i64 - 1) / 2);
+    let directions = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+    let mut direction = 0;
+    let mut run = 1;
+
+    positions.push((x as u32, y as u32));
+    while positions.len() < count {
+        // Runs go 1, 1, 2, 2, 3, 3... turning after each
+        for _ in 0..2 {
+            let (dx, dy) = directions[direction];
+            for _ in 0..run {
+                x += dx;
+                y += dy;
+                if x >= 0 && y >= 0 && x < i64::from(columns) && y < i64::from(rows) {
+                    positions.push((x as u32, y as u32));
+                }
+            }
+            direction = (direction + 1) % 4;
+        }
+        run += 1;
+    }
+    positions
+}
+
+#[derive(Debug)]
+pub struct TileScheduler {
+    width: u32,
+    height: u32,
+    tile_size: u32,
+    columns: u32,
+    // In the order they are rendered
+    tiles: Vec<Tile>,
+    // Position in tiles of each grid cell, row by row
+    order: Vec<usize>,
+}
+
+impl TileScheduler {
+    pub fn new(width: u32, height: u32, tile_size: u32, tile_order: TileOrder) -> TileScheduler {
+        let columns = width.div_ceil(tile_size);
+        let rows = height.div_ceil(tile_size);
+
+        let mut positions: Vec<(u32, u32)> = match tile_order {
+            TileOrder::Scanline | TileOrder::Hilbert => (0..rows)
+                .flat_map(|row| (0..columns).map(move |column| (column, row)))
+                .collect(),
+            TileOrder::Spiral => spiral(columns, rows),
+        };
+        if tile_order == TileOrder::Hilbert {
+            let size = columns.max(rows).next_power_of_two();
+            positions.sort_by_key(|&(column, row)| hilbert_distance(size, column, row));
+        }
+
+        let mut order = vec![0; (columns * rows) as usize];
+        let tiles = positions.iter()
+            .enumerate()
+            .map(|(index, &(column, row))| {
+                order[(row * columns + column) as usize] = index;
+                let x = column * tile_size;
+                let y = row * tile_size;
+                Tile { x, y, width: tile_size.min(width - x), height: tile_size.min(height - y) }
+            })
+            .collect();
+
+        TileScheduler { width, height, tile_size, columns, tiles, order }
+    }
+
+    pub fn tiles(&self) -> &[Tile] {
+        &self.tiles
+    }
+
+    // Each tile's rows of pixels, in the same order as tiles
+    fn split<'a, T>(&self, pixels: &'a mut [T]) -> Vec<Vec<&'a mut [T]>> {
+        let mut tile_rows: Vec<Vec<&mut [T]>> = self.tiles.iter()
+            .map(|tile| Vec::with_capacity(tile.height as usize))
+            .collect();
+
+        for (y, mut row) in pixels.chunks_mut(self.width as usize).enumerate() {
+            let grid_row = y as u32 / self.tile_size;
+            for column in 0..self.columns {
+                let length = (self.tile_size as usize).min(row.len());
+                let (segment, rest) = mem::take(&mut row).split_at_mut(length);
+                row = rest;
+                tile_rows[self.order[(grid_row * self.columns + column) as usize]].push(segment);
+            }
+        }
+        tile_rows
+    }
+
+    // Tiles are handed out in order, render_pixel is given state made by init, which is shared by the tiles a thread
+    // renders, and each pixel's index in the image. on_tile_done is called as each tile finishes along with the
+    // number finished so far
+    pub fn render<T, S, I, F, C>(&self, pixels: &mut [T], init: I, render_pixel: F, on_tile_done: C)
+        where T: Send, I: Fn() -> S + Sync + Send, F: Fn(&mut S, usize, &mut T) + Sync, C: Fn(&Tile, usize) + Sync {
+        assert_eq!(pixels.len(), self.width as usize * self.height as usize, "pixels don't match the size of the image");
+
+        let finished = AtomicUsize::new(0);
+        self.split(pixels)
+            .into_iter()
+            .zip(self.tiles.iter())
+            .par_bridge()
+            .for_each_init(init, |state, (rows, tile)| {
+                for (dy, row) in rows.into_iter().enumerate() {
+                    let start = (tile.y as usize + dy) * self.width as usize + tile.x as usize;
+                    for (dx, pixel) in row.iter_mut().enumerate() {
+                        render_pixel(state, start + dx, pixel);
+                    }
+                }
+                on_tile_done(tile, finished.fetch_add(1, Ordering::Relaxed) + 1);
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORDERS: [TileOrder; 3] = [TileOrder::Scanline, TileOrder::Spiral, TileOrder::Hilbert];
+    // Sizes that are and aren't multiples of the tile size, including images smaller than a tile
+    const SIZES: [(u32, u32, u32); 5] = [(64, 32, 16), (67, 45, 16), (5, 3, 16), (100, 1, 7), (31, 33, 1)];
+
+    #[test]
+    fn tiles_cover_every_pixel_once() {
+        for &order in &ORDERS {
+            for &(width, height, tile_size) in &SIZES {
+                let scheduler = TileScheduler::new(width, height, tile_size, order);
+                let mut covered = vec![0; (width * height) as usize];
+                for tile in scheduler.tiles() {
+                    assert!(tile.width > 0 && tile.height > 0 && tile.width <= tile_size && tile.height <= tile_size);
+                    for y in tile.y..tile.y + tile.height {
+                        for x in tile.x..tile.x + tile.width {
+                            covered[(y * width + x) as usize] += 1;
+                        }
+                    }
+                }
+                assert!(covered.iter().all(|&count| count == 1), "{} {}x{} in tiles of {}", order, width, height, tile_size);
+            }
+        }
+    }
+
+    #[test]
+    fn spirals_visit_every_cell_once_from_the_centre() {
+        for &(columns, rows) in &[(1, 1), (4, 4), (5, 3), (1, 9), (8, 2), (7, 10)] {
+            let positions = spiral(columns, rows);
+            assert_eq!(positions[0], ((columns - 1) / 2, (rows - 1) / 2));
+            let mut sorted = positions.clone();
+            sorted.sort_by_key(|&(column, row)| (row, column));
+            let expected: Vec<(u32, u32)> = (0..rows).flat_map(|row| (0..columns).map(move |column| (column, row))).collect();
+            assert_eq!(sorted, expected, "{}x{}", columns, rows);
+        }
+    }
+
+    #[test]
+    fn render_hands_out_each_pixel_once() {
+        for &order in &ORDERS {
+            for &(width, height, tile_size) in &SIZES {
+                let scheduler = TileScheduler::new(width, height, tile_size, order);
+                let mut pixels = vec![(0, 0); (width * height) as usize];
+                let tiles_done = AtomicUsize::new(0);
+                scheduler.render(&mut pixels, || (), |_, index, pixel| *pixel = (index, pixel.1 + 1), |_, _| {
+                    tiles_done.fetch_add(1, Ordering::Relaxed);
+                });
+                for (index, &pixel) in pixels.iter().enumerate() {
+                    assert_eq!(pixel, (index, 1), "{} {}x{} in tiles of {}", order, width, height, tile_size);
+                }
+                assert_eq!(tiles_done.into_inner(), scheduler.tiles().len());
+            }
+        }
+    }
+}
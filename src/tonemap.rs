@@ -0,0 +1,200 @@
+// Maps the linear radiance in a FrameBuffer to display values in [0, 1]
+
+use vec3::Vec3;
+
+use std::fmt;
+use std::str::FromStr;
+
+// Used by the Hable operator when no white point is given, from the Uncharted 2 presentation
+const HABLE_WHITE_POINT: f32 = 11.2;
+
+#[inline]
+pub fn luminance(colour: &Vec3) -> f32 {
+    0.2126 * colour.r() + 0.7152 * colour.g() + 0.0722 * colour.b()
+}
+
+// Piecewise sRGB transfer function (IEC 61966-2-1)
+#[inline]
+fn srgb_encode(value: f32) -> f32 {
+    if value <= 0.003_130_8 {
+        12.92 * value
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+#[inline]
+fn map(colour: &Vec3, f: impl Fn(f32) -> f32) -> Vec3 {
+    Vec3::new(f(colour.r()), f(colour.g()), f(colour.b()))
+}
+
+// Filmic curve from John Hable's Uncharted 2 presentation - http://filmicworlds.com/blog/filmic-tonemapping-operators/
+fn hable_partial(x: f32) -> f32 {
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+    // (x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f) - e / f over a common denominator, so that 0 maps to
+    // exactly 0 rather than whatever is left after subtracting two rounded fractions
+    x * (f * (a * x + c * b) - e * (a * x + b)) / (f * (x * (a * x + b) + d * f))
+}
+
+// Fit of the ACES reference rendering transform by Krzysztof Narkowicz - https://knarkowicz.wordpress.com/2016/01/06/aces-filmic-tone-mapping-curve/
+fn aces(x: f32) -> f32 {
+    let (a, b, c, d, e) = (2.51, 0.03, 2.43, 0.59, 0.14);
+    ((x * (a * x + b)) / (x * (c * x + d) + e)).clamp(0.0, 1.0)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ToneMapOperator {
+    Clamp,
+    Reinhard,
+    ReinhardExtended,
+    Aces,
+    Hable,
+}
+
+impl ToneMapOperator {
+    pub const NAMES: [&'static str; 5] = ["clamp", "reinhard", "reinhard-extended", "aces", "hable"];
+}
+
+impl FromStr for ToneMapOperator {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<ToneMapOperator, String> {
+        match name {
+            "clamp" => Ok(ToneMapOperator::Clamp),
+            "reinhard" => Ok(ToneMapOperator::Reinhard),
+            "reinhard-extended" => Ok(ToneMapOperator::ReinhardExtended),
+            "aces" => Ok(ToneMapOperator::Aces),
+            "hable" => Ok(ToneMapOperator::Hable),
+            _ => Err(format!("unknown tone mapping operator '{}' (expected one of {})", name, ToneMapOperator::NAMES.join(", "))),
+        }
+    }
+}
+
+impl fmt::Display for ToneMapOperator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let index = match self {
+            ToneMapOperator::Clamp => 0,
+            ToneMapOperator::Reinhard => 1,
+            ToneMapOperator::ReinhardExtended => 2,
+            ToneMapOperator::Aces => 3,
+            ToneMapOperator::Hable => 4,
+        };
+        write!(f, "{}", ToneMapOperator::NAMES[index])
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ToneMapping {
+    pub operator: ToneMapOperator,
+    // Stops, each one doubles the brightness
+    pub exposure: f32,
+    // Radiance that maps to white for the extended Reinhard and Hable operators
+    // When None the brightest pixel is used for extended Reinhard and 11.2 for Hable
+    pub white_point: Option<f32>,
+}
+
+impl ToneMapping {
+    pub fn new(operator: ToneMapOperator, exposure: f32, white_point: Option<f32>) -> ToneMapping {
+        ToneMapping { operator, exposure, white_point }
+    }
+
+    // Resolves the white point for a set of pixels, so that apply can be called on each of them
+    pub fn white_point_for(&self, pixels: &[Vec3]) -> f32 {
+        match (self.white_point, self.operator) {
+            (Some(white_point), _) => white_point,
+            (None, ToneMapOperator::Hable) => HABLE_WHITE_POINT,
+            (None, _) => {
+                let scale = self.exposure.exp2();
+                pixels.iter()
+                    .map(|pixel| luminance(pixel) * scale)
+                    .fold(0.0, f32::max)
+            },
+        }
+    }
+
+    // Returns the sRGB encoded display value of colour
+    pub fn apply(&self, colour: &Vec3, white_point: f32) -> Vec3 {
+        let exposed = *colour * self.exposure.exp2();
+
+        let mapped = match self.operator {
+            ToneMapOperator::Clamp => exposed,
+            ToneMapOperator::Reinhard => map(&exposed, |x| x / (1.0 + x)),
+            // Applied to the luminance so that bright colours keep their hue
+            ToneMapOperator::ReinhardExtended => {
+                let l = luminance(&exposed);
+                if l <= 0.0 || white_point <= 0.0 {
+                    exposed
+                } else {
+                    let mapped_l = l * (1.0 + l / (white_point * white_point)) / (1.0 + l);
+                    exposed * (mapped_l / l)
+                }
+            },
+            ToneMapOperator::Aces => map(&exposed, aces),
+            ToneMapOperator::Hable => {
+                let white_scale = 1.0 / hable_partial(white_point);
+                // Exposure bias from the presentation
+                map(&exposed, |x| hable_partial(2.0 * x) * white_scale)
+            },
+        };
+
+        map(&mapped, |x| srgb_encode(x.clamp(0.0, 1.0)))
+    }
+}
+
+impl Default for ToneMapping {
+    fn default() -> ToneMapping {
+        ToneMapping::new(ToneMapOperator::Clamp, 0.0, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPERATORS: [ToneMapOperator; 5] = [
+        ToneMapOperator::Clamp,
+        ToneMapOperator::Reinhard,
+        ToneMapOperator::ReinhardExtended,
+        ToneMapOperator::Aces,
+        ToneMapOperator::Hable,
+    ];
+
+    // Inverse of srgb_encode
+    fn srgb_decode(value: f32) -> f32 {
+        if value <= 0.040_45 {
+            value / 12.92
+        } else {
+            ((value + 0.055) / 1.055).powf(2.4)
+        }
+    }
+
+    #[test]
+    fn srgb_round_trips_and_meets_at_the_breakpoint() {
+        assert_eq!(srgb_encode(0.0), 0.0);
+        assert!((srgb_encode(1.0) - 1.0).abs() < 1e-6);
+        assert!((srgb_decode(1.0) - 1.0).abs() < 1e-6);
+
+        // The linear and power segments agree where they meet
+        let breakpoint: f32 = 0.003_130_8;
+        assert!((12.92 * breakpoint - (1.055 * breakpoint.powf(1.0 / 2.4) - 0.055)).abs() < 1e-5);
+        for &value in &[0.0, breakpoint * 0.5, breakpoint, breakpoint * 1.001, 0.2, 0.5, 1.0] {
+            assert!((srgb_decode(srgb_encode(value)) - value).abs() < 1e-5 * value.max(1e-2), "{}", value);
+        }
+    }
+
+    #[test]
+    fn operators_map_black_to_black_and_keep_the_order_of_brightnesses() {
+        for &operator in &OPERATORS {
+            let tone_mapping = ToneMapping::new(operator, 0.5, Some(4.0));
+            assert_eq!(tone_mapping.apply(&Vec3::zero(), 4.0), Vec3::zero(), "{}", operator);
+
+            let mut previous = 0.0;
+            for step in 1..=2000 {
+                let display = tone_mapping.apply(&Vec3::uniform(step as f32 * 0.005), 4.0).r();
+                assert!(display >= previous, "{} maps {} below a darker grey", operator, step as f32 * 0.005);
+                previous = display;
+            }
+            assert!(previous > tone_mapping.apply(&Vec3::uniform(0.01), 4.0).r());
+        }
+    }
+}
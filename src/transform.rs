@@ -1,12 +1,12 @@
-use aabb::AABBVolume;
+use aabb::{surrounding_box, AABBVolume};
 use hitable::{HitRecord, Hitable};
-use scene::Entities;
+use scene::{Entities, MaterialRef};
 use ray::Ray;
 use vec3::Vec3;
 
-use std::f32;
+use cgmath::{Deg, InnerSpace, Matrix, Matrix4, Point3, Quaternion, Rotation3, SquareMatrix, Vector3, Vector4};
 
-// TODO: Add a wrapper type for storing a reference to some Primitive/Geometry in order to do proper instancing- e.g. Translate::new(GeometryRef(2), Vec3::uniform(5.0))
+use std::f32;
 
 #[derive(Debug)]
 pub struct FlipNormals<H> {
@@ -29,8 +29,24 @@ impl<H: Hitable> Hitable for FlipNormals<H> {
         hit
     }
 
-    fn bounding_box(&self, t_min: f32, t_max: f32) -> Option<AABBVolume> {
-        self.ptr.bounding_box(t_min, t_max)
+    fn bounding_box(&self, entities: &Entities, t_min: f32, t_max: f32) -> Option<AABBVolume> {
+        self.ptr.bounding_box(entities, t_min, t_max)
+    }
+
+    fn set_shutter(&mut self, entities: &Entities, time0: f32, time1: f32) {
+        self.ptr.set_shutter(entities, time0, time1)
+    }
+
+    fn material(&self) -> Option<MaterialRef> {
+        self.ptr.material()
+    }
+
+    fn pdf_value(&self, entities: &Entities, origin: &Vec3, direction: &Vec3, time: f32) -> f32 {
+        self.ptr.pdf_value(entities, origin, direction, time)
+    }
+
+    fn random_direction(&self, entities: &Entities, origin: &Vec3, time: f32, sample: [f32; 2]) -> Vec3 {
+        self.ptr.random_direction(entities, origin, time, sample)
     }
 }
 
@@ -57,15 +73,26 @@ impl<T: Hitable> Hitable for Translate<T> {
         hit
     }
 
-    fn bounding_box(&self, t_min: f32, t_max: f32) -> Option<AABBVolume> {
-        if let Some(aabb) = self.ptr.bounding_box(t_min, t_max) {
-            Some(AABBVolume::new(
-                aabb.min() + self.offset,
-                aabb.max() + self.offset,
-            ))
-        } else {
-            None
-        }
+    fn bounding_box(&self, entities: &Entities, t_min: f32, t_max: f32) -> Option<AABBVolume> {
+        self.ptr.bounding_box(entities, t_min, t_max).map(|aabb| {
+            AABBVolume::new(aabb.min() + self.offset, aabb.max() + self.offset)
+        })
+    }
+
+    fn set_shutter(&mut self, entities: &Entities, time0: f32, time1: f32) {
+        self.ptr.set_shutter(entities, time0, time1)
+    }
+
+    fn material(&self) -> Option<MaterialRef> {
+        self.ptr.material()
+    }
+
+    fn pdf_value(&self, entities: &Entities, origin: &Vec3, direction: &Vec3, time: f32) -> f32 {
+        self.ptr.pdf_value(entities, &(*origin - self.offset), direction, time)
+    }
+
+    fn random_direction(&self, entities: &Entities, origin: &Vec3, time: f32, sample: [f32; 2]) -> Vec3 {
+        self.ptr.random_direction(entities, &(*origin - self.offset), time, sample)
     }
 }
 
@@ -74,8 +101,6 @@ pub struct RotateY<T: Hitable> {
     ptr: T,
     sin_theta: f32,
     cos_theta: f32,
-    // Pre-calculate and store bbox as calculating on the fly is too expensive
-    aabb_box: Option<AABBVolume>,
 }
 
 impl<T: Hitable> RotateY<T> {
@@ -84,7 +109,54 @@ impl<T: Hitable> RotateY<T> {
         let sin_theta = radians.sin();
         let cos_theta = radians.cos();
 
-        let aabb_box = ptr.bounding_box(0.0, 1.0).unwrap_or_else(AABBVolume::zero);
+        RotateY {
+            ptr,
+            sin_theta,
+            cos_theta,
+        }
+    }
+
+    fn to_object(&self, v: &Vec3) -> Vec3 {
+        Vec3::new(
+            self.cos_theta * v.x() - self.sin_theta * v.z(),
+            v.y(),
+            self.sin_theta * v.x() + self.cos_theta * v.z(),
+        )
+    }
+
+    fn to_world(&self, v: &Vec3) -> Vec3 {
+        Vec3::new(
+            self.cos_theta * v.x() + self.sin_theta * v.z(),
+            v.y(),
+            -self.sin_theta * v.x() + self.cos_theta * v.z(),
+        )
+    }
+}
+
+impl<T: Hitable> Hitable for RotateY<T> {
+    fn hit_ptr(&self, entities: &Entities, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
+        let rotated_ray = Ray::new(self.to_object(&ray.origin()), self.to_object(&ray.direction()), ray.time());
+
+        if self.ptr.hit_ptr(entities, &rotated_ray, t_min, t_max, hit_record) {
+            let p = self.to_world(&hit_record.p);
+            let normal = self.to_world(&hit_record.normal);
+            let dpdu = self.to_world(&hit_record.dpdu);
+            let dpdv = self.to_world(&hit_record.dpdv);
+
+            hit_record.p = p;
+            hit_record.normal = normal;
+            hit_record.dpdu = dpdu;
+            hit_record.dpdv = dpdv;
+
+            true
+        } else {
+            false
+        }
+    }
+
+    // Worked out each time rather than once when created, as what is inside may move differently over each shutter
+    fn bounding_box(&self, entities: &Entities, t_min: f32, t_max: f32) -> Option<AABBVolume> {
+        let aabb_box = self.ptr.bounding_box(entities, t_min, t_max)?;
         let mut min = Vec3::uniform(f32::MAX);
         let mut max = Vec3::uniform(f32::MIN);
 
@@ -94,9 +166,7 @@ impl<T: Hitable> RotateY<T> {
                     let x = i as f32 * aabb_box.max().x() + (1 - i) as f32 * aabb_box.min().x();
                     let y = j as f32 * aabb_box.max().y() + (1 - j) as f32 * aabb_box.min().y();
                     let z = k as f32 * aabb_box.max().z() + (1 - k) as f32 * aabb_box.min().z();
-                    let newx = cos_theta * x + sin_theta * z;
-                    let newz = -sin_theta * x + cos_theta * z;
-                    let tester = Vec3::new(newx, y, newz);
+                    let tester = self.to_world(&Vec3::new(x, y, z));
 
                     for c in 0..3 {
                         if tester[c] > max[c] {
@@ -110,56 +180,504 @@ impl<T: Hitable> RotateY<T> {
             }
         }
 
-        RotateY {
-            ptr,
-            sin_theta,
-            cos_theta,
-            aabb_box: Some(AABBVolume::new(min, max)),
+        Some(AABBVolume::new(min, max))
+    }
+
+    fn set_shutter(&mut self, entities: &Entities, time0: f32, time1: f32) {
+        self.ptr.set_shutter(entities, time0, time1)
+    }
+
+    fn material(&self) -> Option<MaterialRef> {
+        self.ptr.material()
+    }
+
+    fn pdf_value(&self, entities: &Entities, origin: &Vec3, direction: &Vec3, time: f32) -> f32 {
+        self.ptr.pdf_value(entities, &self.to_object(origin), &self.to_object(direction), time)
+    }
+
+    fn random_direction(&self, entities: &Entities, origin: &Vec3, time: f32, sample: [f32; 2]) -> Vec3 {
+        self.to_world(&self.ptr.random_direction(entities, &self.to_object(origin), time, sample))
+    }
+}
+
+fn to_point(v: &Vec3) -> Vector4<f32> {
+    Vector4::new(v.x(), v.y(), v.z(), 1.0)
+}
+
+fn to_vector(v: &Vec3) -> Vector4<f32> {
+    Vector4::new(v.x(), v.y(), v.z(), 0.0)
+}
+
+fn from_vector4(v: Vector4<f32>) -> Vec3 {
+    Vec3::new(v.x, v.y, v.z)
+}
+
+fn to_vector3(v: &Vec3) -> Vector3<f32> {
+    Vector3::new(v.x(), v.y(), v.z())
+}
+
+// Affine transformation from object to world space along with its inverse. The builder methods each apply another
+// transformation after the ones so far, e.g. Affine::identity().scale(s).rotate_y(a).translate(t)
+#[derive(Debug, Clone, Copy)]
+pub struct Affine {
+    matrix: Matrix4<f32>,
+    inverse: Matrix4<f32>,
+}
+
+impl Affine {
+    pub fn identity() -> Affine {
+        Affine {
+            matrix: Matrix4::identity(),
+            inverse: Matrix4::identity(),
+        }
+    }
+
+    // Applies other after self
+    pub fn then(&self, other: &Affine) -> Affine {
+        Affine {
+            matrix: other.matrix * self.matrix,
+            inverse: self.inverse * other.inverse,
+        }
+    }
+
+    pub fn inverse(&self) -> Affine {
+        Affine {
+            matrix: self.inverse,
+            inverse: self.matrix,
+        }
+    }
+
+    pub fn translate(&self, offset: &Vec3) -> Affine {
+        self.then(&Affine {
+            matrix: Matrix4::from_translation(to_vector3(offset)),
+            inverse: Matrix4::from_translation(-to_vector3(offset)),
+        })
+    }
+
+    // Scales by a different amount along each axis, none of which can be zero
+    pub fn scale(&self, scale: &Vec3) -> Affine {
+        self.then(&Affine {
+            matrix: Matrix4::from_nonuniform_scale(scale.x(), scale.y(), scale.z()),
+            inverse: Matrix4::from_nonuniform_scale(1.0 / scale.x(), 1.0 / scale.y(), 1.0 / scale.z()),
+        })
+    }
+
+    // Rotations are anticlockwise in degrees looking down the axis towards the origin
+    pub fn rotate_x(&self, angle: f32) -> Affine {
+        self.rotate(&Vec3::new(1.0, 0.0, 0.0), angle)
+    }
+
+    pub fn rotate_y(&self, angle: f32) -> Affine {
+        self.rotate(&Vec3::new(0.0, 1.0, 0.0), angle)
+    }
+
+    pub fn rotate_z(&self, angle: f32) -> Affine {
+        self.rotate(&Vec3::new(0.0, 0.0, 1.0), angle)
+    }
+
+    pub fn rotate(&self, axis: &Vec3, angle: f32) -> Affine {
+        self.rotate_by(Matrix4::from_axis_angle(to_vector3(&axis.unit()), Deg(angle)))
+    }
+
+    fn rotate_by(&self, rotation: Matrix4<f32>) -> Affine {
+        // A rotation's inverse is its transpose
+        self.then(&Affine {
+            matrix: rotation,
+            inverse: rotation.transpose(),
+        })
+    }
+
+    // Moves the origin to lookfrom and turns -z to face lookat with +y as close to vup as it can be, the same way the
+    // camera is placed
+    pub fn look_at(&self, lookfrom: &Vec3, lookat: &Vec3, vup: &Vec3) -> Affine {
+        let point = |v: &Vec3| Point3::new(v.x(), v.y(), v.z());
+        let view = Matrix4::look_at_rh(point(lookfrom), point(lookat), to_vector3(vup));
+        self.then(&Affine {
+            matrix: view.invert().unwrap_or_else(Matrix4::identity),
+            inverse: view,
+        })
+    }
+
+    pub fn point(&self, p: &Vec3) -> Vec3 {
+        from_vector4(self.matrix * to_point(p))
+    }
+
+    pub fn vector(&self, v: &Vec3) -> Vec3 {
+        from_vector4(self.matrix * to_vector(v))
+    }
+
+    // Normals stay perpendicular to the surface when transformed by the inverse transpose, they aren't normalised
+    pub fn normal(&self, n: &Vec3) -> Vec3 {
+        from_vector4(self.inverse.transpose() * to_vector(n))
+    }
+
+    // Ratio of volumes after the transformation to before it
+    pub fn determinant(&self) -> f32 {
+        self.matrix.determinant()
+    }
+
+    // ray in the space being transformed from. The direction isn't normalised so that t is the same in both spaces
+    pub fn object_ray(&self, ray: &Ray) -> Ray {
+        let inverse = self.inverse();
+        Ray::new(inverse.point(&ray.origin()), inverse.vector(&ray.direction()), ray.time())
+    }
+
+    // Moves a hit found with object_ray into world space
+    pub fn world_hit(&self, hit_record: &mut HitRecord) {
+        hit_record.p = self.point(&hit_record.p);
+        hit_record.normal = self.normal(&hit_record.normal).unit();
+        hit_record.dpdu = self.vector(&hit_record.dpdu);
+        hit_record.dpdv = self.vector(&hit_record.dpdv);
+    }
+
+    // Solid angle pdf in world space from object_pdf, the pdf_value of a hitable in object space. Directions are
+    // mapped between the spaces by the matrix and normalising, which stretches solid angles by |det| / |M w|^3 for a
+    // unit direction w in object space. That is 1 for rigid transformations
+    pub fn world_pdf<F>(&self, origin: &Vec3, direction: &Vec3, object_pdf: F) -> f32
+        where F: FnOnce(&Vec3, &Vec3) -> f32 {
+        let inverse = self.inverse();
+        let object_direction = inverse.vector(direction);
+        let pdf = object_pdf(&inverse.point(origin), &object_direction);
+        if pdf <= 0.0 {
+            return 0.0;
         }
+
+        let stretch = self.vector(&object_direction.unit()).length();
+        pdf * stretch * stretch * stretch / self.determinant().abs()
+    }
+
+    // Box around all eight corners of aabb once they're transformed
+    pub fn bounding_box(&self, aabb: &AABBVolume) -> AABBVolume {
+        let mut min = Vec3::uniform(f32::MAX);
+        let mut max = Vec3::uniform(f32::MIN);
+        for i in 0..8 {
+            let corner = Vec3::new(
+                if i & 1 == 0 { aabb.min().x() } else { aabb.max().x() },
+                if i & 2 == 0 { aabb.min().y() } else { aabb.max().y() },
+                if i & 4 == 0 { aabb.min().z() } else { aabb.max().z() },
+            );
+            let corner = self.point(&corner);
+            min = min.min(&corner);
+            max = max.max(&corner);
+        }
+        AABBVolume::new(min, max)
     }
 }
 
-impl<T: Hitable> Hitable for RotateY<T> {
+// Wraps a hitable with any affine transformation
+#[derive(Debug)]
+pub struct Transform<T: Hitable> {
+    ptr: T,
+    transform: Affine,
+}
+
+impl<T: Hitable> Transform<T> {
+    pub fn new(ptr: T, transform: Affine) -> Transform<T> {
+        Transform { ptr, transform }
+    }
+}
+
+impl<T: Hitable> Hitable for Transform<T> {
     fn hit_ptr(&self, entities: &Entities, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
-        let ray_origin = ray.origin();
-        let ray_direction = ray.direction();
+        if self.ptr.hit_ptr(entities, &self.transform.object_ray(ray), t_min, t_max, hit_record) {
+            self.transform.world_hit(hit_record);
+            true
+        } else {
+            false
+        }
+    }
 
-        let origin = Vec3::new(
-            self.cos_theta * ray_origin.x() - self.sin_theta * ray_origin.z(),
-            ray_origin.y(),
-            self.sin_theta * ray_origin.x() + self.cos_theta * ray_origin.z(),
-        );
+    fn bounding_box(&self, entities: &Entities, t_min: f32, t_max: f32) -> Option<AABBVolume> {
+        self.ptr.bounding_box(entities, t_min, t_max).map(|aabb| self.transform.bounding_box(&aabb))
+    }
 
-        let direction = Vec3::new(
-            self.cos_theta * ray_direction.x() - self.sin_theta * ray_direction.z(),
-            ray_direction.y(),
-            self.sin_theta * ray_direction.x() + self.cos_theta * ray_direction.z(),
-        );
+    fn set_shutter(&mut self, entities: &Entities, time0: f32, time1: f32) {
+        self.ptr.set_shutter(entities, time0, time1)
+    }
 
-        let rotated_ray = Ray::new(origin, direction, ray.time());
+    fn material(&self) -> Option<MaterialRef> {
+        self.ptr.material()
+    }
 
-        if self.ptr.hit_ptr(entities, &rotated_ray, t_min, t_max, hit_record) {
-            let p = Vec3::new(
-                self.cos_theta * hit_record.p.x() + self.sin_theta * hit_record.p.z(),
-                hit_record.p.y(),
-                -self.sin_theta * hit_record.p.x() + self.cos_theta * hit_record.p.z(),
-            );
-            let normal = Vec3::new(
-                self.cos_theta * hit_record.normal.x() + self.sin_theta * hit_record.normal.z(),
-                hit_record.normal.y(),
-                -self.sin_theta * hit_record.normal.x() + self.cos_theta * hit_record.normal.z(),
-            );
+    fn pdf_value(&self, entities: &Entities, origin: &Vec3, direction: &Vec3, time: f32) -> f32 {
+        self.transform.world_pdf(origin, direction, |origin, direction| self.ptr.pdf_value(entities, origin, direction, time))
+    }
 
-            hit_record.p = p;
-            hit_record.normal = normal;
+    fn random_direction(&self, entities: &Entities, origin: &Vec3, time: f32, sample: [f32; 2]) -> Vec3 {
+        let object_origin = self.transform.inverse().point(origin);
+        self.transform.vector(&self.ptr.random_direction(entities, &object_origin, time, sample))
+    }
+}
+
+// Number of times each stretch of an animation between keyframes is sampled to bound its motion
+const MOTION_STEPS: usize = 16;
+
+// Scale, rotation and translation of an animated hitable at one time. Whatever order the builder methods are called in
+// the scale is applied first, then the rotation and then the translation, so that they can be interpolated separately
+#[derive(Debug, Clone, Copy)]
+pub struct Keyframe {
+    time: f32,
+    scale: Vec3,
+    rotation: Quaternion<f32>,
+    translation: Vec3,
+}
+
+impl Keyframe {
+    pub fn new(time: f32) -> Keyframe {
+        Keyframe {
+            time,
+            scale: Vec3::uniform(1.0),
+            rotation: Quaternion::new(1.0, 0.0, 0.0, 0.0),
+            translation: Vec3::zero(),
+        }
+    }
+
+    pub fn scale(&self, scale: &Vec3) -> Keyframe {
+        Keyframe { scale: self.scale * *scale, ..*self }
+    }
+
+    // Rotates after any earlier rotations, angle is anticlockwise in degrees looking down the axis towards the origin
+    pub fn rotate(&self, axis: &Vec3, angle: f32) -> Keyframe {
+        let rotation = Quaternion::from_axis_angle(to_vector3(&axis.unit()), Deg(angle));
+        Keyframe { rotation: rotation * self.rotation, ..*self }
+    }
+
+    pub fn translate(&self, offset: &Vec3) -> Keyframe {
+        Keyframe { translation: self.translation + *offset, ..*self }
+    }
+
+    // Rotations take the shortest way between keyframes, so turns of half a revolution or more need keyframes between
+    fn interpolate(&self, other: &Keyframe, time: f32) -> Keyframe {
+        let amount = (time - self.time) / (other.time - self.time);
+        Keyframe {
+            time,
+            scale: self.scale + amount * (other.scale - self.scale),
+            rotation: self.rotation.slerp(other.rotation, amount),
+            translation: self.translation + amount * (other.translation - self.translation),
+        }
+    }
+
+    fn affine(&self) -> Affine {
+        Affine::identity()
+            .scale(&self.scale)
+            .rotate_by(Matrix4::from(self.rotation))
+            .translate(&self.translation)
+    }
+}
+
+// Wraps a hitable with a transformation interpolated between keyframes at the time of each ray, which blurs it over the
+// camera's shutter. Before the first keyframe and after the last the hitable stays where they put it
+#[derive(Debug)]
+pub struct AnimatedTransform<T: Hitable> {
+    ptr: T,
+    // In order of time
+    keyframes: Vec<Keyframe>,
+}
+
+impl<T: Hitable> AnimatedTransform<T> {
+    pub fn new(ptr: T, mut keyframes: Vec<Keyframe>) -> Result<AnimatedTransform<T>, String> {
+        if keyframes.is_empty() {
+            return Err("an animation needs at least one keyframe".to_owned());
+        }
+        keyframes.sort_by(|a, b| a.time.total_cmp(&b.time));
+        if let Some(pair) = keyframes.windows(2).find(|pair| pair[0].time == pair[1].time) {
+            return Err(format!("there is more than one keyframe at time {}", pair[0].time));
+        }
+        for keyframe in &keyframes {
+            let scale = keyframe.scale;
+            if scale.x() == 0.0 || scale.y() == 0.0 || scale.z() == 0.0 {
+                return Err(format!("the keyframe at time {} has a scale of zero along an axis", keyframe.time));
+            }
+        }
+
+        Ok(AnimatedTransform { ptr, keyframes })
+    }
+
+    fn keyframe_at(&self, time: f32) -> Keyframe {
+        let next = self.keyframes.iter().position(|keyframe| keyframe.time > time);
+        match next {
+            Some(0) => self.keyframes[0],
+            Some(index) => self.keyframes[index - 1].interpolate(&self.keyframes[index], time),
+            None => self.keyframes[self.keyframes.len() - 1],
+        }
+    }
 
+    fn at(&self, time: f32) -> Affine {
+        self.keyframe_at(time).affine()
+    }
+}
+
+impl<T: Hitable> Hitable for AnimatedTransform<T> {
+    fn hit_ptr(&self, entities: &Entities, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
+        let transform = self.at(ray.time());
+        if self.ptr.hit_ptr(entities, &transform.object_ray(ray), t_min, t_max, hit_record) {
+            transform.world_hit(hit_record);
             true
         } else {
             false
         }
     }
 
-    fn bounding_box(&self, _t_min: f32, _t_max: f32) -> Option<AABBVolume> {
-        self.aabb_box
+    // The motion is sampled between each pair of keyframes in the time range. Rotating moves points along arcs that
+    // bulge past the straight lines between the samples, so the box is padded by the furthest they can bulge
+    fn bounding_box(&self, entities: &Entities, t_min: f32, t_max: f32) -> Option<AABBVolume> {
+        let aabb = self.ptr.bounding_box(entities, t_min, t_max)?;
+        // Furthest any point of the hitable is from the origin it is scaled and rotated around
+        let (min, max) = (aabb.min(), aabb.max());
+        let radius = Vec3::new(
+            min.x().abs().max(max.x().abs()),
+            min.y().abs().max(max.y().abs()),
+            min.z().abs().max(max.z().abs()),
+        ).length();
+
+        let mut times = vec![t_min];
+        times.extend(self.keyframes.iter().map(|keyframe| keyframe.time).filter(|&time| time > t_min && time < t_max));
+        times.push(t_max.max(t_min));
+
+        let mut bounds = self.at(t_min).bounding_box(&aabb);
+        let mut padding: f32 = 0.0;
+        for pair in times.windows(2) {
+            let (start, end) = (self.keyframe_at(pair[0]), self.keyframe_at(pair[1]));
+            for step in 1..=MOTION_STEPS {
+                let time = pair[0] + (pair[1] - pair[0]) * step as f32 / MOTION_STEPS as f32;
+                bounds = surrounding_box(bounds, self.at(time).bounding_box(&aabb));
+            }
+
+            let angle = 2.0 * start.rotation.dot(end.rotation).abs().min(1.0).acos();
+            let largest_scale = (start.scale * start.scale).max(&(end.scale * end.scale)).max_component().sqrt();
+            padding = padding.max(largest_scale * radius * (1.0 - (angle / MOTION_STEPS as f32 / 2.0).cos()));
+        }
+
+        Some(AABBVolume::new(bounds.min() - Vec3::uniform(padding), bounds.max() + Vec3::uniform(padding)))
+    }
+
+    fn set_shutter(&mut self, entities: &Entities, time0: f32, time1: f32) {
+        self.ptr.set_shutter(entities, time0, time1)
+    }
+
+    fn material(&self) -> Option<MaterialRef> {
+        self.ptr.material()
+    }
+
+    fn pdf_value(&self, entities: &Entities, origin: &Vec3, direction: &Vec3, time: f32) -> f32 {
+        self.at(time).world_pdf(origin, direction, |origin, direction| self.ptr.pdf_value(entities, origin, direction, time))
+    }
+
+    fn random_direction(&self, entities: &Entities, origin: &Vec3, time: f32, sample: [f32; 2]) -> Vec3 {
+        let transform = self.at(time);
+        let object_origin = transform.inverse().point(origin);
+        transform.vector(&self.ptr.random_direction(entities, &object_origin, time, sample))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sphere::Sphere;
+
+    // A unit sphere moving along x from 0 at frame 1 to 23 at frame 24
+    fn animated_sphere() -> AnimatedTransform<Sphere> {
+        let keyframes = vec![Keyframe::new(1.0), Keyframe::new(24.0).translate(&Vec3::new(23.0, 0.0, 0.0))];
+        AnimatedTransform::new(Sphere::new(Vec3::zero(), 1.0, 0), keyframes).unwrap()
+    }
+
+    #[test]
+    fn rotate_y_bounds_what_it_wraps_over_the_shutter() {
+        // Turned a quarter around y the sphere moves along -z instead, reaching z = -11 at frame 12
+        let entities = Entities::new();
+        let rotated = RotateY::new(animated_sphere(), 90.0);
+        let aabb = rotated.bounding_box(&entities, 12.0, 12.5).unwrap();
+        assert!(aabb.max().z() >= -10.0 && aabb.min().z() <= -12.5);
+
+        let ray = Ray::new(Vec3::new(-5.0, 0.0, -11.0), Vec3::new(1.0, 0.0, 0.0), 12.0);
+        let mut hit_record = HitRecord::zero();
+        assert!(rotated.hit_ptr(&entities, &ray, 0.001, f32::MAX, &mut hit_record));
+        assert!((hit_record.p - Vec3::new(-1.0, 0.0, -11.0)).length() < 1e-3);
+    }
+
+    #[test]
+    fn animated_bounds_cover_every_time_in_the_shutter() {
+        let entities = Entities::new();
+        let animated = AnimatedTransform::new(Sphere::new(Vec3::zero(), 1.0, 0), vec![
+            Keyframe::new(0.0).translate(&Vec3::new(5.0, 0.0, 0.0)),
+            Keyframe::new(4.0).rotate(&Vec3::new(1.0, 1.0, 0.0), 170.0).scale(&Vec3::new(2.0, 1.0, 1.0)),
+            Keyframe::new(10.0).rotate(&Vec3::new(0.0, 0.0, 1.0), -100.0).translate(&Vec3::new(0.0, 3.0, 0.0)),
+        ]).unwrap();
+        let corners = AABBVolume::new(Vec3::uniform(-1.0), Vec3::uniform(1.0));
+
+        for &(time0, time1) in &[(0.0, 10.0), (1.0, 3.0), (-10.0, 20.0), (5.0, 5.0)] {
+            let aabb = animated.bounding_box(&entities, time0, time1).unwrap();
+            for step in 0..=1000 {
+                let bounds = animated.at(time0 + (time1 - time0) * step as f32 / 1000.0).bounding_box(&corners);
+                assert!(bounds.min().min(&aabb.min()) == aabb.min() && bounds.max().max(&aabb.max()) == aabb.max());
+            }
+        }
+    }
+
+    fn assert_close(a: &Vec3, b: &Vec3) {
+        assert!((*a - *b).length() < 1e-4, "{:?} isn't {:?}", a, b);
+    }
+
+    #[test]
+    fn scaled_spheres_have_the_normals_of_an_ellipsoid() {
+        // A sphere stretched to twice as wide along x and moved away from the origin
+        let entities = Entities::new();
+        let offset = Vec3::new(1.0, -2.0, 3.0);
+        let affine = Affine::identity().scale(&Vec3::new(2.0, 1.0, 1.0)).translate(&offset);
+        let ellipsoid = Transform::new(Sphere::new(Vec3::zero(), 1.0, 0), affine);
+
+        for step in 0..16 {
+            // The ray heads for the centre, so it hits the point on the surface at angle a, where the gradient of
+            // x^2 / 4 + y^2 = 1 is (x / 4, y)
+            let a = step as f32 * 2.0 * f32::consts::PI / 16.0;
+            let p = Vec3::new(2.0 * a.cos(), a.sin(), 0.0);
+            let ray = Ray::new(offset + 3.0 * p, -p, 0.0);
+            let mut hit_record = HitRecord::zero();
+            assert!(ellipsoid.hit_ptr(&entities, &ray, 0.001, f32::MAX, &mut hit_record));
+            assert_close(&hit_record.p, &(offset + p));
+            assert_close(&hit_record.normal, &Vec3::new(p.x() / 4.0, p.y(), 0.0).unit());
+        }
+    }
+
+    #[test]
+    fn transformed_bounds_enclose_the_surface() {
+        let entities = Entities::new();
+        let affine = Affine::identity()
+            .scale(&Vec3::new(3.0, 0.5, 1.0))
+            .rotate(&Vec3::new(1.0, 2.0, 3.0), 40.0)
+            .translate(&Vec3::new(0.0, 5.0, 0.0));
+        let aabb = Transform::new(Sphere::new(Vec3::zero(), 1.0, 0), affine).bounding_box(&entities, 0.0, 1.0).unwrap();
+        let inside = |p: &Vec3| p.min(&aabb.min()) == aabb.min() && p.max(&aabb.max()) == aabb.max();
+
+        for i in 0..32 {
+            for j in 0..=16 {
+                let (phi, theta) = (i as f32 * f32::consts::PI / 16.0, j as f32 * f32::consts::PI / 16.0);
+                let p = affine.point(&Vec3::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos()));
+                assert!(inside(&p), "{:?} is outside {:?}", p, aabb);
+            }
+        }
+    }
+
+    #[test]
+    fn inverses_undo_transformations() {
+        let affine = Affine::identity()
+            .scale(&Vec3::new(2.0, 0.5, -1.0))
+            .rotate_x(30.0)
+            .rotate_z(-75.0)
+            .translate(&Vec3::new(4.0, 0.0, -1.0));
+        let p = Vec3::new(0.3, -1.2, 2.5);
+        for round_trip in &[affine.then(&affine.inverse()), affine.inverse().then(&affine)] {
+            assert_close(&round_trip.point(&p), &p);
+            assert_close(&round_trip.vector(&p), &p);
+            assert_close(&round_trip.normal(&p), &p);
+            assert!((round_trip.determinant() - 1.0).abs() < 1e-5);
+        }
+        assert_close(&affine.inverse().point(&affine.point(&p)), &p);
+
+        // then applies its argument second
+        let moved = Affine::identity().translate(&Vec3::new(1.0, 0.0, 0.0));
+        let scaled = Affine::identity().scale(&Vec3::uniform(2.0));
+        assert_close(&moved.then(&scaled).point(&Vec3::zero()), &Vec3::new(2.0, 0.0, 0.0));
+        assert_close(&scaled.then(&moved).point(&Vec3::zero()), &Vec3::new(1.0, 0.0, 0.0));
     }
 }
@@ -55,6 +55,8 @@ impl<H: Hitable> Hitable for ConstantMedium<H> {
                 hit_record.t = t;
                 hit_record.p = ray.point_at_parameter(t);
                 hit_record.normal = Vec3::new(1.0, 0.0, 0.0);
+                hit_record.dpdu = Vec3::new(0.0, 1.0, 0.0);
+                hit_record.dpdv = Vec3::new(0.0, 0.0, 1.0);
                 hit_record.material = self.phase_function;
                 return true;
             }
@@ -63,7 +65,11 @@ impl<H: Hitable> Hitable for ConstantMedium<H> {
         false
     }
 
-    fn bounding_box(&self, t_min: f32, t_max: f32) -> Option<AABBVolume> {
-        self.boundary.bounding_box(t_min, t_max)
+    fn bounding_box(&self, entities: &Entities, t_min: f32, t_max: f32) -> Option<AABBVolume> {
+        self.boundary.bounding_box(entities, t_min, t_max)
+    }
+
+    fn set_shutter(&mut self, entities: &Entities, time0: f32, time1: f32) {
+        self.boundary.set_shutter(entities, time0, time1)
     }
 }